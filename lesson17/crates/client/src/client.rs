@@ -1,24 +1,45 @@
 use std::{
-    collections::BTreeMap,
+    collections::{BTreeMap, HashMap},
     io::{stdin, Read},
     net::TcpStream,
     ops::Deref,
     sync::{Arc, Mutex},
+    time::{Duration, Instant},
 };
 
 use base64::{engine::general_purpose, Engine as _};
+use chrono::Local;
 use utils::{
-    auth_request, deserialize_server_response, message_request, read_request, text, AuthRequest,
-    AuthRequestKind, ErrorResponse, MessageContent, MessageRequest, MessageResponse,
-    ServerResponse,
+    auth_request, ban_request, create_room_request, delete_message_request,
+    deserialize_server_response, edit_message_request, fetch_attachment_request, join_room_request,
+    kick_request, leave_room_request, list_mentions_request, list_online_users_request,
+    list_rooms_request, logout_request, mark_read_request, message_request, mute_request,
+    react_request, read_request, read_thread_request, search_request, text, unban_request,
+    unmute_request, unreact_request, AttachmentInfo, AuthRequest, AuthRequestKind,
+    CreateRoomRequest, DeleteMessageRequest, EditMessageRequest, ErrorResponse,
+    FetchAttachmentRequest, ListMentionsRequest, ListOnlineUsersRequest, ListRoomsRequest,
+    LogoutRequest, MarkReadRequest, MessageContent, MessageRequest, MessageResponse, MessageTarget,
+    ModerationAction, MuteRequest, ReactionRequest, ReadThreadRequest, RoomRequest, SearchRequest,
+    ServerResponse, UserRequest,
 };
-use utils::{errors::ClientError, ReadRequest};
 use utils::{
-    flush,
-    write_utils::{handle_file, handle_image, serialize_and_write},
+    db::GENERAL_ROOM_ID,
+    errors::{ClientError, ServerError},
+    ReadRequest,
+};
+use utils::{
+    flush, save_file,
+    write_utils::{handle_file, handle_image, handle_upload_progress, serialize_and_write},
 };
 use yew::{platform::spawn_local, prelude::*};
 
+/// The number of replies `.thread` displays
+static THREAD_AMOUNT: i32 = 50;
+/// The number of mentions `.mentions` displays
+static MENTIONS_AMOUNT: i32 = 20;
+/// The number of results `.search` displays
+static SEARCH_AMOUNT: i32 = 20;
+
 /// Prints the help message
 fn print_help() {
     println!("Possible commands:");
@@ -26,6 +47,27 @@ fn print_help() {
     println!("    .image <path> - Send an image located at <path> to the chat");
     println!("    .quit - Exit the chat application");
     println!("    .history <amount> - Display the last <amount> messages from the chat history");
+    println!("    .rooms - List all rooms");
+    println!("    .online - List the users who are online");
+    println!("    .mentions - Display the last messages mentioning you");
+    println!("    .search <words> - Search the messages you can see");
+    println!("    .create <name> - Create a new room and switch into it");
+    println!("    .join <room_id> - Join a room and switch into it");
+    println!("    .leave <room_id> - Leave a room");
+    println!("    .dm <user_id> - Switch to a direct conversation with the user");
+    println!("    .edit <message_id> <text> - Replace the text of your message");
+    println!("    .delete <message_id> - Delete your message, or any message as a moderator");
+    println!("    .reply <message_id> <text> - Reply to the message");
+    println!("    .thread <message_id> - Display the thread of the message");
+    println!("    .react <message_id> <emoji> - React to the message with the emoji");
+    println!("    .unreact <message_id> <emoji> - Take your reaction back");
+    println!("    .fetch <attachment_id> - Download an attachment into ./files");
+    println!("    .mute <user_id> <minutes> - Mute the user (moderators only)");
+    println!("    .unmute <user_id> - Unmute the user (moderators only)");
+    println!("    .kick <user_id> - Disconnect the user (moderators only)");
+    println!("    .ban <user_id> - Disconnect the user and keep them out (moderators only)");
+    println!("    .unban <user_id> - Let the user log in again (moderators only)");
+    println!("    .logout - End the session and log in again");
     println!("    .help - Display this help message");
 }
 
@@ -48,14 +90,29 @@ fn app(props: &Props) -> Html {
     let stream =
         TcpStream::connect(&address).expect("Failed to connect to the server, is the server live?");
     let mut stream_clone = stream.try_clone().unwrap();
+    let upload_stream = stream.try_clone().unwrap();
 
     let jwt: Arc<Mutex<Option<String>>> = Arc::new(Mutex::new(None));
     let jwt_clone = Arc::clone(&jwt);
 
+    let target: Arc<Mutex<MessageTarget>> =
+        Arc::new(Mutex::new(MessageTarget::Room(GENERAL_ROOM_ID)));
+    let target_clone = Arc::clone(&target);
+
+    // Files being uploaded by name, with their path and the target they are sent to
+    let uploads: Arc<Mutex<HashMap<String, (String, MessageTarget)>>> =
+        Arc::new(Mutex::new(HashMap::new()));
+    let uploads_clone = Arc::clone(&uploads);
+
     let messages = use_state(|| BTreeMap::<i32, MessageResponse>::new());
     let messages_a = messages.clone();
 
     spawn_local(async move {
+        // Attachments being downloaded, the chunks are collected until the last one arrives
+        let mut downloads = HashMap::<i32, (AttachmentInfo, Vec<u8>)>::new();
+        // Users shown as typing, until their indicator expires
+        let mut typing = HashMap::<i32, Instant>::new();
+
         loop {
             let mut len_buffer = [0; 4];
 
@@ -83,11 +140,18 @@ fn app(props: &Props) -> Html {
             };
 
             match message_data {
-                ServerResponse::AuthToken(token) => {
+                ServerResponse::Auth(auth) => {
                     let mut jwt_lock = jwt.lock().unwrap();
-                    *jwt_lock = Some(token);
+                    *jwt_lock = Some(auth.token);
 
                     flush("Successfully logged in");
+                    for unread in auth.unread {
+                        let conversation = match unread.target {
+                            MessageTarget::Room(room_id) => format!("room #{}", room_id),
+                            MessageTarget::User(user_id) => format!("the DMs of user {}", user_id),
+                        };
+                        flush(&format!("{} unread in {}", unread.count, conversation));
+                    }
                 }
                 ServerResponse::Error(error) => {
                     match error {
@@ -96,14 +160,264 @@ fn app(props: &Props) -> Html {
                         }
                         ErrorResponse::ServerError(e) => {
                             eprintln!("Server error: {e}");
+                            // The connection has been signed out, log in again
+                            if let ServerError::TokenExpired | ServerError::NotAuthenticated = e {
+                                *jwt.lock().unwrap() = None;
+                            }
                         }
                     };
                 }
+                ServerResponse::LoggedOut(_) => {
+                    *jwt.lock().unwrap() = None;
+
+                    flush("Logged out");
+                }
                 ServerResponse::Message(message) => {
+                    typing.remove(&message.user_id);
                     let mut messages_clone = messages_a.clone().deref().to_owned();
                     messages_clone.insert(message.id, message);
                     messages_a.set(messages_clone);
                 }
+                ServerResponse::MessageEdited(message)
+                | ServerResponse::MessageDeleted(message) => {
+                    let mut messages_clone = messages_a.clone().deref().to_owned();
+                    messages_clone.insert(message.id, message);
+                    messages_a.set(messages_clone);
+                }
+                ServerResponse::UploadProgress(progress) => {
+                    let Some((path, upload_target)) =
+                        uploads.lock().unwrap().get(&progress.name).cloned()
+                    else {
+                        continue;
+                    };
+
+                    let percent = match progress.size {
+                        0 => 100,
+                        size => progress.received * 100 / size,
+                    };
+                    flush(&format!("Uploading {}: {}%", progress.name, percent));
+
+                    match handle_upload_progress(&upload_stream, &path, upload_target, &progress) {
+                        Ok(true) => {
+                            uploads.lock().unwrap().remove(&progress.name);
+                        }
+                        Ok(false) => {}
+                        Err(e) => {
+                            eprintln!("Upload of {} failed: {}", progress.name, e);
+                            uploads.lock().unwrap().remove(&progress.name);
+                        }
+                    }
+                }
+                ServerResponse::AttachmentInfo(info) => {
+                    downloads.insert(info.id, (info, vec![]));
+                }
+                ServerResponse::AttachmentChunk(chunk) => {
+                    let Some((_, bytes)) = downloads.get_mut(&chunk.attachment_id) else {
+                        continue;
+                    };
+                    bytes.extend(chunk.data);
+
+                    if chunk.last {
+                        let (info, bytes) = downloads.remove(&chunk.attachment_id).unwrap();
+                        match save_file(&info.name, &bytes) {
+                            Ok(filename) => {
+                                flush(&format!("Saved attachment to files/{}", filename))
+                            }
+                            Err(e) => eprintln!("Failed to save attachment: {}", e),
+                        }
+                    }
+                }
+                ServerResponse::History(history) => {
+                    // The newest page is being read, so the conversation is read up to its end
+                    if let Some(newest) = history.messages.last() {
+                        serialize_and_write(
+                            &upload_stream,
+                            mark_read_request(MarkReadRequest {
+                                target: history.target.clone(),
+                                message_id: newest.id,
+                            }),
+                        )
+                        .map_err(|e| eprintln!("{}", e))
+                        .ok();
+                    }
+
+                    let mut messages_clone = messages_a.clone().deref().to_owned();
+                    for message in history.messages {
+                        messages_clone.insert(message.id, message);
+                    }
+                    messages_a.set(messages_clone);
+
+                    if history.has_more {
+                        flush("There are older messages in the history");
+                    }
+                }
+                ServerResponse::RoomJoined(room) => {
+                    let mut target_lock = target.lock().unwrap();
+                    *target_lock = MessageTarget::Room(room.id);
+
+                    flush(&format!("Joined room #{} {}", room.id, room.name));
+                }
+                ServerResponse::RoomLeft(room) => {
+                    let mut target_lock = target.lock().unwrap();
+                    if *target_lock == MessageTarget::Room(room.id) {
+                        *target_lock = MessageTarget::Room(GENERAL_ROOM_ID);
+                    }
+
+                    flush(&format!("Left room #{} {}", room.id, room.name));
+                }
+                ServerResponse::RoomList(room_list) => {
+                    for room in room_list.rooms {
+                        let joined = if room.joined { " (joined)" } else { "" };
+                        flush(&format!("#{} {}{}", room.id, room.name, joined));
+                    }
+                }
+                ServerResponse::OnlineUsers(online_users) => {
+                    flush(&format!("Online ({}):", online_users.users.len()));
+                    for user in online_users.users {
+                        flush(&format!("    {} (user {})", user.username, user.user_id));
+                    }
+                }
+                ServerResponse::Typing(typing_response) => {
+                    // A direct message is typed to this user, in the conversation with the typist
+                    let typing_target = match typing_response.target {
+                        MessageTarget::User(_) => MessageTarget::User(typing_response.user_id),
+                        room => room,
+                    };
+                    if typing_target != *target.lock().unwrap() {
+                        continue;
+                    }
+
+                    let now = Instant::now();
+                    // Only announced once while the user keeps typing
+                    if typing
+                        .get(&typing_response.user_id)
+                        .is_none_or(|until| *until <= now)
+                    {
+                        flush(&format!("{} is typing…", typing_response.username));
+                    }
+                    typing.insert(
+                        typing_response.user_id,
+                        now + Duration::from_secs(typing_response.expires_in),
+                    );
+                }
+                ServerResponse::ReadReceipt(receipt) => {
+                    flush(&format!(
+                        "{} has read up to message #{}",
+                        receipt.username, receipt.message_id
+                    ));
+                }
+                ServerResponse::Thread(thread) => {
+                    flush(&format!(
+                        "Thread of #{} by {}: {}",
+                        thread.root.id,
+                        thread.root.username,
+                        thread.root.content.snippet()
+                    ));
+                    for reply in thread.replies {
+                        flush(&format!(
+                            "    #{} {}: {}",
+                            reply.id,
+                            reply.username,
+                            reply.content.snippet()
+                        ));
+                    }
+                    if thread.has_more {
+                        flush("There are more replies in the thread");
+                    }
+                }
+                ServerResponse::Reaction(reaction) => {
+                    let verb = if reaction.added {
+                        "reacted"
+                    } else {
+                        "took back"
+                    };
+                    flush(&format!(
+                        "{} {} {} on message #{}",
+                        reaction.username, verb, reaction.emoji, reaction.message_id
+                    ));
+
+                    let mut messages_clone = messages_a.clone().deref().to_owned();
+                    if let Some(message) = messages_clone.get_mut(&reaction.message_id) {
+                        message.reactions = reaction.reactions;
+                    }
+                    messages_a.set(messages_clone);
+                }
+                ServerResponse::Mention(mention) => {
+                    flush(&format!(
+                        "{} mentioned you in message #{}: {}",
+                        mention.username,
+                        mention.id,
+                        mention.content.snippet()
+                    ));
+                }
+                ServerResponse::Mentions(mentions) => {
+                    if mentions.has_more {
+                        flush("There are older mentions");
+                    }
+                    for mention in mentions.messages {
+                        flush(&format!(
+                            "    #{} {}: {}",
+                            mention.id,
+                            mention.username,
+                            mention.content.snippet()
+                        ));
+                    }
+                }
+                ServerResponse::Search(search) => {
+                    flush(&format!(
+                        "Results for \"{}\" ({}):",
+                        search.query,
+                        search.results.len()
+                    ));
+                    for result in search.results {
+                        // The matched words are shown in brackets
+                        let snippet: String = result
+                            .snippet
+                            .iter()
+                            .map(|part| {
+                                if part.highlighted {
+                                    format!("[{}]", part.text)
+                                } else {
+                                    part.text.to_owned()
+                                }
+                            })
+                            .collect();
+                        flush(&format!(
+                            "    #{} {}: {}",
+                            result.message.id, result.message.username, snippet
+                        ));
+                    }
+                    if search.has_more {
+                        flush("There are more results");
+                    }
+                }
+                ServerResponse::Moderation(moderation) => {
+                    let action = match moderation.action {
+                        ModerationAction::Mute => "muted",
+                        ModerationAction::Unmute => "unmuted",
+                        ModerationAction::Ban => "banned",
+                        ModerationAction::Unban => "unbanned",
+                        ModerationAction::Kick => "kicked",
+                    };
+                    match moderation.muted_until {
+                        Some(muted_until) => flush(&format!(
+                            "{} has been {} until {}",
+                            moderation.username,
+                            action,
+                            muted_until.with_timezone(&Local).format("%Y-%m-%d %H:%M")
+                        )),
+                        None => flush(&format!("{} has been {}", moderation.username, action)),
+                    }
+                }
+                ServerResponse::UserOnline(user) => {
+                    flush(&format!("{} is online", user.username));
+                }
+                ServerResponse::UserOffline(user) => {
+                    flush(&format!("{} went offline", user.username));
+                }
+                ServerResponse::ShuttingDown(_) => {
+                    flush("The server is shutting down");
+                }
             }
         }
     });
@@ -111,6 +425,7 @@ fn app(props: &Props) -> Html {
     spawn_local(async move {
         loop {
             let jwt_lock = jwt_clone.lock().unwrap().clone();
+            let current_target = target_clone.lock().unwrap().clone();
             match &jwt_lock {
                 None => {
                     let auth_request_content = prompt_auth();
@@ -119,7 +434,7 @@ fn app(props: &Props) -> Html {
                         .map_err(|e| eprintln!("{}", e))
                         .ok();
                 }
-                Some(jwt_token) => {
+                Some(_) => {
                     flush("\nEnter message (Ctrl+D to send), send `.help` for possible commands:");
 
                     let mut input_bytes = vec![];
@@ -136,7 +451,7 @@ fn app(props: &Props) -> Html {
                         Some(".help") => print_help(),
                         Some(".image") => {
                             if let Some(path) = command.next() {
-                                handle_image(&stream, path, jwt_token.to_owned())
+                                handle_image(&stream, path, current_target)
                                     .map_err(|e| println!("{}", e))
                                     .ok();
                             } else {
@@ -145,7 +460,17 @@ fn app(props: &Props) -> Html {
                         }
                         Some(".file") => {
                             if let Some(path) = command.next() {
-                                handle_file(&stream, path, jwt_token.to_owned())
+                                let name = std::path::Path::new(path)
+                                    .file_name()
+                                    .and_then(|name| name.to_str())
+                                    .unwrap_or_default()
+                                    .to_string();
+                                uploads_clone
+                                    .lock()
+                                    .unwrap()
+                                    .insert(name, (path.to_string(), current_target));
+
+                                handle_file(&stream, path)
                                     .map_err(|e| println!("{}", e))
                                     .ok();
                             } else {
@@ -158,8 +483,11 @@ fn app(props: &Props) -> Html {
                                     serialize_and_write(
                                         &stream,
                                         read_request(ReadRequest {
-                                            jwt: jwt_token.to_owned(),
+                                            target: current_target,
                                             amount,
+                                            before_id: None,
+                                            before: None,
+                                            after: None,
                                         }),
                                     )
                                     .map_err(|e| eprintln!("{}", e))
@@ -171,11 +499,254 @@ fn app(props: &Props) -> Html {
                                 eprintln!("No amount provided");
                             }
                         }
+                        Some(".rooms") => {
+                            serialize_and_write(&stream, list_rooms_request(ListRoomsRequest {}))
+                                .map_err(|e| eprintln!("{}", e))
+                                .ok();
+                        }
+                        Some(".mentions") => {
+                            serialize_and_write(
+                                &stream,
+                                list_mentions_request(ListMentionsRequest {
+                                    amount: MENTIONS_AMOUNT,
+                                    before_id: None,
+                                }),
+                            )
+                            .map_err(|e| eprintln!("{}", e))
+                            .ok();
+                        }
+                        Some(".search") => match command.next() {
+                            Some(query) if !query.trim().is_empty() => {
+                                serialize_and_write(
+                                    &stream,
+                                    search_request(SearchRequest {
+                                        query: query.to_string(),
+                                        user_id: None,
+                                        amount: SEARCH_AMOUNT,
+                                        offset: 0,
+                                    }),
+                                )
+                                .map_err(|e| eprintln!("{}", e))
+                                .ok();
+                            }
+                            _ => eprintln!("No words to search for provided"),
+                        },
+                        Some(".online") => {
+                            serialize_and_write(
+                                &stream,
+                                list_online_users_request(ListOnlineUsersRequest {}),
+                            )
+                            .map_err(|e| eprintln!("{}", e))
+                            .ok();
+                        }
+                        Some(".create") => {
+                            if let Some(name) = command.next() {
+                                serialize_and_write(
+                                    &stream,
+                                    create_room_request(CreateRoomRequest {
+                                        name: name.to_string(),
+                                    }),
+                                )
+                                .map_err(|e| eprintln!("{}", e))
+                                .ok();
+                            } else {
+                                eprintln!("No room name provided");
+                            }
+                        }
+                        Some(command_name @ (".join" | ".leave")) => {
+                            if let Some(Ok(target_room_id)) =
+                                command.next().map(|id| id.parse::<i32>())
+                            {
+                                let room_request = RoomRequest {
+                                    room_id: target_room_id,
+                                };
+                                let request = if command_name == ".join" {
+                                    join_room_request(room_request)
+                                } else {
+                                    leave_room_request(room_request)
+                                };
+                                serialize_and_write(&stream, request)
+                                    .map_err(|e| eprintln!("{}", e))
+                                    .ok();
+                            } else {
+                                eprintln!("Invalid room id provided");
+                            }
+                        }
+                        Some(".dm") => {
+                            if let Some(Ok(user_id)) = command.next().map(|id| id.parse::<i32>()) {
+                                let mut target_lock = target_clone.lock().unwrap();
+                                *target_lock = MessageTarget::User(user_id);
+
+                                flush(&format!("Now messaging user #{}", user_id));
+                            } else {
+                                eprintln!("Invalid user id provided");
+                            }
+                        }
+                        Some(".edit") => {
+                            let mut edit_args = command.next().unwrap_or_default().splitn(2, ' ');
+                            match (
+                                edit_args.next().map(|id| id.parse::<i32>()),
+                                edit_args.next(),
+                            ) {
+                                (Some(Ok(message_id)), Some(new_text)) => {
+                                    serialize_and_write(
+                                        &stream,
+                                        edit_message_request(EditMessageRequest {
+                                            message_id,
+                                            message: text(new_text.to_string()),
+                                        }),
+                                    )
+                                    .map_err(|e| eprintln!("{}", e))
+                                    .ok();
+                                }
+                                (Some(Ok(_)), None) => eprintln!("No text provided"),
+                                _ => eprintln!("Invalid message id provided"),
+                            }
+                        }
+                        Some(".reply") => {
+                            let mut reply_args = command.next().unwrap_or_default().splitn(2, ' ');
+                            match (
+                                reply_args.next().map(|id| id.parse::<i32>()),
+                                reply_args.next(),
+                            ) {
+                                (Some(Ok(parent_id)), Some(reply_text)) => {
+                                    serialize_and_write(
+                                        &stream,
+                                        message_request(MessageRequest::reply(
+                                            current_target,
+                                            text(reply_text.to_string()),
+                                            parent_id,
+                                        )),
+                                    )
+                                    .map_err(|e| eprintln!("{}", e))
+                                    .ok();
+                                }
+                                (Some(Ok(_)), None) => eprintln!("No text provided"),
+                                _ => eprintln!("Invalid message id provided"),
+                            }
+                        }
+                        Some(".thread") => {
+                            if let Some(Ok(message_id)) = command.next().map(|id| id.parse::<i32>())
+                            {
+                                serialize_and_write(
+                                    &stream,
+                                    read_thread_request(ReadThreadRequest {
+                                        message_id,
+                                        amount: THREAD_AMOUNT,
+                                        after_id: None,
+                                    }),
+                                )
+                                .map_err(|e| eprintln!("{}", e))
+                                .ok();
+                            } else {
+                                eprintln!("Invalid message id provided");
+                            }
+                        }
+                        Some(command_name @ (".react" | ".unreact")) => {
+                            let mut reaction_args =
+                                command.next().unwrap_or_default().splitn(2, ' ');
+                            match (
+                                reaction_args.next().map(|id| id.parse::<i32>()),
+                                reaction_args.next(),
+                            ) {
+                                (Some(Ok(message_id)), Some(emoji)) => {
+                                    let reaction_request = ReactionRequest {
+                                        message_id,
+                                        emoji: emoji.trim().to_string(),
+                                    };
+                                    let request = if command_name == ".react" {
+                                        react_request(reaction_request)
+                                    } else {
+                                        unreact_request(reaction_request)
+                                    };
+                                    serialize_and_write(&stream, request)
+                                        .map_err(|e| eprintln!("{}", e))
+                                        .ok();
+                                }
+                                (Some(Ok(_)), None) => eprintln!("No emoji provided"),
+                                _ => eprintln!("Invalid message id provided"),
+                            }
+                        }
+                        Some(".fetch") => {
+                            if let Some(Ok(attachment_id)) =
+                                command.next().map(|id| id.parse::<i32>())
+                            {
+                                serialize_and_write(
+                                    &stream,
+                                    fetch_attachment_request(FetchAttachmentRequest {
+                                        attachment_id,
+                                    }),
+                                )
+                                .map_err(|e| eprintln!("{}", e))
+                                .ok();
+                            } else {
+                                eprintln!("Invalid attachment id provided");
+                            }
+                        }
+                        Some(".mute") => {
+                            let mut mute_args = command.next().unwrap_or_default().splitn(2, ' ');
+                            match (
+                                mute_args.next().map(|id| id.parse::<i32>()),
+                                mute_args
+                                    .next()
+                                    .map(|minutes| minutes.trim().parse::<u64>()),
+                            ) {
+                                (Some(Ok(user_id)), Some(Ok(minutes))) => {
+                                    serialize_and_write(
+                                        &stream,
+                                        mute_request(MuteRequest {
+                                            user_id,
+                                            duration: minutes * 60,
+                                        }),
+                                    )
+                                    .map_err(|e| eprintln!("{}", e))
+                                    .ok();
+                                }
+                                (Some(Ok(_)), _) => eprintln!("Invalid amount of minutes provided"),
+                                _ => eprintln!("Invalid user id provided"),
+                            }
+                        }
+                        Some(command_name @ (".unmute" | ".kick" | ".ban" | ".unban")) => {
+                            if let Some(Ok(user_id)) =
+                                command.next().map(|id| id.trim().parse::<i32>())
+                            {
+                                let user_request = UserRequest { user_id };
+                                let request = match command_name {
+                                    ".unmute" => unmute_request(user_request),
+                                    ".kick" => kick_request(user_request),
+                                    ".ban" => ban_request(user_request),
+                                    _ => unban_request(user_request),
+                                };
+                                serialize_and_write(&stream, request)
+                                    .map_err(|e| eprintln!("{}", e))
+                                    .ok();
+                            } else {
+                                eprintln!("Invalid user id provided");
+                            }
+                        }
+                        Some(".logout") => {
+                            serialize_and_write(&stream, logout_request(LogoutRequest {}))
+                                .map_err(|e| eprintln!("{}", e))
+                                .ok();
+                        }
+                        Some(".delete") => {
+                            if let Some(Ok(message_id)) = command.next().map(|id| id.parse::<i32>())
+                            {
+                                serialize_and_write(
+                                    &stream,
+                                    delete_message_request(DeleteMessageRequest { message_id }),
+                                )
+                                .map_err(|e| eprintln!("{}", e))
+                                .ok();
+                            } else {
+                                eprintln!("Invalid message id provided");
+                            }
+                        }
                         _ => {
                             serialize_and_write(
                                 &stream,
                                 message_request(MessageRequest::new(
-                                    jwt_token.to_owned(),
+                                    current_target,
                                     text(input_string),
                                 )),
                             )
@@ -200,7 +771,9 @@ fn app(props: &Props) -> Html {
                             messages.iter().map(|(_, message)| {
                                 html! {
                                     <div class="flex flex-col gap-2 items-center">
-                                        <p>{match &message.content {
+                                        <p>{if message.deleted_at.is_some() {
+                                            html!{<i>{"message deleted"}</i>}
+                                        } else { match &message.content {
                                             MessageContent::Text(text) => html!{text},
                                             MessageContent::Image(bytes) => {
                                                 html!{<img src={"data:image/png;base64,".to_owned() + &general_purpose::STANDARD.encode(bytes)} alt="img"/>}},
@@ -208,8 +781,12 @@ fn app(props: &Props) -> Html {
                                                 let mime_type = mime_guess::from_path(&filename).first_or_octet_stream();
                                                 let base64_encoded = &general_purpose::STANDARD.encode(file_bytes);
                                                 html!{<a href={format!("data:{};base64,{}", mime_type, base64_encoded)} download={filename.to_owned()}></a>}},
-                                        }}</p>
+                                            MessageContent::Attachment(info) => {
+                                                html!{<span>{format!("{} ({} bytes), download it with `.fetch {}`", info.name, info.size, info.id)}</span>}},
+                                        }}}</p>
+                                        <p>{message.reactions.iter().map(|reaction| format!("{} {}", reaction.emoji, reaction.count)).collect::<Vec<String>>().join(" ")}</p>
                                         <p>{&message.user.username}</p>
+                                        <p>{message.created_at.with_timezone(&Local).format("%Y-%m-%d %H:%M").to_string()}{if message.edited_at.is_some() { " (edited)" } else { "" }}</p>
                                     </div>
                                 }
                             }).collect::<Html>()
@@ -0,0 +1,567 @@
+use clap::{Parser, ValueEnum};
+use serde::{Deserialize, Serialize};
+use std::{
+    fs::read_to_string,
+    io::{Error, ErrorKind},
+    path::Path,
+};
+
+/// The config file read when `--config` isn't given, it's fine if it doesn't exist
+static DEFAULT_CONFIG_PATH: &str = "server.toml";
+
+/// Shown instead of the JWT secret by `--print-config`
+static REDACTED: &str = "<redacted>";
+
+/// The amount of seconds in a day
+static ONE_DAY: u64 = 24 * 60 * 60;
+
+/// How much the server logs, from the least to the most
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum LogLevel {
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+/// How the log lines are written
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// Readable lines for a terminal
+    Human,
+    /// A JSON object per line, for log collectors
+    Json,
+}
+
+/// The address the server listens on, and how it shuts down
+///
+/// # Fields
+/// * `hostname` - The hostname or IP to bind to
+/// * `port` - The port to bind to
+/// * `handshake_timeout` - How long (in seconds) a connection has to finish the WebSocket
+///   handshake before it's dropped
+/// * `shutdown_timeout` - How long (in seconds) the server waits for the requests it's handling
+///   and the pending responses when it shuts down
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    pub hostname: String,
+    pub port: u16,
+    pub handshake_timeout: u64,
+    pub shutdown_timeout: u64,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            hostname: "localhost".to_string(),
+            port: 11111,
+            handshake_timeout: 10,
+            shutdown_timeout: 10,
+        }
+    }
+}
+
+/// # Fields
+/// * `url` - The SQLite database, a file path or a `file:` URI
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DatabaseConfig {
+    pub url: String,
+}
+
+impl Default for DatabaseConfig {
+    fn default() -> Self {
+        DatabaseConfig {
+            url: "chat.db".to_string(),
+        }
+    }
+}
+
+/// # Fields
+/// * `path` - The directory of the attachment store, also holding the partial uploads
+/// * `upload_lifetime` - How long (in seconds) an upload can stay unfinished before it's deleted
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AttachmentsConfig {
+    pub path: String,
+    pub upload_lifetime: u64,
+}
+
+impl Default for AttachmentsConfig {
+    fn default() -> Self {
+        AttachmentsConfig {
+            path: "attachments".to_string(),
+            upload_lifetime: ONE_DAY,
+        }
+    }
+}
+
+/// The JWT keys and the lifetimes of the tokens, see `JwtKeys::load`
+///
+/// # Fields
+/// * `secret` - The current secret, instead of the first key of the keys file
+/// * `kid` - The key ID of `secret`
+/// * `secret_file` - The keys file, with one `<kid> <hex secret>` per line
+/// * `token_lifetime` - How long the tokens are valid (in seconds)
+/// * `refresh_token_lifetime` - How long the refresh tokens are valid (in seconds)
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct JwtConfig {
+    pub secret: Option<String>,
+    pub kid: String,
+    pub secret_file: String,
+    pub token_lifetime: u64,
+    pub refresh_token_lifetime: u64,
+}
+
+impl Default for JwtConfig {
+    fn default() -> Self {
+        JwtConfig {
+            secret: None,
+            kid: "env".to_string(),
+            secret_file: "jwt_keys".to_string(),
+            token_lifetime: ONE_DAY,
+            refresh_token_lifetime: 30 * ONE_DAY,
+        }
+    }
+}
+
+/// The sizes of the frames and the files clients send and receive (in bytes)
+///
+/// # Fields
+/// * `max_message_size` - The largest WebSocket message a client can send, including inline
+///   files and images
+/// * `max_upload_size` - The largest file a client can upload
+/// * `upload_chunk_size` - The size of the chunks clients upload, changing it breaks the uploads
+///   in progress
+/// * `attachment_chunk_size` - The size of the chunks attachments are sent in
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LimitsConfig {
+    pub max_message_size: usize,
+    pub max_upload_size: u64,
+    pub upload_chunk_size: u32,
+    pub attachment_chunk_size: usize,
+}
+
+impl Default for LimitsConfig {
+    fn default() -> Self {
+        LimitsConfig {
+            max_message_size: 16 * 1024 * 1024,
+            max_upload_size: 100 * 1024 * 1024,
+            upload_chunk_size: 64 * 1024,
+            attachment_chunk_size: 64 * 1024,
+        }
+    }
+}
+
+/// The message limits and the login backoff, see `RateLimits`
+///
+/// # Fields
+/// * `message_burst` - The messages a connection can send in a row
+/// * `message_rate` - How many more messages a connection can send each second
+/// * `user_message_burst` - The messages a user can send in a row, across their connections
+/// * `user_message_rate` - How many more messages a user can send each second
+/// * `login_free_attempts` - The failed logins of a username before the delays start
+/// * `login_ip_free_attempts` - The failed logins from an IP before the delays start
+/// * `login_backoff` - The first delay (in seconds), doubled by each next failure
+/// * `login_backoff_max` - The longest delay (in seconds)
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RateLimitsConfig {
+    pub message_burst: f64,
+    pub message_rate: f64,
+    pub user_message_burst: f64,
+    pub user_message_rate: f64,
+    pub login_free_attempts: u32,
+    pub login_ip_free_attempts: u32,
+    pub login_backoff: u64,
+    pub login_backoff_max: u64,
+}
+
+impl Default for RateLimitsConfig {
+    fn default() -> Self {
+        RateLimitsConfig {
+            message_burst: 10.0,
+            message_rate: 1.0,
+            user_message_burst: 20.0,
+            user_message_rate: 2.0,
+            login_free_attempts: 3,
+            login_ip_free_attempts: 10,
+            login_backoff: 1,
+            login_backoff_max: 300,
+        }
+    }
+}
+
+/// # Fields
+/// * `level` - The most detailed messages logged
+/// * `format` - How the log lines are written
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LogConfig {
+    pub level: LogLevel,
+    pub format: LogFormat,
+}
+
+impl Default for LogConfig {
+    fn default() -> Self {
+        LogConfig {
+            level: LogLevel::Info,
+            format: LogFormat::Human,
+        }
+    }
+}
+
+/// The configuration of the server, each section is a table of the config file
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub server: ServerConfig,
+    pub database: DatabaseConfig,
+    pub attachments: AttachmentsConfig,
+    pub jwt: JwtConfig,
+    pub limits: LimitsConfig,
+    pub rate_limits: RateLimitsConfig,
+    pub log: LogConfig,
+}
+
+/// Runs the chat server
+///
+/// The settings are read from the config file, then from the environment variables (all prefixed
+/// with `CHAT_`), then from the flags, each overriding the previous ones.
+#[derive(Parser, Debug)]
+#[command(version, about, long_about = None)]
+pub struct Args {
+    /// The TOML config file [default: server.toml, if it exists]
+    #[arg(long, env = "CHAT_SERVER_CONFIG")]
+    pub config: Option<String>,
+
+    /// Print the effective config (with the JWT secret redacted) and exit
+    #[arg(long)]
+    pub print_config: bool,
+
+    #[arg(long, env = "CHAT_SERVER_HOSTNAME")]
+    pub hostname: Option<String>,
+    #[arg(long, env = "CHAT_SERVER_PORT")]
+    pub port: Option<u16>,
+    /// In seconds
+    #[arg(long, env = "CHAT_HANDSHAKE_TIMEOUT")]
+    pub handshake_timeout: Option<u64>,
+    /// In seconds
+    #[arg(long, env = "CHAT_SHUTDOWN_TIMEOUT")]
+    pub shutdown_timeout: Option<u64>,
+
+    /// The SQLite database, a file path or a `file:` URI
+    #[arg(long, env = "CHAT_DATABASE_URL")]
+    pub database_url: Option<String>,
+    /// The directory of the attachment store
+    #[arg(long, env = "CHAT_ATTACHMENTS_PATH")]
+    pub attachments_path: Option<String>,
+    /// In seconds
+    #[arg(long, env = "CHAT_UPLOAD_LIFETIME")]
+    pub upload_lifetime: Option<u64>,
+
+    #[arg(long, env = "CHAT_JWT_SECRET", hide_env_values = true)]
+    pub jwt_secret: Option<String>,
+    #[arg(long, env = "CHAT_JWT_KID")]
+    pub jwt_kid: Option<String>,
+    #[arg(long, env = "CHAT_JWT_SECRET_FILE")]
+    pub jwt_secret_file: Option<String>,
+    /// In seconds
+    #[arg(long, env = "CHAT_JWT_TOKEN_LIFETIME")]
+    pub jwt_token_lifetime: Option<u64>,
+    /// In seconds
+    #[arg(long, env = "CHAT_JWT_REFRESH_TOKEN_LIFETIME")]
+    pub jwt_refresh_token_lifetime: Option<u64>,
+
+    /// In bytes
+    #[arg(long, env = "CHAT_MAX_MESSAGE_SIZE")]
+    pub max_message_size: Option<usize>,
+    /// In bytes
+    #[arg(long, env = "CHAT_MAX_UPLOAD_SIZE")]
+    pub max_upload_size: Option<u64>,
+    /// In bytes
+    #[arg(long, env = "CHAT_UPLOAD_CHUNK_SIZE")]
+    pub upload_chunk_size: Option<u32>,
+    /// In bytes
+    #[arg(long, env = "CHAT_ATTACHMENT_CHUNK_SIZE")]
+    pub attachment_chunk_size: Option<usize>,
+
+    #[arg(long, env = "CHAT_MESSAGE_BURST")]
+    pub message_burst: Option<f64>,
+    /// Messages per second
+    #[arg(long, env = "CHAT_MESSAGE_RATE")]
+    pub message_rate: Option<f64>,
+    #[arg(long, env = "CHAT_USER_MESSAGE_BURST")]
+    pub user_message_burst: Option<f64>,
+    /// Messages per second
+    #[arg(long, env = "CHAT_USER_MESSAGE_RATE")]
+    pub user_message_rate: Option<f64>,
+    #[arg(long, env = "CHAT_LOGIN_FREE_ATTEMPTS")]
+    pub login_free_attempts: Option<u32>,
+    #[arg(long, env = "CHAT_LOGIN_IP_FREE_ATTEMPTS")]
+    pub login_ip_free_attempts: Option<u32>,
+    /// In seconds
+    #[arg(long, env = "CHAT_LOGIN_BACKOFF")]
+    pub login_backoff: Option<u64>,
+    /// In seconds
+    #[arg(long, env = "CHAT_LOGIN_BACKOFF_MAX")]
+    pub login_backoff_max: Option<u64>,
+
+    #[arg(long, env = "CHAT_LOG_LEVEL")]
+    pub log_level: Option<LogLevel>,
+    #[arg(long, env = "CHAT_LOG_FORMAT")]
+    pub log_format: Option<LogFormat>,
+}
+
+/// Replaces the setting with the value of the flag (or environment variable), if it's given
+fn set<T>(setting: &mut T, value: Option<T>) {
+    if let Some(value) = value {
+        *setting = value;
+    }
+}
+
+impl Config {
+    /// Loads the config of the flags and environment variables the server was started with
+    pub fn load(args: Args) -> Result<Self, Error> {
+        let mut config = match &args.config {
+            Some(path) => Config::read(path)?,
+            None if Path::new(DEFAULT_CONFIG_PATH).exists() => Config::read(DEFAULT_CONFIG_PATH)?,
+            None => Config::default(),
+        };
+
+        // Clap has already taken each value from the flag, or else from its environment variable
+        set(&mut config.server.hostname, args.hostname);
+        set(&mut config.server.port, args.port);
+        set(&mut config.server.handshake_timeout, args.handshake_timeout);
+        set(&mut config.server.shutdown_timeout, args.shutdown_timeout);
+        set(&mut config.database.url, args.database_url);
+        set(&mut config.attachments.path, args.attachments_path);
+        set(
+            &mut config.attachments.upload_lifetime,
+            args.upload_lifetime,
+        );
+
+        set(&mut config.jwt.secret, args.jwt_secret.map(Some));
+        set(&mut config.jwt.kid, args.jwt_kid);
+        set(&mut config.jwt.secret_file, args.jwt_secret_file);
+        set(&mut config.jwt.token_lifetime, args.jwt_token_lifetime);
+        set(
+            &mut config.jwt.refresh_token_lifetime,
+            args.jwt_refresh_token_lifetime,
+        );
+
+        set(&mut config.limits.max_message_size, args.max_message_size);
+        set(&mut config.limits.max_upload_size, args.max_upload_size);
+        set(&mut config.limits.upload_chunk_size, args.upload_chunk_size);
+        set(
+            &mut config.limits.attachment_chunk_size,
+            args.attachment_chunk_size,
+        );
+
+        let rate_limits = &mut config.rate_limits;
+        set(&mut rate_limits.message_burst, args.message_burst);
+        set(&mut rate_limits.message_rate, args.message_rate);
+        set(&mut rate_limits.user_message_burst, args.user_message_burst);
+        set(&mut rate_limits.user_message_rate, args.user_message_rate);
+        set(
+            &mut rate_limits.login_free_attempts,
+            args.login_free_attempts,
+        );
+        set(
+            &mut rate_limits.login_ip_free_attempts,
+            args.login_ip_free_attempts,
+        );
+        set(&mut rate_limits.login_backoff, args.login_backoff);
+        set(&mut rate_limits.login_backoff_max, args.login_backoff_max);
+
+        set(&mut config.log.level, args.log_level);
+        set(&mut config.log.format, args.log_format);
+
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Reads the config file, the settings it leaves out keep their defaults
+    fn read(path: &str) -> Result<Self, Error> {
+        let contents = read_to_string(path).map_err(|e| {
+            Error::new(
+                e.kind(),
+                format!("Failed to read config file {}: {}", path, e),
+            )
+        })?;
+        toml::from_str(&contents).map_err(|e| {
+            Error::new(
+                ErrorKind::InvalidData,
+                format!("Invalid config file {}: {}", path, e),
+            )
+        })
+    }
+
+    /// Checks the settings that would only fail once the server is running
+    fn validate(&self) -> Result<(), Error> {
+        let invalid = |message: &str| Err(Error::new(ErrorKind::InvalidInput, message));
+
+        let rates = [
+            self.rate_limits.message_burst,
+            self.rate_limits.message_rate,
+            self.rate_limits.user_message_burst,
+            self.rate_limits.user_message_rate,
+        ];
+        if rates.iter().any(|rate| rate.is_nan() || *rate <= 0.0) {
+            return invalid("The message bursts and rates must be above zero");
+        }
+        if self.limits.upload_chunk_size == 0 || self.limits.attachment_chunk_size == 0 {
+            return invalid("The chunk sizes must be above zero");
+        }
+        // An upload chunk has to fit into a message, with the header of the request
+        if self.limits.max_message_size <= self.limits.upload_chunk_size as usize {
+            return invalid("max_message_size must be larger than upload_chunk_size");
+        }
+        // Every connection would be dropped before it could finish the handshake
+        if self.server.handshake_timeout == 0 {
+            return invalid("handshake_timeout must be above zero");
+        }
+        // Every upload would be deleted while it's still running
+        if self.attachments.upload_lifetime == 0 {
+            return invalid("upload_lifetime must be above zero");
+        }
+        if self.jwt.secret.as_deref() == Some("") {
+            return invalid("The JWT secret can't be empty");
+        }
+
+        Ok(())
+    }
+
+    /// The address to listen on
+    pub fn address(&self) -> String {
+        format!("{}:{}", self.server.hostname, self.server.port)
+    }
+
+    /// Returns the config as TOML, with the JWT secret redacted
+    pub fn to_toml(&self) -> String {
+        let mut config = self.clone();
+        if config.jwt.secret.is_some() {
+            config.jwt.secret = Some(REDACTED.to_string());
+        }
+        toml::to_string_pretty(&config).expect("The config is always serializable")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{env, fs, sync::Mutex};
+
+    /// Held while the arguments are parsed, clap reads the `CHAT_` variables of the whole process
+    /// and the tests run in parallel
+    static ENV_LOCK: Mutex<()> = Mutex::new(());
+
+    /// Writes the config file of a test, named after the test so they can run in parallel
+    fn config_file(name: &str, contents: &str) -> String {
+        let path =
+            env::temp_dir().join(format!("chat-config-{}-{}.toml", name, std::process::id()));
+        fs::write(&path, contents).unwrap();
+        path.to_string_lossy().into_owned()
+    }
+
+    fn load(args: &[&str]) -> Result<Config, Error> {
+        load_with_env(args, &[])
+    }
+
+    /// Loads the config of the flags with the environment variables set while they're parsed
+    fn load_with_env(args: &[&str], vars: &[(&str, &str)]) -> Result<Config, Error> {
+        let _guard = ENV_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        for (key, value) in vars {
+            env::set_var(key, value);
+        }
+        let args = Args::parse_from([&["server"], args].concat());
+        for (key, _) in vars {
+            env::remove_var(key);
+        }
+        Config::load(args)
+    }
+
+    #[test]
+    fn defaults_are_valid() {
+        assert!(Config::default().validate().is_ok());
+    }
+
+    #[test]
+    fn flags_override_the_config_file() {
+        let path = config_file(
+            "override",
+            "[server]\nport = 1234\nhostname = \"0.0.0.0\"\n[limits]\nmax_upload_size = 10\n",
+        );
+        let config = load(&["--config", &path, "--port", "4321"]).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(config.server.port, 4321);
+        assert_eq!(config.server.hostname, "0.0.0.0");
+        assert_eq!(config.limits.max_upload_size, 10);
+        // Left out of the file, so still the default
+        assert_eq!(config.limits.upload_chunk_size, 64 * 1024);
+    }
+
+    #[test]
+    fn environment_overrides_the_config_file_and_flags_override_the_environment() {
+        let path = config_file("env", "[jwt]\nkid = \"from-file\"\ntoken_lifetime = 30\n");
+        let config = load_with_env(
+            &["--config", &path, "--jwt-token-lifetime", "90"],
+            &[
+                ("CHAT_JWT_KID", "from-env"),
+                ("CHAT_JWT_TOKEN_LIFETIME", "60"),
+            ],
+        );
+        fs::remove_file(&path).unwrap();
+
+        let config = config.unwrap();
+        assert_eq!(config.jwt.kid, "from-env");
+        assert_eq!(config.jwt.token_lifetime, 90);
+    }
+
+    #[test]
+    fn unknown_settings_and_missing_files_are_errors() {
+        let path = config_file("unknown", "[server]\nprot = 1\n");
+        let error = load(&["--config", &path]).unwrap_err();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(error.kind(), ErrorKind::InvalidData);
+
+        let error = load(&["--config", "/nonexistent/server.toml"]).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn validate_rejects_settings_that_would_fail_at_runtime() {
+        let invalid = |change: fn(&mut Config)| {
+            let mut config = Config::default();
+            change(&mut config);
+            config.validate().unwrap_err().kind() == ErrorKind::InvalidInput
+        };
+        assert!(invalid(|config| config.rate_limits.message_rate = 0.0));
+        assert!(invalid(
+            |config| config.rate_limits.user_message_burst = f64::NAN
+        ));
+        assert!(invalid(|config| config.limits.attachment_chunk_size = 0));
+        assert!(invalid(|config| {
+            config.limits.max_message_size = config.limits.upload_chunk_size as usize
+        }));
+        assert!(invalid(|config| config.server.handshake_timeout = 0));
+        assert!(invalid(|config| config.attachments.upload_lifetime = 0));
+        assert!(invalid(|config| config.jwt.secret = Some(String::new())));
+    }
+
+    #[test]
+    fn printed_config_redacts_the_secret() {
+        let mut config = Config::default();
+        config.jwt.secret = Some("hunter2".to_string());
+        let toml = config.to_toml();
+        assert!(!toml.contains("hunter2"));
+        assert!(toml.contains(REDACTED));
+    }
+}
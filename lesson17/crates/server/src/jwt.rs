@@ -0,0 +1,365 @@
+use crate::config::JwtConfig;
+use jsonwebtoken::{
+    decode, decode_header, encode, errors::ErrorKind, get_current_timestamp, Algorithm,
+    DecodingKey, EncodingKey, Header, Validation,
+};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{
+    fs::{read_to_string, OpenOptions},
+    io::{Error, ErrorKind as IoErrorKind, Write},
+    path::Path,
+};
+use tracing::info;
+
+#[cfg(unix)]
+use std::os::unix::fs::OpenOptionsExt;
+
+/// A secret tokens are signed with
+///
+/// # Fields
+/// * `kid` - The key ID, written into the `kid` header of the signed tokens
+/// * `secret` - The secret itself
+pub struct JwtKey {
+    pub kid: String,
+    secret: Vec<u8>,
+}
+
+impl JwtKey {
+    pub fn new(kid: String, secret: Vec<u8>) -> Self {
+        JwtKey { kid, secret }
+    }
+    /// Generates a random 32-byte key, using the current timestamp as its ID
+    pub fn generate() -> Self {
+        let mut secret = vec![0u8; 32];
+        rand::thread_rng().fill(&mut secret[..]);
+        JwtKey::new(format!("{:x}", get_current_timestamp()), secret)
+    }
+}
+
+/// The keys tokens are signed and validated with
+///
+/// # Fields
+/// * `current` - The key new tokens are signed with
+/// * `previous` - Retired keys, the tokens signed with them stay valid until they expire
+/// * `token_lifetime` - How long the new tokens are valid (in seconds)
+/// * `refresh_token_lifetime` - How long the sessions can be refreshed without logging in again (in seconds)
+pub struct JwtKeys {
+    pub current: JwtKey,
+    pub previous: Vec<JwtKey>,
+    pub token_lifetime: u64,
+    pub refresh_token_lifetime: u64,
+}
+
+impl JwtKeys {
+    /// Loads the keys of the config
+    ///
+    /// * `secret` - The current secret, `kid` is its ID. The keys from the keys file are still
+    ///   accepted as previous keys.
+    /// * `secret_file` - The keys file, with one `<kid> <hex secret>` per line. The first line is
+    ///   the current key (unless there's a `secret`), the rest are the previous keys, so a key is
+    ///   rotated by adding a new first line. The file is generated if it doesn't exist.
+    pub fn load(config: &JwtConfig) -> Result<Self, Error> {
+        let path = &config.secret_file;
+        let token_lifetime = config.token_lifetime;
+        let refresh_token_lifetime = config.refresh_token_lifetime;
+
+        let keys = match &config.secret {
+            Some(secret) => {
+                let previous = if Path::new(path).exists() {
+                    read_keys(path)?
+                } else {
+                    vec![]
+                };
+                JwtKeys {
+                    current: JwtKey::new(config.kid.clone(), secret.clone().into_bytes()),
+                    previous,
+                    token_lifetime,
+                    refresh_token_lifetime,
+                }
+            }
+            None => {
+                if !Path::new(path).exists() {
+                    let key = JwtKey::generate();
+                    write_new_keys_file(
+                        path,
+                        &format!("{} {}\n", key.kid, encode_hex(&key.secret)),
+                    )?;
+                    info!(path, "Generated a new JWT secret");
+                }
+                let mut keys = read_keys(path)?.into_iter();
+                let current = keys.next().ok_or(Error::new(
+                    IoErrorKind::InvalidData,
+                    format!("{} doesn't contain any key", path),
+                ))?;
+                JwtKeys {
+                    current,
+                    previous: keys.collect(),
+                    token_lifetime,
+                    refresh_token_lifetime,
+                }
+            }
+        };
+
+        Ok(keys)
+    }
+    /// Finds the key with the given ID
+    pub fn find(&self, kid: &str) -> Option<&JwtKey> {
+        std::iter::once(&self.current)
+            .chain(self.previous.iter())
+            .find(|key| key.kid == kid)
+    }
+}
+
+/// Creates the keys file with the given content, failing if it already exists
+///
+/// On Unix only the owner can read it, the secrets sign every token of the server.
+fn write_new_keys_file(path: &str, content: &str) -> Result<(), Error> {
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    options.mode(0o600);
+
+    options.open(path)?.write_all(content.as_bytes())
+}
+
+/// Reads the `<kid> <hex secret>` lines of the keys file, skipping empty lines and `#` comments
+fn read_keys(path: &str) -> Result<Vec<JwtKey>, Error> {
+    read_to_string(path)?
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| {
+            line.split_once(char::is_whitespace)
+                .and_then(|(kid, secret)| {
+                    Some(JwtKey::new(kid.to_string(), decode_hex(secret.trim())?))
+                })
+                .ok_or(Error::new(
+                    IoErrorKind::InvalidData,
+                    format!("Invalid key in {}: expected `<kid> <hex secret>`", path),
+                ))
+        })
+        .collect()
+}
+
+fn encode_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// Generates a random opaque refresh token
+pub fn generate_refresh_token() -> String {
+    let mut token = [0u8; 32];
+    rand::thread_rng().fill(&mut token);
+    encode_hex(&token)
+}
+
+/// Hashes the refresh token, only the hashes are stored in the database
+pub fn hash_refresh_token(refresh_token: &str) -> String {
+    format!("{:x}", Sha256::digest(refresh_token.as_bytes()))
+}
+
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    // `from_str_radix` would also take a sign, e.g. `+f`
+    if !hex.len().is_multiple_of(2) || !hex.bytes().all(|byte| byte.is_ascii_hexdigit()) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+/// The claims of the JWT token
+///
+/// # Fields
+/// * `sub` - User ID
+/// * `sid` - Session ID, the token is rejected once the session is revoked
+/// * `exp` - Expiration time (in seconds from UNIX epoch)
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Claims {
+    /// User ID
+    pub sub: i32,
+    pub sid: i32,
+    pub exp: u64,
+}
+
+impl Claims {
+    pub fn new(user_id: i32, session_id: i32, exp: u64) -> Self {
+        Claims {
+            sub: user_id,
+            sid: session_id,
+            exp,
+        }
+    }
+    /// Creates claims for the session, expiring after the configured token lifetime
+    ///
+    /// # Arguments
+    /// * `user_id` - The user the token is for
+    /// * `session_id` - The session the token belongs to
+    /// * `keys` - The keys holding the token lifetime
+    pub fn for_session(user_id: i32, session_id: i32, keys: &JwtKeys) -> Self {
+        Claims::new(
+            user_id,
+            session_id,
+            get_current_timestamp() + keys.token_lifetime,
+        )
+    }
+    /// Decodes the token and returns the claims
+    ///
+    /// The token is validated with the key its `kid` header points to, so tokens signed with
+    /// a previous key stay valid after a rotation.
+    ///
+    /// # Arguments
+    /// * `token` - The token to decode
+    /// * `keys` - The current and previous keys to decode the token with
+    pub fn from_token(token: &str, keys: &JwtKeys) -> Result<Self, jsonwebtoken::errors::Error> {
+        let header = decode_header(token)?;
+        // Every token the server signs names its key, one without a `kid` isn't ours
+        let key = header
+            .kid
+            .as_deref()
+            .and_then(|kid| keys.find(kid))
+            .ok_or(ErrorKind::InvalidSignature)?;
+        let validation = Validation::new(Algorithm::HS256);
+        let token_data =
+            decode::<Claims>(token, &DecodingKey::from_secret(&key.secret), &validation)?;
+        Ok(token_data.claims)
+    }
+    /// Encodes the claims into a token, signed with the current key
+    ///
+    /// # Arguments
+    /// * `keys` - The keys to encode the token with
+    pub fn get_token(&self, keys: &JwtKeys) -> Result<String, jsonwebtoken::errors::Error> {
+        let mut header = Header::new(Algorithm::HS256);
+        header.kid = Some(keys.current.kid.clone());
+        let token = encode(
+            &header,
+            &self,
+            &EncodingKey::from_secret(&keys.current.secret),
+        )?;
+        Ok(token)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{env, fs};
+
+    /// Returns a path for the keys file of a test, named after the test so they can run in
+    /// parallel
+    fn keys_path(name: &str) -> String {
+        let path = env::temp_dir().join(format!("chat-jwt-keys-{}-{}", name, std::process::id()));
+        let _ = fs::remove_file(&path);
+        path.to_string_lossy().into_owned()
+    }
+
+    fn config(secret_file: &str, secret: Option<&str>) -> JwtConfig {
+        JwtConfig {
+            secret: secret.map(str::to_string),
+            secret_file: secret_file.to_string(),
+            ..JwtConfig::default()
+        }
+    }
+
+    #[test]
+    fn hex_round_trips() {
+        let bytes = [0x00, 0x0f, 0xa5, 0xff];
+        assert_eq!(encode_hex(&bytes), "000fa5ff");
+        assert_eq!(decode_hex("000fa5ff").as_deref(), Some(&bytes[..]));
+        assert_eq!(decode_hex("000FA5FF").as_deref(), Some(&bytes[..]));
+        assert_eq!(decode_hex("").as_deref(), Some(&[][..]));
+    }
+
+    #[test]
+    fn invalid_hex_is_rejected() {
+        assert_eq!(decode_hex("abc"), None);
+        assert_eq!(decode_hex("zz"), None);
+        assert_eq!(decode_hex("+1"), None);
+        // Two bytes, but a single character
+        assert_eq!(decode_hex("é"), None);
+    }
+
+    #[test]
+    fn keys_file_is_parsed_in_order_skipping_comments() {
+        let path = keys_path("parse");
+        fs::write(&path, "# rotated in May\n new 0102 \n\nold\t0304\n").unwrap();
+        let keys = read_keys(&path);
+        fs::remove_file(&path).unwrap();
+
+        let keys = keys.unwrap();
+        let keys: Vec<(&str, &[u8])> = keys
+            .iter()
+            .map(|key| (key.kid.as_str(), &key.secret[..]))
+            .collect();
+        assert_eq!(keys, [("new", &[1, 2][..]), ("old", &[3, 4][..])]);
+    }
+
+    #[test]
+    fn invalid_keys_are_rejected() {
+        let path = keys_path("invalid");
+        for contents in ["lonely\n", "kid nothex\n", "kid 012\n"] {
+            fs::write(&path, contents).unwrap();
+            let error = read_keys(&path).err().unwrap();
+            assert_eq!(error.kind(), IoErrorKind::InvalidData, "{:?}", contents);
+        }
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn missing_keys_file_is_generated_once() {
+        let path = keys_path("generate");
+        let first = JwtKeys::load(&config(&path, None)).unwrap();
+        let second = JwtKeys::load(&config(&path, None)).unwrap();
+        #[cfg(unix)]
+        let mode = {
+            use std::os::unix::fs::PermissionsExt;
+            fs::metadata(&path).unwrap().permissions().mode() & 0o777
+        };
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(first.current.kid, second.current.kid);
+        assert_eq!(first.current.secret, second.current.secret);
+        assert_eq!(first.current.secret.len(), 32);
+        assert!(second.previous.is_empty());
+        #[cfg(unix)]
+        assert_eq!(mode, 0o600);
+    }
+
+    #[test]
+    fn secret_signs_and_the_file_keys_still_validate() {
+        let path = keys_path("secret");
+        fs::write(&path, "old 0102\n").unwrap();
+        let keys = JwtKeys::load(&config(&path, Some("hunter2")));
+        fs::remove_file(&path).unwrap();
+
+        let keys = keys.unwrap();
+        assert_eq!(keys.current.kid, "env");
+        assert_eq!(keys.current.secret, b"hunter2");
+        assert_eq!(
+            keys.find("old").map(|key| &key.secret[..]),
+            Some(&[1, 2][..])
+        );
+        assert_eq!(keys.find("env").map(|key| key.kid.as_str()), Some("env"));
+        assert!(keys.find("unknown").is_none());
+    }
+
+    #[test]
+    fn tokens_without_a_kid_are_rejected() {
+        let path = keys_path("no-kid");
+        let keys = JwtKeys::load(&config(&path, Some("hunter2"))).unwrap();
+        let claims = Claims::for_session(1, 1, &keys);
+        let signed = claims.get_token(&keys).unwrap();
+        let unnamed = encode(
+            &Header::new(Algorithm::HS256),
+            &claims,
+            &EncodingKey::from_secret(b"hunter2"),
+        )
+        .unwrap();
+        let _ = fs::remove_file(&path);
+
+        assert_eq!(Claims::from_token(&signed, &keys).unwrap().sub, 1);
+        assert!(Claims::from_token(&unnamed, &keys).is_err());
+    }
+}
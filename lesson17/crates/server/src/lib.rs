@@ -1,2 +1,7 @@
+mod config;
+mod jwt;
+mod logging;
+mod rate_limit;
 mod server;
-pub use server::*;
\ No newline at end of file
+pub use config::{Args, Config};
+pub use server::*;
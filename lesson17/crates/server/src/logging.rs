@@ -0,0 +1,39 @@
+use crate::config::{LogConfig, LogFormat, LogLevel};
+use std::{
+    io::{stdout, IsTerminal},
+    panic,
+};
+use tracing::{error, level_filters::LevelFilter};
+
+impl From<LogLevel> for LevelFilter {
+    fn from(level: LogLevel) -> Self {
+        match level {
+            LogLevel::Error => LevelFilter::ERROR,
+            LogLevel::Warn => LevelFilter::WARN,
+            LogLevel::Info => LevelFilter::INFO,
+            LogLevel::Debug => LevelFilter::DEBUG,
+            LogLevel::Trace => LevelFilter::TRACE,
+        }
+    }
+}
+
+/// Installs the subscriber writing the logs of the server and the database to stdout
+///
+/// Panics are logged too, in the span they happen in, instead of printed to stderr. Does nothing
+/// if a subscriber has already been installed.
+///
+/// # Arguments
+///
+/// * `config` - The level and the format of the logs
+pub fn init_logging(config: &LogConfig) {
+    let builder = tracing_subscriber::fmt()
+        .with_max_level(LevelFilter::from(config.level))
+        .with_ansi(stdout().is_terminal());
+    let installed = match config.format {
+        LogFormat::Human => builder.try_init(),
+        LogFormat::Json => builder.json().try_init(),
+    };
+    if installed.is_ok() {
+        panic::set_hook(Box::new(|info| error!(panic = %info, "Panicked")));
+    }
+}
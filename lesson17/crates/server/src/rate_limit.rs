@@ -0,0 +1,303 @@
+use crate::config::RateLimitsConfig;
+use std::{
+    borrow::Borrow,
+    collections::HashMap,
+    hash::Hash,
+    net::IpAddr,
+    time::{Duration, Instant},
+};
+
+/// The limits of a token bucket
+///
+/// # Fields
+/// * `burst` - The amount of tokens the bucket holds, i.e. how many requests pass in a row
+/// * `rate` - The amount of tokens refilled per second
+#[derive(Debug, Clone, Copy)]
+pub struct BucketLimit {
+    pub burst: f64,
+    pub rate: f64,
+}
+
+/// The limits of the login backoff
+///
+/// # Fields
+/// * `free_attempts` - The amount of failed logins of a username without a delay
+/// * `ip_free_attempts` - The amount of failed logins from an IP without a delay
+/// * `base_delay` - The delay after the first failure over the free attempts, doubled by each
+///   next one
+/// * `max_delay` - The longest delay, the failures are forgotten after twice as long without a
+///   new one
+#[derive(Debug, Clone, Copy)]
+pub struct BackoffLimit {
+    pub free_attempts: u32,
+    pub ip_free_attempts: u32,
+    pub base_delay: Duration,
+    pub max_delay: Duration,
+}
+
+/// The limits of the requests clients can flood the server with
+///
+/// # Fields
+/// * `connection_messages` - The messages of one connection
+/// * `user_messages` - The messages of one user, across all their connections
+/// * `login` - The delays after failed logins
+#[derive(Debug, Clone, Copy)]
+pub struct RateLimits {
+    pub connection_messages: BucketLimit,
+    pub user_messages: BucketLimit,
+    pub login: BackoffLimit,
+}
+
+impl RateLimits {
+    /// Creates the limits of the config, its rates have been checked to be above zero
+    pub fn new(config: &RateLimitsConfig) -> Self {
+        RateLimits {
+            connection_messages: BucketLimit {
+                burst: config.message_burst,
+                rate: config.message_rate,
+            },
+            user_messages: BucketLimit {
+                burst: config.user_message_burst,
+                rate: config.user_message_rate,
+            },
+            login: BackoffLimit {
+                free_attempts: config.login_free_attempts,
+                ip_free_attempts: config.login_ip_free_attempts,
+                base_delay: Duration::from_secs(config.login_backoff),
+                max_delay: Duration::from_secs(config.login_backoff_max),
+            },
+        }
+    }
+}
+
+/// A token bucket, every request takes a token and the tokens refill at a steady rate
+///
+/// # Fields
+/// * `limit` - The size and the refill rate of the bucket
+/// * `tokens` - The tokens left, as of `refilled_at`
+/// * `refilled_at` - When the tokens were last refilled
+#[derive(Debug)]
+pub struct TokenBucket {
+    limit: BucketLimit,
+    tokens: f64,
+    refilled_at: Instant,
+}
+
+impl TokenBucket {
+    /// Creates a full bucket
+    pub fn new(limit: BucketLimit) -> Self {
+        TokenBucket {
+            limit,
+            tokens: limit.burst,
+            refilled_at: Instant::now(),
+        }
+    }
+    /// Returns how long until the bucket has a token, `None` if it has one now
+    pub fn wait_time(&mut self, now: Instant) -> Option<Duration> {
+        let elapsed = now
+            .saturating_duration_since(self.refilled_at)
+            .as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.limit.rate).min(self.limit.burst);
+        self.refilled_at = now;
+
+        let missing = 1.0 - self.tokens;
+        (missing > 0.0).then(|| Duration::from_secs_f64(missing / self.limit.rate))
+    }
+    /// Takes a token, after `wait_time` has said there is one
+    pub fn take(&mut self) {
+        self.tokens -= 1.0;
+    }
+}
+
+/// The failed logins of a username or an IP
+///
+/// # Fields
+/// * `failures` - The amount of failures in a row
+/// * `blocked_until` - When the next attempt is allowed
+/// * `failed_at` - When the last failure was
+#[derive(Debug)]
+struct Backoff {
+    failures: u32,
+    blocked_until: Instant,
+    failed_at: Instant,
+}
+
+/// Failed logins by key, each failure over the free attempts doubles the delay until the next
+/// attempt
+#[derive(Debug)]
+struct BackoffMap<K> {
+    free_attempts: u32,
+    entries: HashMap<K, Backoff>,
+}
+
+impl<K: Eq + Hash> BackoffMap<K> {
+    fn new(free_attempts: u32) -> Self {
+        BackoffMap {
+            free_attempts,
+            entries: HashMap::new(),
+        }
+    }
+    /// Returns how long until the key can attempt again, `None` if it can now
+    fn wait_time<Q>(&self, key: &Q, now: Instant) -> Option<Duration>
+    where
+        K: Borrow<Q>,
+        Q: Eq + Hash + ?Sized,
+    {
+        self.entries
+            .get(key)
+            .map(|backoff| backoff.blocked_until.saturating_duration_since(now))
+            .filter(|wait| !wait.is_zero())
+    }
+    fn record_failure(&mut self, key: K, now: Instant, limit: &BackoffLimit) {
+        // Failures are forgotten after twice the longest delay without a new one, so retrying
+        // right after the longest delay keeps it
+        self.entries
+            .retain(|_, backoff| now.duration_since(backoff.failed_at) < limit.max_delay * 2);
+
+        let backoff = self.entries.entry(key).or_insert(Backoff {
+            failures: 0,
+            blocked_until: now,
+            failed_at: now,
+        });
+        backoff.failures += 1;
+        backoff.failed_at = now;
+        if let Some(exponent) = backoff.failures.checked_sub(self.free_attempts + 1) {
+            let delay = limit
+                .base_delay
+                .checked_mul(2u32.saturating_pow(exponent))
+                .map_or(limit.max_delay, |delay| delay.min(limit.max_delay));
+            backoff.blocked_until = now + delay;
+        }
+    }
+    fn reset<Q>(&mut self, key: &Q)
+    where
+        K: Borrow<Q>,
+        Q: Eq + Hash + ?Sized,
+    {
+        self.entries.remove(key);
+    }
+}
+
+/// The failed logins by username and by IP
+///
+/// A username is blocked to stop guessing its password, an IP to stop guessing the passwords of
+/// many usernames. Only a successful login resets its username, the IP is reset by time, so
+/// logging into an own account doesn't let an attacker guess on.
+#[derive(Debug)]
+pub struct LoginThrottle {
+    limit: BackoffLimit,
+    usernames: BackoffMap<String>,
+    ips: BackoffMap<IpAddr>,
+}
+
+impl LoginThrottle {
+    pub fn new(limit: BackoffLimit) -> Self {
+        LoginThrottle {
+            limit,
+            usernames: BackoffMap::new(limit.free_attempts),
+            ips: BackoffMap::new(limit.ip_free_attempts),
+        }
+    }
+    /// Returns how long until the username can log in from the IP, `None` if it can now
+    pub fn wait_time(&self, username: &str, ip: IpAddr) -> Option<Duration> {
+        let now = Instant::now();
+        let username_wait = self.usernames.wait_time(username, now);
+        let ip_wait = self.ips.wait_time(&ip, now);
+        username_wait.max(ip_wait)
+    }
+    /// Records a failed login, delaying the next attempts once the free ones are used up
+    pub fn record_failure(&mut self, username: &str, ip: IpAddr) {
+        let now = Instant::now();
+        self.usernames
+            .record_failure(username.to_string(), now, &self.limit);
+        self.ips.record_failure(ip, now, &self.limit);
+    }
+    /// Forgets the failed logins of the username after it has logged in
+    pub fn record_success(&mut self, username: &str) {
+        self.usernames.reset(username);
+    }
+}
+
+/// Rounds the wait time up to whole seconds, so retrying after it never comes too early
+pub fn retry_after(wait: Duration) -> u64 {
+    wait.as_secs() + u64::from(wait.subsec_nanos() > 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LIMIT: BackoffLimit = BackoffLimit {
+        free_attempts: 2,
+        ip_free_attempts: 2,
+        base_delay: Duration::from_secs(1),
+        max_delay: Duration::from_secs(4),
+    };
+
+    #[test]
+    fn token_bucket_lets_a_burst_through_then_refills() {
+        let mut bucket = TokenBucket::new(BucketLimit {
+            burst: 2.0,
+            rate: 0.5,
+        });
+        let start = Instant::now();
+        for _ in 0..2 {
+            assert_eq!(bucket.wait_time(start), None);
+            bucket.take();
+        }
+        assert_eq!(bucket.wait_time(start), Some(Duration::from_secs(2)));
+        assert_eq!(
+            bucket.wait_time(start + Duration::from_secs(1)),
+            Some(Duration::from_secs(1))
+        );
+        assert_eq!(bucket.wait_time(start + Duration::from_secs(2)), None);
+    }
+
+    #[test]
+    fn token_bucket_never_holds_more_than_its_burst() {
+        let mut bucket = TokenBucket::new(BucketLimit {
+            burst: 1.0,
+            rate: 1.0,
+        });
+        let later = Instant::now() + Duration::from_secs(60);
+        assert_eq!(bucket.wait_time(later), None);
+        bucket.take();
+        assert!(bucket.wait_time(later).is_some());
+    }
+
+    #[test]
+    fn backoff_doubles_after_the_free_attempts_up_to_the_max() {
+        let mut map = BackoffMap::new(LIMIT.free_attempts);
+        let now = Instant::now();
+        let mut waits = vec![];
+        for _ in 0..6 {
+            map.record_failure("bob", now, &LIMIT);
+            waits.push(map.wait_time("bob", now).map(|wait| wait.as_secs()));
+        }
+        assert_eq!(waits, [None, None, Some(1), Some(2), Some(4), Some(4)]);
+        assert_eq!(map.wait_time("alice", now), None);
+        assert_eq!(map.wait_time("bob", now + LIMIT.max_delay), None);
+    }
+
+    #[test]
+    fn backoff_is_forgotten_after_a_reset_or_twice_the_max_delay() {
+        let mut map = BackoffMap::new(0);
+        let now = Instant::now();
+        map.record_failure("bob".to_string(), now, &LIMIT);
+        map.reset("bob");
+        assert_eq!(map.wait_time("bob", now), None);
+
+        map.record_failure("bob".to_string(), now, &LIMIT);
+        map.record_failure("bob".to_string(), now, &LIMIT);
+        let later = now + LIMIT.max_delay * 2;
+        map.record_failure("bob".to_string(), later, &LIMIT);
+        assert_eq!(map.wait_time("bob", later), Some(LIMIT.base_delay));
+    }
+
+    #[test]
+    fn retry_after_rounds_up() {
+        assert_eq!(retry_after(Duration::ZERO), 0);
+        assert_eq!(retry_after(Duration::from_secs(2)), 2);
+        assert_eq!(retry_after(Duration::from_millis(2001)), 3);
+    }
+}
@@ -1,208 +1,922 @@
+use crate::config::{Config, LimitsConfig};
+use crate::jwt::{generate_refresh_token, hash_refresh_token, Claims, JwtKeys};
+use crate::logging::init_logging;
+use crate::rate_limit::{retry_after, BucketLimit, LoginThrottle, RateLimits, TokenBucket};
 use futures_util::stream::{SplitSink, SplitStream};
-use jsonwebtoken::{
-    decode, encode, get_current_timestamp, Algorithm, DecodingKey, EncodingKey, Header, Validation,
+use jsonwebtoken::get_current_timestamp;
+use std::io::{Error, Read};
+use std::{
+    collections::{HashMap, HashSet},
+    future::pending,
+    io::ErrorKind,
+    net::{IpAddr, SocketAddr},
+    panic::AssertUnwindSafe,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, LazyLock,
+    },
+    time::{Duration, Instant},
 };
-use rand::Rng;
-use serde::{Deserialize, Serialize};
-use std::io::Error;
-use std::{collections::HashMap, io::ErrorKind, net::SocketAddr, sync::Arc};
-use tokio;
-use tokio::io::{self, AsyncReadExt, AsyncWriteExt};
-use tokio::net::tcp::{ReadHalf, WriteHalf};
 use tokio::net::{TcpListener, TcpStream};
 use tokio::sync::Mutex;
+use tokio::time::{sleep, timeout, timeout_at, Instant as TokioInstant};
 use tokio_tungstenite::WebSocketStream;
-use tokio_tungstenite::{accept_async, tungstenite::Message};
-use utils::db::structs::User;
+use tokio_tungstenite::{
+    accept_async_with_config,
+    tungstenite::{
+        protocol::{frame::coding::CloseCode, CloseFrame, WebSocketConfig},
+        Error as WSError, Message,
+    },
+};
+use tokio_util::{sync::CancellationToken, task::TaskTracker};
+use tracing::{debug, error, field, info, info_span, warn, Instrument, Span};
 
-use futures_util::{SinkExt, StreamExt};
-use utils::db::DB;
+use futures_util::{FutureExt, SinkExt, StreamExt};
+use utils::attachments::{guess_mime_type, image_name, AttachmentStore};
+use utils::db::{
+    structs::{Message as DBMessage, Upload, User as DBUser},
+    DB,
+};
 use utils::errors::{
-    handle_stream_error, invalid_credentials, invalid_token, username_used, StreamError,
+    attachment_not_allowed, cannot_moderate, deserialize_object_error, internal_error,
+    invalid_credentials, invalid_reaction, invalid_refresh_token, invalid_token,
+    invalid_upload_chunk, not_authenticated, not_message_author, not_moderator, not_room_member,
+    serialize_object_error, session_revoked, text_only_edit, token_expired,
+    upload_checksum_mismatch, upload_incomplete, upload_too_large, user_banned, username_used,
+    DBError, ServerError, StreamError,
+};
+use utils::{
+    attachment, attachment_chunk, attachment_info, auth, db_error, error, history, message,
+    message_deleted, message_edited, room_joined, room_left, room_list, server_error,
+    AttachmentChunkResponse, AttachmentInfo, Auth, AuthRequest, AuthRequestKind, CreateRoomRequest,
+    DeleteMessageRequest, EditMessageRequest, ErrorResponse, FetchAttachmentRequest,
+    HistoryResponse, MessageContent, MessageRequest, MessageResponse, MessageTarget, ReadRequest,
+    RoomListResponse, RoomRequest, RoomResponse, ServerResponse,
 };
 use utils::{
-    auth, db_error, error, message, server_error, Auth, AuthRequest, AuthRequestKind,
-    MessageRequest, MessageResponse, ServerResponse,
+    deserialize_stream, deserialize_stream_bincode, logged_out, mention, mentions, moderation,
+    online_users, reaction, read_receipt, resumed, search, shutting_down, thread, typing,
+    upload_progress, user_offline, user_online, BeginUploadRequest, Encoding, FinishUploadRequest,
+    ListMentionsRequest, MarkReadRequest, MentionsResponse, ModerationAction, ModerationResponse,
+    OnlineUsersResponse, ReactionCount, ReactionRequest, ReactionResponse, ReadReceiptResponse,
+    ReadThreadRequest, RefreshRequest, ResumeRequest, ResumedResponse, Role, SearchRequest,
+    SearchResponse, SearchResult, SnippetPart, StreamRequest, ThreadResponse, TypingRequest,
+    TypingResponse, UnreadCount, UploadChunkRequest, UploadProgressResponse, UserPresence,
+    UserRequest,
 };
-use utils::{deserialize_stream, StreamRequest};
 
-/// The amount of seconds in a minute
-static ONE_MINUTE: u64 = 60;
-/// The amount of seconds in an hour
-static ONE_HOUR: u64 = 60 * ONE_MINUTE;
-/// The amount of seconds in a day
-static ONE_DAY: u64 = 24 * ONE_HOUR;
+/// The id of the next request, unique across the connections
+static NEXT_REQUEST_ID: AtomicU64 = AtomicU64::new(1);
+/// The tasks spawned by `spawn_write_task`, awaited when the server shuts down
+static WRITE_TASKS: LazyLock<TaskTracker> = LazyLock::new(TaskTracker::new);
+/// The shortest interval between two typing indicators relayed from a user into one target
+static TYPING_THROTTLE: Duration = Duration::from_secs(2);
+/// How long (in seconds) a typing indicator lasts without being renewed
+static TYPING_TIMEOUT: u64 = 5;
+/// The longest mute (in seconds), a year, users who shouldn't talk anymore are banned instead
+static MAX_MUTE_DURATION: u64 = 365 * 24 * 60 * 60;
+/// How long to wait before accepting again after accepting a connection failed, e.g. when the
+/// process is out of file descriptors
+static ACCEPT_RETRY_DELAY: Duration = Duration::from_millis(100);
+/// How often the uploads left unfinished for longer than their lifetime are deleted
+static UPLOAD_SWEEP_INTERVAL: Duration = Duration::from_secs(60 * 60);
 
-type WSWriter = SplitSink<WebSocketStream<TcpStream>, Message>;
+type WSSink = SplitSink<WebSocketStream<TcpStream>, Message>;
 type WSReader = SplitStream<WebSocketStream<TcpStream>>;
 
+/// The write half of a connection
+///
+/// # Fields
+/// * `sink` - The WebSocket sink
+/// * `encoding` - The encoding of the responses, negotiated by the first request of the client
+struct WSWriter {
+    sink: WSSink,
+    encoding: Option<Encoding>,
+}
+impl WSWriter {
+    pub fn new(sink: WSSink) -> Self {
+        WSWriter {
+            sink,
+            encoding: None,
+        }
+    }
+}
+
 /// The type of the client
 ///
 /// # Fields
 /// * `writer` - The writer half of the stream
-/// * `token` - The JWT token of the client
+/// * `claims` - The claims of the token the connection has authenticated with, `None` until it
+///   logs in and again after its token expires or its session ends
+/// * `messages` - The token bucket limiting the messages sent through the connection
 struct Client {
     writer: Arc<Mutex<WSWriter>>,
-    token: String,
+    claims: Option<Claims>,
+    messages: TokenBucket,
 }
 impl Client {
-    pub fn new(writer: Arc<Mutex<WSWriter>>, token: String) -> Self {
-        Client { writer, token }
+    pub fn new(writer: Arc<Mutex<WSWriter>>, message_limit: BucketLimit) -> Self {
+        Client {
+            writer,
+            claims: None,
+            messages: TokenBucket::new(message_limit),
+        }
     }
 }
 
-/// The claims of the JWT token
+/// A change of the users who are online, a user is online while they have at least one
+/// authenticated connection
+#[derive(Debug)]
+enum Presence {
+    Online(i32),
+    Offline(i32),
+}
+
+/// The registry of all open connections, indexable both by address and by user
+///
+/// The methods changing who is authenticated return the presence changes they caused, for the
+/// caller to broadcast.
 ///
 /// # Fields
-/// * `sub` - User ID
-/// * `exp` - Expiration time (in seconds from UNIX epoch)
-#[derive(Debug, Serialize, Deserialize)]
-struct Claims {
-    /// User ID
-    pub sub: i32,
-    pub exp: u64,
+/// * `connections` - The clients by their address
+/// * `users` - The addresses of each user's authenticated connections (a user can have several)
+/// * `user_messages` - The token buckets limiting the messages of each user, kept after they
+///   disconnect so reconnecting doesn't refill them
+/// * `typing` - When the last typing indicator of each online user was relayed, by target
+#[derive(Default)]
+struct Clients {
+    connections: HashMap<SocketAddr, Client>,
+    users: HashMap<i32, HashSet<SocketAddr>>,
+    user_messages: HashMap<i32, TokenBucket>,
+    typing: HashMap<(i32, MessageTarget), Instant>,
 }
+impl Clients {
+    /// Registers a new, not yet authenticated, connection
+    pub fn insert(&mut self, addr: SocketAddr, client: Client) {
+        self.connections.insert(addr, client);
+    }
+    /// Binds the connection to the user and session of the token it has authenticated with
+    pub fn authenticate(&mut self, addr: SocketAddr, claims: Claims) -> Vec<Presence> {
+        let mut changes = self.sign_out(addr);
+        if let Some(client) = self.connections.get_mut(&addr) {
+            let addrs = self.users.entry(claims.sub).or_default();
+            if addrs.is_empty() {
+                changes.push(Presence::Online(claims.sub));
+            }
+            addrs.insert(addr);
+            client.claims = Some(claims);
+        }
+        // Authenticating again as the only connection of the same user isn't a change
+        if let [Presence::Offline(old), Presence::Online(new)] = changes[..] {
+            if old == new {
+                changes.clear();
+            }
+        }
+        changes
+    }
+    /// Returns the claims the connection has authenticated with
+    pub fn claims(&self, addr: SocketAddr) -> Option<&Claims> {
+        self.connections
+            .get(&addr)
+            .and_then(|client| client.claims.as_ref())
+    }
+    /// Downgrades the connection to unauthenticated
+    pub fn sign_out(&mut self, addr: SocketAddr) -> Vec<Presence> {
+        let Some(claims) = self
+            .connections
+            .get_mut(&addr)
+            .and_then(|client| client.claims.take())
+        else {
+            return vec![];
+        };
+        if let Some(addrs) = self.users.get_mut(&claims.sub) {
+            addrs.remove(&addr);
+            if addrs.is_empty() {
+                self.users.remove(&claims.sub);
+                self.typing.retain(|(user_id, _), _| *user_id != claims.sub);
+                return vec![Presence::Offline(claims.sub)];
+            }
+        }
+        vec![]
+    }
+    /// Signs out the connection if its token has expired, returns `None` if it hasn't
+    pub fn expire(&mut self, addr: SocketAddr) -> Option<Vec<Presence>> {
+        let expired = self
+            .claims(addr)
+            .is_some_and(|claims| claims.exp <= get_current_timestamp());
+        expired.then(|| self.sign_out(addr))
+    }
+    /// Signs out every connection of the session, returns their writers
+    pub fn sign_out_session(
+        &mut self,
+        session_id: i32,
+    ) -> (Vec<Arc<Mutex<WSWriter>>>, Vec<Presence>) {
+        let addrs: Vec<SocketAddr> = self
+            .connections
+            .iter()
+            .filter(|(_, client)| {
+                client
+                    .claims
+                    .as_ref()
+                    .is_some_and(|claims| claims.sid == session_id)
+            })
+            .map(|(addr, _)| *addr)
+            .collect();
 
-impl Claims {
-    pub fn new(user_id: i32, exp: u64) -> Self {
-        Claims { sub: user_id, exp }
+        let mut writers = vec![];
+        let mut changes = vec![];
+        for addr in addrs {
+            changes.extend(self.sign_out(addr));
+            if let Some(client) = self.connections.get(&addr) {
+                writers.push(Arc::clone(&client.writer));
+            }
+        }
+        (writers, changes)
     }
-    /// Decodes the token and returns the claims
-    ///
-    /// # Arguments
-    /// * `token` - The token to decode
-    /// * `secret` - The secret to decode the token with
-    pub fn from_token(token: &str, secret: &[u8]) -> Result<Self, jsonwebtoken::errors::Error> {
-        let validation = Validation::new(Algorithm::HS256);
-        let token_data = decode::<Claims>(token, &DecodingKey::from_secret(secret), &validation)?;
-        Ok(token_data.claims)
-    }
-    /// Encodes the claims into a token
+    /// Signs out every connection of the user, returns their writers
+    pub fn sign_out_user(&mut self, user_id: i32) -> (Vec<Arc<Mutex<WSWriter>>>, Vec<Presence>) {
+        let addrs: Vec<SocketAddr> = self
+            .users
+            .get(&user_id)
+            .into_iter()
+            .flatten()
+            .copied()
+            .collect();
+
+        let mut writers = vec![];
+        let mut changes = vec![];
+        for addr in addrs {
+            changes.extend(self.sign_out(addr));
+            if let Some(client) = self.connections.get(&addr) {
+                writers.push(Arc::clone(&client.writer));
+            }
+        }
+        (writers, changes)
+    }
+    /// Removes the connection from the registry
+    pub fn remove(&mut self, addr: SocketAddr) -> Vec<Presence> {
+        let changes = self.sign_out(addr);
+        self.connections.remove(&addr);
+        changes
+    }
+    /// Returns all live connections of the user
+    pub fn user_clients(&self, user_id: i32) -> impl Iterator<Item = &Client> {
+        self.users
+            .get(&user_id)
+            .into_iter()
+            .flatten()
+            .filter_map(|addr| self.connections.get(addr))
+    }
+    /// Records a typing indicator of the user, returns whether it should be relayed, i.e. the
+    /// last one into the target, from any of the user's connections, was relayed long enough ago
+    pub fn throttle_typing(&mut self, user_id: i32, target: &MessageTarget) -> bool {
+        let now = Instant::now();
+        let key = (user_id, target.clone());
+        if self
+            .typing
+            .get(&key)
+            .is_some_and(|last| now.duration_since(*last) < TYPING_THROTTLE)
+        {
+            return false;
+        }
+        self.typing.insert(key, now);
+        true
+    }
+    /// Records a message of the connection, returns how long until it can send one if either its
+    /// own or its user's bucket is empty
     ///
-    /// # Arguments
-    /// * `secret` - The secret to encode the token with
-    pub fn get_token(&self, secret: &[u8]) -> Result<String, jsonwebtoken::errors::Error> {
-        let header = Header::new(Algorithm::HS256);
-        let token = encode(&header, &self, &EncodingKey::from_secret(secret))?;
-        Ok(token)
+    /// A token is only taken when both buckets have one, so rejected messages cost nothing.
+    pub fn throttle_message(
+        &mut self,
+        addr: SocketAddr,
+        user_id: i32,
+        user_limit: BucketLimit,
+    ) -> Option<Duration> {
+        let client = self.connections.get_mut(&addr)?;
+        let user_bucket = self
+            .user_messages
+            .entry(user_id)
+            .or_insert_with(|| TokenBucket::new(user_limit));
+
+        let now = Instant::now();
+        let wait = client
+            .messages
+            .wait_time(now)
+            .max(user_bucket.wait_time(now));
+        if wait.is_none() {
+            client.messages.take();
+            user_bucket.take();
+        }
+        wait
+    }
+    /// Returns the ids of the users who are online
+    pub fn online_user_ids(&self) -> Vec<i32> {
+        self.users.keys().copied().collect()
+    }
+}
+
+/// The state shared by all connections of the server
+///
+/// # Fields
+/// * `db` - The database
+/// * `clients` - The clients registry
+/// * `store` - The attachment store
+/// * `jwt_keys` - The JWT keys
+/// * `login_throttle` - The failed logins by username and IP
+/// * `rate_limits` - The limits of the requests of the clients
+/// * `limits` - The size limits of the messages and uploads
+/// * `ws_config` - The WebSocket config of the connections
+/// * `handshake_timeout` - How long a connection has to finish the WebSocket handshake
+/// * `shutdown` - Cancelled when the server shuts down, stopping the connections
+struct ServerState {
+    db: Arc<DB>,
+    clients: Arc<Mutex<Clients>>,
+    store: Arc<AttachmentStore>,
+    jwt_keys: JwtKeys,
+    login_throttle: Arc<Mutex<LoginThrottle>>,
+    rate_limits: RateLimits,
+    limits: LimitsConfig,
+    ws_config: WebSocketConfig,
+    handshake_timeout: Duration,
+    shutdown: CancellationToken,
+}
+
+/// Starts a server with the given config, it runs until SIGINT (Ctrl+C) or SIGTERM
+///
+/// Fails only if the server can't start, failures of single connections and requests are logged
+/// and answered instead.
+pub async fn start_server(config: Config) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    init_logging(&config.log);
+    let jwt_keys =
+        JwtKeys::load(&config.jwt).map_err(|e| format!("Failed to load the JWT keys: {}", e))?;
+    let rate_limits = RateLimits::new(&config.rate_limits);
+    let login_throttle = Arc::new(Mutex::new(LoginThrottle::new(rate_limits.login)));
+
+    let db = Arc::new(DB::open(&config.database.url)?);
+    match db.index_messages() {
+        Ok(0) => {}
+        Ok(indexed) => info!(indexed, "Indexed messages for search"),
+        Err(e) => error!(error = %e, "Failed to index the messages for search"),
+    }
+    let store = Arc::new(AttachmentStore::new(&config.attachments.path));
+
+    let address = config.address();
+    info!(address, "Creating a WebSocket server");
+    let listener = TcpListener::bind(&address)
+        .await
+        .map_err(|e| format!("Failed to bind to {}: {}", address, e))?;
+
+    let state = Arc::new(ServerState {
+        db,
+        clients: Arc::new(Mutex::new(Clients::default())),
+        store,
+        jwt_keys,
+        login_throttle,
+        rate_limits,
+        ws_config: WebSocketConfig {
+            max_message_size: Some(config.limits.max_message_size),
+            max_frame_size: Some(config.limits.max_message_size),
+            ..Default::default()
+        },
+        limits: config.limits.clone(),
+        handshake_timeout: Duration::from_secs(config.server.handshake_timeout),
+        shutdown: CancellationToken::new(),
+    });
+    tokio::spawn(sweep_uploads(
+        Arc::clone(&state),
+        config.attachments.upload_lifetime,
+    ));
+
+    let connections = TaskTracker::new();
+    let signal = shutdown_signal();
+    tokio::pin!(signal);
+    loop {
+        let (stream, client_addr) = tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok(accepted) => accepted,
+                // E.g. too many open files, the listener itself keeps working
+                Err(e) => {
+                    error!(error = %e, "Failed to accept a connection");
+                    sleep(ACCEPT_RETRY_DELAY).await;
+                    continue;
+                }
+            },
+            _ = &mut signal => break,
+        };
+        let span = info_span!("connection", addr = %client_addr, user_id = field::Empty);
+        connections
+            .spawn(handle_connection(stream, client_addr, Arc::clone(&state)).instrument(span));
     }
+
+    let timeout = Duration::from_secs(config.server.shutdown_timeout);
+    shut_down(&state, connections, timeout).await;
+    Ok(())
 }
 
-/// Starts a server on the given address
-pub async fn start_server(address: String) {
-    let mut jwt_secret = [0u8; 32];
-    rand::thread_rng().fill(&mut jwt_secret);
+/// Runs a connection from the WebSocket handshake until it closes
+///
+/// A failed handshake only drops the connection, and a request failing (even by a panic) is
+/// answered with an error while the connection goes on.
+///
+/// # Arguments
+///
+/// * `stream` - The accepted TCP stream
+/// * `client_addr` - The address of the client
+/// * `state` - The state shared by the connections
+async fn handle_connection(stream: TcpStream, client_addr: SocketAddr, state: Arc<ServerState>) {
+    // Sockets that never finish the handshake would be held open forever otherwise
+    let handshake = accept_async_with_config(stream, Some(state.ws_config));
+    let ws_stream = match timeout(state.handshake_timeout, handshake).await {
+        Ok(Ok(ws_stream)) => ws_stream,
+        Ok(Err(e)) => {
+            warn!(error = %e, "WebSocket handshake failed");
+            return;
+        }
+        Err(_) => {
+            warn!("WebSocket handshake timed out");
+            return;
+        }
+    };
+    let (wr, rd) = ws_stream.split();
+
+    let reader = Arc::new(Mutex::new(rd));
+    let writer = Arc::new(Mutex::new(WSWriter::new(wr)));
 
-    let db = Arc::new(DB::new().unwrap());
+    state.clients.lock().await.insert(
+        client_addr,
+        Client::new(Arc::clone(&writer), state.rate_limits.connection_messages),
+    );
 
-    println!("Creating a WebSocket server on address: {}", address);
-    let listener = TcpListener::bind(address).await.unwrap();
+    info!("Stream opened");
 
-    let clients: Arc<Mutex<HashMap<SocketAddr, Client>>> = Arc::new(Mutex::new(HashMap::new()));
+    let mut logged_user = None;
     loop {
-        let (stream, client_addr) = listener.accept().await.unwrap();
-        let ws_stream = accept_async(stream).await.expect("Failed to accept");
-        let (wr, rd) = ws_stream.split();
+        // The connection is signed out once its token expires, unless the session is
+        // refreshed before that
+        let claims = state
+            .clients
+            .lock()
+            .await
+            .claims(client_addr)
+            .map(|claims| (claims.sub, claims.exp));
+        // The span keeps the last user, so the logs after a sign-out can still be traced back.
+        // Recorded only when it changes, the formatter appends every record
+        if let Some((user_id, _)) = claims.filter(|(user_id, _)| logged_user != Some(*user_id)) {
+            Span::current().record("user_id", user_id);
+            logged_user = Some(user_id);
+        }
+        let expires_at = claims.map(|(_, exp)| exp);
+        // A request being handled is finished before the connection stops, it stays registered
+        // for the shutdown to close it
+        let received = tokio::select! {
+            received = read_frame(&reader) => received,
+            _ = state.shutdown.cancelled() => return,
+            _ = sleep_until_expiry(expires_at) => {
+                let mut clients = state.clients.lock().await;
+                if let Some(changes) = clients.expire(client_addr) {
+                    spawn_write_task(&writer, error(server_error(token_expired())));
+                    broadcast_presence(&clients, changes, &state.db);
+                }
+                continue;
+            }
+        };
 
-        let reader = Arc::new(Mutex::new(rd));
-        let writer = Arc::new(Mutex::new(wr));
+        let request = match handle_stream(received, &writer).await {
+            Ok(Some(request)) => request,
+            Ok(None) => continue,
+            Err(StreamError::StreamClosed) => break,
+            Err(e) => {
+                warn!(error = %e, "Failed to read a request");
+                spawn_write_task(&writer, error(server_error(deserialize_object_error())));
+                continue;
+            }
+        };
 
-        clients.lock().await.insert(
-            client_addr,
-            Client::new(Arc::clone(&writer).clone(), String::new()),
+        let span = info_span!(
+            "request",
+            id = NEXT_REQUEST_ID.fetch_add(1, Ordering::Relaxed),
+            kind = request.name()
         );
+        // The panic itself is logged by the panic hook, in the span of the request
+        let handled = AssertUnwindSafe(handle_request(request, client_addr, &writer, &state))
+            .catch_unwind()
+            .instrument(span)
+            .await;
+        if handled.is_err() {
+            error!("Failed to handle a request");
+            spawn_write_task(&writer, error(server_error(internal_error())));
+        }
+    }
+
+    info!("Stream has been closed");
+    let mut clients = state.clients.lock().await;
+    let changes = clients.remove(client_addr);
+    broadcast_presence(&clients, changes, &state.db);
+}
+
+/// Handles a request of the connection
+///
+/// # Arguments
+///
+/// * `request` - The request
+/// * `client_addr` - The address of the client
+/// * `writer` - The stream writer (for response)
+/// * `state` - The state shared by the connections
+async fn handle_request(
+    request: StreamRequest,
+    client_addr: SocketAddr,
+    writer: &Arc<Mutex<WSWriter>>,
+    state: &ServerState,
+) {
+    // The passwords, tokens and file contents are redacted by the Debug implementations
+    debug!(?request, "Request received");
+    match request {
+        StreamRequest::AuthRequest(auth_request) => {
+            let claims = match auth_request.kind {
+                AuthRequestKind::Login => {
+                    handle_login(
+                        writer,
+                        &state.db,
+                        auth_request,
+                        &state.jwt_keys,
+                        client_addr.ip(),
+                        &state.login_throttle,
+                    )
+                    .await
+                }
+                AuthRequestKind::Register => {
+                    handle_register(writer, &state.db, auth_request, &state.jwt_keys)
+                }
+            };
+            if let Some(claims) = claims {
+                authenticate(client_addr, claims, &state.clients, &state.db).await;
+            }
+        }
+        StreamRequest::RefreshRequest(refresh_request) => {
+            if let Some(claims) =
+                handle_refresh(writer, &state.db, refresh_request, &state.jwt_keys)
+            {
+                authenticate(client_addr, claims, &state.clients, &state.db).await;
+            }
+        }
+        StreamRequest::ResumeRequest(resume_request) => {
+            if let Some(claims) = handle_resume(writer, &state.db, resume_request, &state.jwt_keys)
+            {
+                authenticate(client_addr, claims, &state.clients, &state.db).await;
+            }
+        }
+        stream_arrival => {
+            // Every other request is authorized by the identity bound to the connection
+            let Some(user_id) = authorize(client_addr, &state.clients, writer, &state.db).await
+            else {
+                return;
+            };
 
-        println!("Stream opened (addr: {})", client_addr);
-
-        let db_clone = Arc::clone(&db);
-        let clients_clone = Arc::clone(&clients);
-        tokio::spawn(async move {
-            loop {
-                match handle_stream(&reader).await {
-                    Ok(stream_arrival) => match stream_arrival {
-                        StreamRequest::MessageRequest(message_request) => {
-                            handle_message_request(
-                                message_request,
-                                &writer,
-                                &clients_clone,
-                                &db_clone,
-                                &jwt_secret,
-                            )
-                            .await;
-                        }
-                        StreamRequest::AuthRequest(auth_request) => match auth_request.kind {
-                            AuthRequestKind::Login => {
-                                if let Some(token) =
-                                    handle_login(&writer, &db_clone, auth_request, &jwt_secret)
-                                {
-                                    clients_clone
-                                        .lock()
-                                        .await
-                                        .get_mut(&client_addr)
-                                        .unwrap()
-                                        .token = token;
-                                }
-                            }
-                            AuthRequestKind::Register => {
-                                if let Some(token) =
-                                    handle_register(&writer, &db_clone, auth_request, &jwt_secret)
-                                {
-                                    clients_clone
-                                        .lock()
-                                        .await
-                                        .get_mut(&client_addr)
-                                        .unwrap()
-                                        .token = token;
-                                }
-                            }
-                        },
-                        StreamRequest::ReadRequest(read_request) => {
-                            let messages = db_clone
-                                .read_history(read_request.amount, read_request.offset)
-                                .unwrap();
-                            for message_obj in messages {
-                                let message_response_res =
-                                    MessageResponse::from_db_message(&message_obj, &db_clone);
-
-                                println!("{:?}", message_response_res);
-
-                                match message_response_res {
-                                    Ok(message_response) => {
-                                        await_write_task(&writer, message(message_response)).await;
-                                    }
-                                    Err(error_response) => {
-                                        await_write_task(&writer, error(error_response)).await;
-                                    }
-                                }
-                            }
-                        }
-                    },
-                    Err(e) => match e {
-                        StreamError::StreamClosed => {
-                            eprintln!("Stream has been closed (addr: {})", &client_addr);
-                            clients_clone.lock().await.remove(&client_addr);
-                            break;
-                        }
-                        _ => handle_stream_error(e),
-                    },
+            match stream_arrival {
+                StreamRequest::MessageRequest(message_request) => {
+                    if !check_message_rate(
+                        client_addr,
+                        user_id,
+                        &state.rate_limits,
+                        writer,
+                        &state.clients,
+                    )
+                    .await
+                    {
+                        return;
+                    }
+                    handle_message_request(
+                        user_id,
+                        message_request,
+                        writer,
+                        &state.clients,
+                        &state.db,
+                        &state.store,
+                    )
+                    .await;
+                }
+                StreamRequest::LogoutRequest(_) => {
+                    handle_logout(user_id, client_addr, &state.clients, &state.db, writer).await;
+                }
+                StreamRequest::ReadRequest(read_request) => {
+                    handle_read_request(user_id, read_request, writer, &state.db).await;
+                }
+                StreamRequest::CreateRoomRequest(create_room_request) => {
+                    handle_create_room(user_id, create_room_request, writer, &state.db);
+                }
+                StreamRequest::JoinRoomRequest(room_request) => {
+                    handle_join_room(user_id, room_request, writer, &state.db);
+                }
+                StreamRequest::LeaveRoomRequest(room_request) => {
+                    handle_leave_room(user_id, room_request, writer, &state.db);
+                }
+                StreamRequest::ListRoomsRequest(_) => {
+                    handle_list_rooms(user_id, writer, &state.db);
+                }
+                StreamRequest::TypingRequest(typing_request) => {
+                    handle_typing(user_id, typing_request, writer, &state.clients, &state.db).await;
+                }
+                StreamRequest::MarkReadRequest(mark_read_request) => {
+                    handle_mark_read(
+                        user_id,
+                        mark_read_request,
+                        writer,
+                        &state.clients,
+                        &state.db,
+                    )
+                    .await;
+                }
+                StreamRequest::ReadThreadRequest(read_thread_request) => {
+                    handle_read_thread(user_id, read_thread_request, writer, &state.db).await;
+                }
+                StreamRequest::ReactRequest(reaction_request) => {
+                    handle_reaction(
+                        user_id,
+                        reaction_request,
+                        true,
+                        writer,
+                        &state.clients,
+                        &state.db,
+                    )
+                    .await;
+                }
+                StreamRequest::UnreactRequest(reaction_request) => {
+                    handle_reaction(
+                        user_id,
+                        reaction_request,
+                        false,
+                        writer,
+                        &state.clients,
+                        &state.db,
+                    )
+                    .await;
                 }
+                StreamRequest::ListMentionsRequest(list_mentions_request) => {
+                    handle_list_mentions(user_id, list_mentions_request, writer, &state.db).await;
+                }
+                StreamRequest::SearchRequest(search_request) => {
+                    handle_search(user_id, search_request, writer, &state.db).await;
+                }
+                StreamRequest::MuteRequest(mute_request) => {
+                    handle_mute(
+                        user_id,
+                        mute_request.user_id,
+                        Some(mute_request.duration),
+                        writer,
+                        &state.clients,
+                        &state.db,
+                    )
+                    .await;
+                }
+                StreamRequest::UnmuteRequest(user_request) => {
+                    handle_mute(
+                        user_id,
+                        user_request.user_id,
+                        None,
+                        writer,
+                        &state.clients,
+                        &state.db,
+                    )
+                    .await;
+                }
+                StreamRequest::KickRequest(user_request) => {
+                    handle_kick(user_id, user_request, writer, &state.clients, &state.db).await;
+                }
+                StreamRequest::BanRequest(user_request) => {
+                    handle_ban(
+                        user_id,
+                        user_request,
+                        true,
+                        writer,
+                        &state.clients,
+                        &state.db,
+                    )
+                    .await;
+                }
+                StreamRequest::UnbanRequest(user_request) => {
+                    handle_ban(
+                        user_id,
+                        user_request,
+                        false,
+                        writer,
+                        &state.clients,
+                        &state.db,
+                    )
+                    .await;
+                }
+                StreamRequest::ListOnlineUsersRequest(_) => {
+                    handle_list_online_users(writer, &state.clients, &state.db).await;
+                }
+                StreamRequest::EditMessageRequest(edit_message_request) => {
+                    handle_edit_message(
+                        user_id,
+                        edit_message_request,
+                        writer,
+                        &state.clients,
+                        &state.db,
+                    )
+                    .await;
+                }
+                StreamRequest::FetchAttachmentRequest(fetch_attachment_request) => {
+                    handle_fetch_attachment(
+                        user_id,
+                        fetch_attachment_request,
+                        writer,
+                        &state.db,
+                        &state.store,
+                        &state.limits,
+                    )
+                    .await;
+                }
+                StreamRequest::BeginUploadRequest(begin_upload_request) => {
+                    handle_begin_upload(
+                        user_id,
+                        begin_upload_request,
+                        writer,
+                        &state.db,
+                        &state.store,
+                        &state.limits,
+                    );
+                }
+                StreamRequest::UploadChunkRequest(upload_chunk_request) => {
+                    handle_upload_chunk(
+                        user_id,
+                        upload_chunk_request,
+                        writer,
+                        &state.db,
+                        &state.store,
+                        &state.limits,
+                    );
+                }
+                StreamRequest::FinishUploadRequest(finish_upload_request) => {
+                    // The finished upload becomes a message
+                    if !check_message_rate(
+                        client_addr,
+                        user_id,
+                        &state.rate_limits,
+                        writer,
+                        &state.clients,
+                    )
+                    .await
+                    {
+                        return;
+                    }
+                    handle_finish_upload(
+                        user_id,
+                        finish_upload_request,
+                        writer,
+                        &state.clients,
+                        &state.db,
+                        &state.store,
+                    )
+                    .await;
+                }
+                StreamRequest::DeleteMessageRequest(delete_message_request) => {
+                    handle_delete_message(
+                        user_id,
+                        delete_message_request,
+                        writer,
+                        &state.clients,
+                        &state.db,
+                    )
+                    .await;
+                }
+                // Handled above, they authenticate the connection
+                StreamRequest::AuthRequest(_)
+                | StreamRequest::RefreshRequest(_)
+                | StreamRequest::ResumeRequest(_) => unreachable!(),
+            }
+        }
+    }
+}
+
+/// Waits for SIGINT (Ctrl+C) or, on Unix, SIGTERM
+async fn shutdown_signal() {
+    // A signal that can't be listened for never arrives, the server keeps running without it
+    let ctrl_c = async {
+        if let Err(e) = tokio::signal::ctrl_c().await {
+            error!(error = %e, "Failed to listen for Ctrl+C");
+            pending::<()>().await;
+        }
+    };
+    #[cfg(unix)]
+    let terminate = async {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                terminate.recv().await;
+            }
+            Err(e) => {
+                error!(error = %e, "Failed to listen for SIGTERM");
+                pending::<()>().await;
             }
-        });
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {}
+        _ = terminate => {}
+    }
+}
+
+/// Deletes the stale uploads on start and then every `UPLOAD_SWEEP_INTERVAL`, until the server
+/// shuts down
+///
+/// # Arguments
+///
+/// * `state` - The state shared by the connections
+/// * `lifetime` - How long an upload can stay unfinished (in seconds)
+async fn sweep_uploads(state: Arc<ServerState>, lifetime: u64) {
+    loop {
+        match state.db.remove_stale_uploads(&state.store, lifetime) {
+            Ok(0) => {}
+            Ok(removed) => info!(removed, "Deleted stale uploads"),
+            Err(e) => error!(error = %e, "Failed to delete the stale uploads"),
+        }
+        tokio::select! {
+            _ = sleep(UPLOAD_SWEEP_INTERVAL) => {}
+            _ = state.shutdown.cancelled() => return,
+        }
+    }
+}
+
+/// Shuts the server down once it has stopped accepting connections
+///
+/// The connections stop reading and finish the requests they're handling, every client gets
+/// `ShuttingDown`, and the connections are closed once the pending responses are written. Each
+/// step waits until the timeout (counted from the start) at most.
+///
+/// # Arguments
+///
+/// * `state` - The state shared by the connections, whose token cancels them
+/// * `connections` - The connection tasks
+/// * `timeout` - How long to wait for the requests and the pending responses
+async fn shut_down(state: &ServerState, connections: TaskTracker, timeout: Duration) {
+    info!(timeout_secs = timeout.as_secs(), "Shutting down");
+    let deadline = TokioInstant::now() + timeout;
+
+    state.shutdown.cancel();
+    connections.close();
+    if timeout_at(deadline, connections.wait()).await.is_err() {
+        warn!("Some requests were still being handled when the server shut down");
+    }
+
+    let writers: Vec<Arc<Mutex<WSWriter>>> = state
+        .clients
+        .lock()
+        .await
+        .connections
+        .values()
+        .map(|client| Arc::clone(&client.writer))
+        .collect();
+    for writer in &writers {
+        spawn_write_task(writer, shutting_down(timeout.as_secs()));
+    }
+    WRITE_TASKS.close();
+    if timeout_at(deadline, WRITE_TASKS.wait()).await.is_err() {
+        warn!("Some responses were still being written when the server shut down");
+    }
+
+    let close_all = futures_util::future::join_all(writers.iter().map(close_connection));
+    if timeout_at(deadline, close_all).await.is_err() {
+        warn!("Some connections didn't close in time");
     }
+    info!("Server has shut down");
 }
 
-/// Writes the given content into the given stream
+/// Sends the close frame telling the client that the server is going away
+async fn close_connection(writer: &Arc<Mutex<WSWriter>>) {
+    let frame = CloseFrame {
+        code: CloseCode::Away,
+        reason: "Server shutting down".into(),
+    };
+    writer
+        .lock()
+        .await
+        .sink
+        .send(Message::Close(Some(frame)))
+        .await
+        .map_err(|e| debug!(error = %e, "Failed to write into the stream"))
+        .ok();
+}
+
+/// Writes the given response into the given stream, in the encoding of the connection
 ///
 /// # Arguments
 ///
 /// * `writer` - The writer to write into
-/// * `content` - The content to write
+/// * `response` - The response to write
 async fn write_into_stream(
     writer: &Arc<Mutex<WSWriter>>,
-    content: String,
+    response: ServerResponse,
 ) -> Result<(), tokio_tungstenite::tungstenite::Error> {
     let mut locked_writer = writer.lock().await;
-    locked_writer.send(Message::Text(content)).await?;
+    let frame = match locked_writer.encoding.unwrap_or_default() {
+        Encoding::Json => Message::Text(response.serialize()),
+        Encoding::Bincode => Message::Binary(response.serialize_bincode()),
+    };
+    locked_writer.sink.send(frame).await?;
 
     Ok(())
 }
@@ -214,10 +928,9 @@ async fn write_into_stream(
 /// * `writer` - The writer to write into
 /// * `response` - The response to write
 async fn await_write_task(writer: &Arc<Mutex<WSWriter>>, response: ServerResponse) {
-    let content = response.serialize();
-    write_into_stream(writer, content)
+    write_into_stream(writer, response)
         .await
-        .map_err(|e| println!("{}", e))
+        .map_err(|e| debug!(error = %e, "Failed to write into the stream"))
         .ok();
 }
 
@@ -228,184 +941,2057 @@ async fn await_write_task(writer: &Arc<Mutex<WSWriter>>, response: ServerRespons
 /// * `writer` - The writer to write into
 /// * `response` - The response to write
 fn spawn_write_task(writer: &Arc<Mutex<WSWriter>>, response: ServerResponse) {
-    let content = response.serialize();
     let writer = Arc::clone(writer);
-    tokio::spawn(async move {
-        println!("Writing...");
-        write_into_stream(&writer, content)
-            .await
-            .map_err(|e| println!("{}", e))
-            .ok();
-    });
+    // Stays in the span of the request, so the failed writes can be told apart
+    WRITE_TASKS.spawn(
+        async move {
+            debug!("Writing...");
+            write_into_stream(&writer, response)
+                .await
+                .map_err(|e| debug!(error = %e, "Failed to write into the stream"))
+                .ok();
+        }
+        .in_current_span(),
+    );
 }
 
-/// Handles input from the stream and returns the StreamArrival
+/// Waits for the next frame of the stream
+///
+/// Cancel-safe, a frame is never lost when the waiting is interrupted.
 ///
 /// # Arguments
 ///
 /// * `reader` - The stream reader
-async fn handle_stream(reader: &Arc<Mutex<WSReader>>) -> Result<StreamRequest, StreamError> {
-    let mut locked_reader = reader.lock().await;
-
-    let received = locked_reader.next().await;
+async fn read_frame(reader: &Arc<Mutex<WSReader>>) -> Option<Result<Message, WSError>> {
+    reader.lock().await.next().await
+}
 
-    println!("{:?}", received);
+/// Sleeps until the given time (in seconds from UNIX epoch), or forever if there is none
+async fn sleep_until_expiry(expires_at: Option<u64>) {
+    match expires_at {
+        Some(expires_at) => {
+            let remaining = expires_at.saturating_sub(get_current_timestamp());
+            sleep(Duration::from_secs(remaining)).await;
+        }
+        None => pending().await,
+    }
+}
 
-    let received = match received {
-        Some(Ok(Message::Text(data))) => data,
-        _ => return Err(StreamError::StreamClosed),
+/// Handles a frame from the stream and returns the StreamArrival, `None` for control frames
+///
+/// Text frames are decoded as JSON and binary frames as bincode. The first frame decides the
+/// encoding of the responses on the connection.
+///
+/// # Arguments
+///
+/// * `received` - The frame read from the stream
+/// * `writer` - The stream writer, whose encoding is negotiated
+async fn handle_stream(
+    received: Option<Result<Message, WSError>>,
+    writer: &Arc<Mutex<WSWriter>>,
+) -> Result<Option<StreamRequest>, StreamError> {
+    let (stream_arrival, encoding) = match received {
+        Some(Ok(Message::Text(data))) => (deserialize_stream(data).ok(), Encoding::Json),
+        Some(Ok(Message::Binary(data))) => {
+            (deserialize_stream_bincode(&data).ok(), Encoding::Bincode)
+        }
+        // Pings are answered by tungstenite itself
+        Some(Ok(Message::Ping(_) | Message::Pong(_) | Message::Frame(_))) => return Ok(None),
+        Some(Ok(Message::Close(_))) | None => return Err(StreamError::StreamClosed),
+        // The stream can't be read any further, e.g. a message over the size limit
+        Some(Err(e)) => {
+            warn!(error = %e, "Failed to read from the stream");
+            return Err(StreamError::StreamClosed);
+        }
     };
-    /*     locked_reader
-        .read_exact(&mut len_buffer)
-        .await
-        .map_err(|_| StreamError::StreamClosed)?;
-
-    let len = u32::from_be_bytes(len_buffer);
-
-    let mut buffer = vec![0; len as usize];
-    locked_reader
-        .read_exact(&mut buffer)
-        .await
-        .map_err(StreamError::ReadMessageError)?; */
-
-    drop(locked_reader); // Manually drop just to be sure
+    writer.lock().await.encoding.get_or_insert(encoding);
 
-    let stream_arrival = deserialize_stream(received).map_err(|_| {
+    stream_arrival.map(Some).ok_or_else(|| {
         StreamError::ReadMessageError(Error::new(ErrorKind::InvalidData, "Failed to deserialize"))
-    })?;
-
-    Ok(stream_arrival)
+    })
 }
 
-/// Handles login request
+/// Handles login request, returns the claims of the token of the new session
+///
+/// Failed logins delay the next attempts for the username and the IP, those coming too early are
+/// answered with `RateLimited` without checking the password.
 ///
 /// # Arguments
 ///
 /// * `writer` - The stream writer (for response)
 /// * `db` - The database
 /// * `auth_request` - The auth request
-/// * `jwt_secret` - The JWT secret
-fn handle_login(
+/// * `jwt_keys` - The JWT keys
+/// * `ip` - The IP the client connects from
+/// * `login_throttle` - The failed logins by username and IP
+async fn handle_login(
     writer: &Arc<Mutex<WSWriter>>,
     db: &Arc<DB>,
     auth_request: AuthRequest,
-    jwt_secret: &[u8; 32],
-) -> Option<String> {
+    jwt_keys: &JwtKeys,
+    ip: IpAddr,
+    login_throttle: &Arc<Mutex<LoginThrottle>>,
+) -> Option<Claims> {
+    if let Some(wait) = login_throttle
+        .lock()
+        .await
+        .wait_time(&auth_request.username, ip)
+    {
+        let response = error(server_error(ServerError::RateLimited(retry_after(wait))));
+        spawn_write_task(writer, response);
+        return None;
+    }
+
     let check = db.check_password(&auth_request.username, &auth_request.password);
 
+    // Unknown usernames count too, otherwise they would be told apart by the missing delay
     let correct = match check {
         Ok(correct) => correct,
+        Err(DBError::UserNotFoundError) => false,
         Err(e) => {
             let response = error(db_error(e));
-            spawn_write_task(&writer, response);
+            spawn_write_task(writer, response);
             return None;
         }
     };
 
     if correct {
-        let user_id = db.get_user_id(&auth_request.username).unwrap();
-
-        let token = Claims::new(user_id, get_current_timestamp() + ONE_DAY)
-            .get_token(jwt_secret)
-            .unwrap();
+        let user_id = match db.get_user_id(&auth_request.username) {
+            Ok(user_id) => user_id,
+            Err(_) => {
+                spawn_write_task(writer, error(db_error(DBError::UserNotFoundError)));
+                return None;
+            }
+        };
+        let user = match db.get_user(user_id) {
+            Ok(user) => user,
+            Err(e) => {
+                spawn_write_task(writer, error(db_error(e)));
+                return None;
+            }
+        };
+        // Checked after the password, so the ban of an account isn't revealed to anyone else
+        if user.banned_at.is_some() {
+            spawn_write_task(writer, error(server_error(user_banned())));
+            return None;
+        }
 
-        let auth_obj = Auth {
-            token: token.clone(),
-            username: auth_request.username,
+        let session = start_session(
             user_id,
+            auth_request.username.clone(),
+            user.role(),
+            db,
+            jwt_keys,
+        );
+        return match session {
+            Ok((auth_obj, claims)) => {
+                // Only a login that went through forgets the failures
+                login_throttle
+                    .lock()
+                    .await
+                    .record_success(&auth_request.username);
+                spawn_write_task(writer, auth(auth_obj));
+                Some(claims)
+            }
+            Err(e) => {
+                spawn_write_task(writer, error(e));
+                None
+            }
         };
-
-        spawn_write_task(&writer, auth(auth_obj));
-
-        return Some(token);
     }
 
-    spawn_write_task(&writer, error(server_error(invalid_credentials())));
+    login_throttle
+        .lock()
+        .await
+        .record_failure(&auth_request.username, ip);
+    spawn_write_task(writer, error(server_error(invalid_credentials())));
 
-    return None;
+    None
 }
 
-/// Handles register request
+/// Handles register request, returns the claims of the token of the new session
 ///
 /// # Arguments
 ///
 /// * `writer` - The stream writer (for response)
 /// * `db` - The database
 /// * `auth_request` - The auth request
-/// * `jwt_secret` - The JWT secret
+/// * `jwt_keys` - The JWT keys
 fn handle_register(
     writer: &Arc<Mutex<WSWriter>>,
     db: &Arc<DB>,
     auth_request: AuthRequest,
-    jwt_secret: &[u8; 32],
-) -> Option<String> {
+    jwt_keys: &JwtKeys,
+) -> Option<Claims> {
     match db.create_user(auth_request.username, auth_request.password) {
         Ok(new_user) => {
-            let token = Claims::new(new_user.id.unwrap(), get_current_timestamp() + ONE_DAY)
-                .get_token(jwt_secret)
-                .unwrap();
-
-            let auth_obj = Auth {
-                token: token.clone(),
-                username: new_user.username,
-                user_id: new_user.id.unwrap(),
-            };
-
-            spawn_write_task(&writer, auth(auth_obj));
-
-            Some(token)
+            let role = new_user.role();
+            match start_session(new_user.id.unwrap(), new_user.username, role, db, jwt_keys) {
+                Ok((auth_obj, claims)) => {
+                    spawn_write_task(writer, auth(auth_obj));
+                    Some(claims)
+                }
+                Err(e) => {
+                    spawn_write_task(writer, error(e));
+                    None
+                }
+            }
         }
         Err(e) => {
-            println!("{}", e);
-            spawn_write_task(&writer, error(server_error(username_used())));
+            debug!(error = %e, "Failed to register");
+            // Only a failed user insert is a taken username, the joins after it are rolled back
+            let response = match e.downcast::<DBError>() {
+                Ok(DBError::UserInsertionError) | Err(_) => server_error(username_used()),
+                Ok(e) => db_error(e),
+            };
+            spawn_write_task(writer, error(response));
             None
         }
     }
 }
 
-/// Handles a message from the stream
+/// Starts a new session of the user, returns its tokens and the claims of the JWT token
+///
+/// # Arguments
+///
+/// * `user_id` - The user who logged in
+/// * `username` - The username of the user
+/// * `role` - The role of the user
+/// * `db` - The database
+/// * `jwt_keys` - The JWT keys
+fn start_session(
+    user_id: i32,
+    username: String,
+    role: Role,
+    db: &Arc<DB>,
+    jwt_keys: &JwtKeys,
+) -> Result<(Auth, Claims), ErrorResponse> {
+    let refresh_token = generate_refresh_token();
+    let session = db
+        .create_session(
+            user_id,
+            hash_refresh_token(&refresh_token),
+            jwt_keys.refresh_token_lifetime,
+        )
+        .map_err(db_error)?;
+
+    let claims = Claims::for_session(user_id, session.id.unwrap(), jwt_keys);
+    let token = claims
+        .get_token(jwt_keys)
+        .map_err(|_| server_error(serialize_object_error()))?;
+
+    let auth_obj = Auth {
+        token,
+        refresh_token,
+        username,
+        user_id,
+        role,
+        unread: get_unread_counts(user_id, db)?,
+    };
+
+    Ok((auth_obj, claims))
+}
+
+/// Returns the amount of unread messages in the rooms and direct conversations of the user
+///
+/// # Arguments
+///
+/// * `user_id` - The user
+/// * `db` - The database
+fn get_unread_counts(user_id: i32, db: &Arc<DB>) -> Result<Vec<UnreadCount>, ErrorResponse> {
+    let unread_counts = db.get_unread_counts(user_id).map_err(db_error)?;
+
+    Ok(unread_counts
+        .into_iter()
+        .map(|(target, count)| UnreadCount { target, count })
+        .collect())
+}
+
+/// Handles refresh request, exchanges the refresh token for new tokens of the same session and
+/// returns the claims of the new JWT token
 ///
 /// # Arguments
 ///
-/// * `message_request` - The message
 /// * `writer` - The stream writer (for response)
-/// * `clients` - The clients hashmap
-/// * `client_addr` - The sending client's address
 /// * `db` - The database
-/// * `jwt_secret` - The JWT secret
-async fn handle_message_request(
-    message_request: MessageRequest,
+/// * `refresh_request` - The refresh request
+/// * `jwt_keys` - The JWT keys
+fn handle_refresh(
     writer: &Arc<Mutex<WSWriter>>,
-    clients: &Arc<Mutex<HashMap<SocketAddr, Client>>>,
     db: &Arc<DB>,
-    jwt_secret: &[u8; 32],
-) {
-    let user_id = match Claims::from_token(&message_request.jwt, jwt_secret) {
-        Ok(claims) => claims.sub,
-        _ => {
-            eprintln!("Invalid token");
-            spawn_write_task(&writer, error(server_error(invalid_token())));
-            return;
+    refresh_request: RefreshRequest,
+    jwt_keys: &JwtKeys,
+) -> Option<Claims> {
+    let refresh_token = generate_refresh_token();
+    let session = match db.refresh_session(
+        &hash_refresh_token(&refresh_request.refresh_token),
+        hash_refresh_token(&refresh_token),
+        jwt_keys.refresh_token_lifetime,
+    ) {
+        Ok(session) => session,
+        Err(DBError::SessionNotFoundError) => {
+            spawn_write_task(writer, error(server_error(invalid_refresh_token())));
+            return None;
+        }
+        Err(e) => {
+            spawn_write_task(writer, error(db_error(e)));
+            return None;
         }
     };
 
-    println!("incoming: {:?}", message_request.message);
-
-    let message_obj = db
-        .save_message(user_id, message_request.message.clone())
-        .unwrap();
-
-    for (_, client) in clients.lock().await.iter() {
-        match Claims::from_token(&client.token, jwt_secret) {
-            Ok(_) => match MessageResponse::from_db_message(&message_obj, &db) {
-                Ok(message_response) => {
-                    spawn_write_task(&client.writer, message(message_response));
-                }
-                Err(error_response) => spawn_write_task(&writer, error(error_response)),
-            },
-            _ => (), // I could message the client that the token has expired, but messaging on every message that passes through the chat seems counterproductive
+    let user = match db.get_user(session.user_id) {
+        Ok(user) => user,
+        Err(e) => {
+            spawn_write_task(writer, error(db_error(e)));
+            return None;
         }
+    };
+    // A ban holds even if the sessions of the user weren't revoked with it
+    if user.banned_at.is_some() {
+        spawn_write_task(writer, error(server_error(user_banned())));
+        return None;
     }
 
-    return;
+    let unread = match get_unread_counts(session.user_id, db) {
+        Ok(unread) => unread,
+        Err(e) => {
+            spawn_write_task(writer, error(e));
+            return None;
+        }
+    };
+
+    let claims = Claims::for_session(session.user_id, session.id.unwrap(), jwt_keys);
+    let token = match claims.get_token(jwt_keys) {
+        Ok(token) => token,
+        Err(_) => {
+            spawn_write_task(writer, error(server_error(serialize_object_error())));
+            return None;
+        }
+    };
+
+    let auth_obj = Auth {
+        token,
+        refresh_token,
+        role: user.role(),
+        username: user.username,
+        user_id: session.user_id,
+        unread,
+    };
+
+    spawn_write_task(writer, auth(auth_obj));
+
+    Some(claims)
+}
+
+/// Handles resume request, accepts the JWT token of a live session for the connection and
+/// returns its claims
+///
+/// # Arguments
+///
+/// * `writer` - The stream writer (for response)
+/// * `db` - The database
+/// * `resume_request` - The resume request
+/// * `jwt_keys` - The JWT keys
+fn handle_resume(
+    writer: &Arc<Mutex<WSWriter>>,
+    db: &Arc<DB>,
+    resume_request: ResumeRequest,
+    jwt_keys: &JwtKeys,
+) -> Option<Claims> {
+    let Ok(claims) = Claims::from_token(&resume_request.token, jwt_keys) else {
+        spawn_write_task(writer, error(server_error(invalid_token())));
+        return None;
+    };
+
+    match db.is_session_active(claims.sid) {
+        Ok(true) => {}
+        Ok(false) => {
+            spawn_write_task(writer, error(server_error(session_revoked())));
+            return None;
+        }
+        Err(e) => {
+            spawn_write_task(writer, error(db_error(e)));
+            return None;
+        }
+    }
+
+    let user = match db.get_user(claims.sub) {
+        Ok(user) => user,
+        Err(e) => {
+            spawn_write_task(writer, error(db_error(e)));
+            return None;
+        }
+    };
+    // A ban holds even if the sessions of the user weren't revoked with it
+    if user.banned_at.is_some() {
+        spawn_write_task(writer, error(server_error(user_banned())));
+        return None;
+    }
+
+    spawn_write_task(
+        writer,
+        resumed(ResumedResponse {
+            role: user.role(),
+            username: user.username,
+            user_id: claims.sub,
+            expires_at: claims.exp,
+        }),
+    );
+
+    Some(claims)
+}
+
+/// Handles logout request, revokes the session of the connection and signs out all of its
+/// connections
+///
+/// # Arguments
+///
+/// * `user_id` - The user the connection is authenticated as
+/// * `client_addr` - The address of the connection
+/// * `clients` - The clients registry
+/// * `db` - The database
+/// * `writer` - The stream writer (for response)
+async fn handle_logout(
+    user_id: i32,
+    client_addr: SocketAddr,
+    clients: &Arc<Mutex<Clients>>,
+    db: &Arc<DB>,
+    writer: &Arc<Mutex<WSWriter>>,
+) {
+    let Some(session_id) = clients
+        .lock()
+        .await
+        .claims(client_addr)
+        .map(|claims| claims.sid)
+    else {
+        return;
+    };
+
+    if let Err(e) = db.revoke_session(session_id) {
+        spawn_write_task(writer, error(db_error(e)));
+        return;
+    }
+
+    let mut clients = clients.lock().await;
+    let (signed_out, changes) = clients.sign_out_session(session_id);
+    for signed_out in signed_out {
+        spawn_write_task(&signed_out, logged_out(user_id));
+    }
+    broadcast_presence(&clients, changes, db);
+}
+
+/// Returns the user the connection is authenticated as, responds with an error if it isn't or
+/// its token has just expired
+///
+/// # Arguments
+///
+/// * `client_addr` - The address of the connection
+/// * `clients` - The clients registry
+/// * `writer` - The stream writer (for response)
+/// * `db` - The database
+async fn authorize(
+    client_addr: SocketAddr,
+    clients: &Arc<Mutex<Clients>>,
+    writer: &Arc<Mutex<WSWriter>>,
+    db: &Arc<DB>,
+) -> Option<i32> {
+    let mut clients = clients.lock().await;
+    // The expiry timer may not have fired yet
+    if let Some(changes) = clients.expire(client_addr) {
+        spawn_write_task(writer, error(server_error(token_expired())));
+        broadcast_presence(&clients, changes, db);
+        return None;
+    }
+
+    match clients.claims(client_addr) {
+        Some(claims) => Some(claims.sub),
+        None => {
+            spawn_write_task(writer, error(server_error(not_authenticated())));
+            None
+        }
+    }
+}
+
+/// Binds the connection to the claims it has authenticated with and announces the user if it
+/// is their first connection
+///
+/// # Arguments
+///
+/// * `client_addr` - The address of the connection
+/// * `claims` - The claims of the token the connection has authenticated with
+/// * `clients` - The clients registry
+/// * `db` - The database
+async fn authenticate(
+    client_addr: SocketAddr,
+    claims: Claims,
+    clients: &Arc<Mutex<Clients>>,
+    db: &Arc<DB>,
+) {
+    let mut clients = clients.lock().await;
+    let changes = clients.authenticate(client_addr, claims);
+    broadcast_presence(&clients, changes, db);
+}
+
+/// Sends the presence changes to every authenticated connection
+///
+/// # Arguments
+///
+/// * `clients` - The clients registry
+/// * `changes` - The users who came online or went offline
+/// * `db` - The database
+fn broadcast_presence(clients: &Clients, changes: Vec<Presence>, db: &Arc<DB>) {
+    if changes.is_empty() {
+        return;
+    }
+    let online_user_ids = clients.online_user_ids();
+
+    for change in changes {
+        let user_id = match change {
+            Presence::Online(user_id) | Presence::Offline(user_id) => user_id,
+        };
+        let user = match db.get_user(user_id) {
+            Ok(user) => user,
+            Err(e) => {
+                error!(user_id, error = %e, "Failed to announce the presence of the user");
+                continue;
+            }
+        };
+        let presence = UserPresence {
+            user_id,
+            username: user.username,
+        };
+        let response = match change {
+            Presence::Online(_) => user_online(presence),
+            Presence::Offline(_) => user_offline(presence),
+        };
+        send_to_users(clients, &online_user_ids, &response);
+    }
+}
+
+/// Checks that the user can access the target, i.e. is a member of the room or the recipient
+/// exists, responds with an error if they can't
+///
+/// # Arguments
+///
+/// * `target` - The room or the recipient
+/// * `user_id` - The user
+/// * `writer` - The stream writer (for response)
+/// * `db` - The database
+fn check_target(
+    target: &MessageTarget,
+    user_id: i32,
+    writer: &Arc<Mutex<WSWriter>>,
+    db: &Arc<DB>,
+) -> bool {
+    let check = match *target {
+        MessageTarget::Room(room_id) => db.is_room_member(room_id, user_id),
+        MessageTarget::User(recipient_id) => db.get_user(recipient_id).map(|_| true),
+    };
+
+    match check {
+        Ok(true) => true,
+        Ok(false) => {
+            spawn_write_task(writer, error(server_error(not_room_member())));
+            false
+        }
+        Err(e) => {
+            spawn_write_task(writer, error(db_error(e)));
+            false
+        }
+    }
+}
+
+/// Returns the ids of all users who can see messages sent to the target
+///
+/// # Arguments
+///
+/// * `target` - The room or the recipient
+/// * `sender_id` - The user who sent the message
+/// * `db` - The database
+fn get_audience(target: &MessageTarget, sender_id: i32, db: &Arc<DB>) -> Result<Vec<i32>, DBError> {
+    match *target {
+        MessageTarget::Room(room_id) => db.get_room_member_ids(room_id),
+        MessageTarget::User(recipient_id) if recipient_id == sender_id => Ok(vec![sender_id]),
+        MessageTarget::User(recipient_id) => Ok(vec![sender_id, recipient_id]),
+    }
+}
+
+/// Sends the response to every live connection of the given users
+///
+/// # Arguments
+///
+/// * `clients` - The clients registry
+/// * `user_ids` - The users to send the response to
+/// * `response` - The response to send
+fn send_to_users(clients: &Clients, user_ids: &[i32], response: &ServerResponse) {
+    for &user_id in user_ids {
+        // Connections are signed out when their token expires, so all of these are authorized
+        for client in clients.user_clients(user_id) {
+            spawn_write_task(&client.writer, response.clone());
+        }
+    }
+}
+
+/// Handles a message from the stream
+///
+/// # Arguments
+///
+/// * `user_id` - The user the connection is authenticated as
+/// * `message_request` - The message
+/// * `writer` - The stream writer (for response)
+/// * `clients` - The clients hashmap
+/// * `db` - The database
+/// * `store` - The attachment store
+async fn handle_message_request(
+    user_id: i32,
+    message_request: MessageRequest,
+    writer: &Arc<Mutex<WSWriter>>,
+    clients: &Arc<Mutex<Clients>>,
+    db: &Arc<DB>,
+    store: &Arc<AttachmentStore>,
+) {
+    let target = message_request.target;
+    if !check_target(&target, user_id, writer, db) || !check_not_muted(user_id, writer, db) {
+        return;
+    }
+
+    // A reply has to quote a message of the same conversation that hasn't been deleted
+    let parent = match message_request
+        .parent_id
+        .map(|parent_id| db.get_message(parent_id))
+    {
+        None => None,
+        Some(Ok(parent))
+            if parent.deleted_at.is_none() && is_in_conversation(&parent, &target, user_id) =>
+        {
+            Some(parent)
+        }
+        Some(Ok(_)) => {
+            spawn_write_task(writer, error(db_error(DBError::MessageNotFoundError)));
+            return;
+        }
+        Some(Err(e)) => {
+            spawn_write_task(writer, error(db_error(e)));
+            return;
+        }
+    };
+
+    let content = match store_attachment(message_request.message, db, store) {
+        Ok(content) => content,
+        Err(error_response) => {
+            spawn_write_task(writer, error(error_response));
+            return;
+        }
+    };
+
+    send_message(
+        user_id,
+        target,
+        content,
+        parent.as_ref(),
+        writer,
+        clients,
+        db,
+    )
+    .await;
+}
+
+/// Saves the message and sends it to everyone who can see it
+///
+/// # Arguments
+///
+/// * `user_id` - The sender
+/// * `target` - The room or the user the message is sent to, already checked
+/// * `content` - The content to save
+/// * `parent` - The message it replies to, already checked
+/// * `writer` - The stream writer (for response)
+/// * `clients` - The clients registry
+/// * `db` - The database
+async fn send_message(
+    user_id: i32,
+    target: MessageTarget,
+    content: MessageContent,
+    parent: Option<&DBMessage>,
+    writer: &Arc<Mutex<WSWriter>>,
+    clients: &Arc<Mutex<Clients>>,
+    db: &Arc<DB>,
+) {
+    debug!(?content, "Incoming message");
+
+    let message_obj = match db.save_message(user_id, &target, content, parent) {
+        Ok(message_obj) => message_obj,
+        Err(_) => {
+            spawn_write_task(writer, error(db_error(DBError::MessageInsertionError)));
+            return;
+        }
+    };
+    let message_response = match MessageResponse::from_db_message(&message_obj, db) {
+        Ok(message_response) => message_response,
+        Err(error_response) => {
+            spawn_write_task(writer, error(error_response));
+            return;
+        }
+    };
+    let audience = match get_audience(&target, user_id, db) {
+        Ok(audience) => audience,
+        Err(e) => {
+            spawn_write_task(writer, error(db_error(e)));
+            return;
+        }
+    };
+
+    send_to_users(
+        &*clients.lock().await,
+        &audience,
+        &message(message_response.clone()),
+    );
+    send_mentions(message_response, &audience, writer, clients, db).await;
+}
+
+/// Records the users mentioned in the message and sends them a `Mention`
+///
+/// Only the users who can see the message are mentioned, each of them once per message, so an
+/// edited message only notifies the newly mentioned users.
+///
+/// # Arguments
+///
+/// * `message_response` - The message, as sent to its audience
+/// * `audience` - The users who can see the message
+/// * `writer` - The stream writer (for response)
+/// * `clients` - The clients registry
+/// * `db` - The database
+async fn send_mentions(
+    message_response: MessageResponse,
+    audience: &[i32],
+    writer: &Arc<Mutex<WSWriter>>,
+    clients: &Arc<Mutex<Clients>>,
+    db: &Arc<DB>,
+) {
+    let usernames = message_response.content.mentions();
+    if usernames.is_empty() {
+        return;
+    }
+
+    let mentioned_res = db.get_users_by_username(&usernames).and_then(|users| {
+        let user_ids: Vec<i32> = users
+            .iter()
+            .filter_map(|user| user.id)
+            .filter(|id| *id != message_response.user_id && audience.contains(id))
+            .collect();
+        db.add_mentions(message_response.id, &user_ids)
+    });
+    match mentioned_res {
+        Ok(mentioned) => send_to_users(
+            &*clients.lock().await,
+            &mentioned,
+            &mention(message_response),
+        ),
+        Err(e) => spawn_write_task(writer, error(db_error(e))),
+    }
+}
+
+/// Handles a typing indicator, relays it to the other users who can see the target
+///
+/// Nothing is stored, and indicators of a user coming faster than `TYPING_THROTTLE` are dropped,
+/// whichever connections they come from. The server never sends a stop event, the receivers
+/// expire the indicator themselves after `TYPING_TIMEOUT` seconds.
+///
+/// # Arguments
+///
+/// * `user_id` - The user the connection is authenticated as
+/// * `typing_request` - The typing request
+/// * `writer` - The stream writer (for response)
+/// * `clients` - The clients registry
+/// * `db` - The database
+async fn handle_typing(
+    user_id: i32,
+    typing_request: TypingRequest,
+    writer: &Arc<Mutex<WSWriter>>,
+    clients: &Arc<Mutex<Clients>>,
+    db: &Arc<DB>,
+) {
+    let target = typing_request.target;
+    // Only targets the user can type to take a throttle slot
+    if !check_target(&target, user_id, writer, db) {
+        return;
+    }
+    if !clients.lock().await.throttle_typing(user_id, &target) {
+        return;
+    }
+
+    let audience = match get_audience(&target, user_id, db) {
+        Ok(audience) => audience,
+        Err(e) => {
+            spawn_write_task(writer, error(db_error(e)));
+            return;
+        }
+    };
+    let others: Vec<i32> = audience.into_iter().filter(|&id| id != user_id).collect();
+    let user = match db.get_user(user_id) {
+        Ok(user) => user,
+        Err(e) => {
+            spawn_write_task(writer, error(db_error(e)));
+            return;
+        }
+    };
+
+    let response = typing(TypingResponse {
+        user_id,
+        username: user.username,
+        target,
+        expires_in: TYPING_TIMEOUT,
+    });
+    send_to_users(&*clients.lock().await, &others, &response);
+}
+
+/// Handles a request to mark a conversation as read, broadcasts the receipt to everyone who can
+/// see the conversation
+///
+/// # Arguments
+///
+/// * `user_id` - The user the connection is authenticated as
+/// * `mark_read_request` - The mark read request
+/// * `writer` - The stream writer (for response)
+/// * `clients` - The clients registry
+/// * `db` - The database
+async fn handle_mark_read(
+    user_id: i32,
+    mark_read_request: MarkReadRequest,
+    writer: &Arc<Mutex<WSWriter>>,
+    clients: &Arc<Mutex<Clients>>,
+    db: &Arc<DB>,
+) {
+    let MarkReadRequest { target, message_id } = mark_read_request;
+    if !check_target(&target, user_id, writer, db) {
+        return;
+    }
+
+    let in_conversation = db
+        .get_message(message_id)
+        .map(|message| is_in_conversation(&message, &target, user_id));
+    match in_conversation {
+        Ok(true) => {}
+        Ok(false) => {
+            spawn_write_task(writer, error(db_error(DBError::MessageNotFoundError)));
+            return;
+        }
+        Err(e) => {
+            spawn_write_task(writer, error(db_error(e)));
+            return;
+        }
+    }
+
+    match db.mark_read(user_id, &target, message_id) {
+        Ok(true) => {}
+        // The user has already read past the message
+        Ok(false) => return,
+        Err(e) => {
+            spawn_write_task(writer, error(db_error(e)));
+            return;
+        }
+    }
+
+    let audience = match get_audience(&target, user_id, db) {
+        Ok(audience) => audience,
+        Err(e) => {
+            spawn_write_task(writer, error(db_error(e)));
+            return;
+        }
+    };
+    let user = match db.get_user(user_id) {
+        Ok(user) => user,
+        Err(e) => {
+            spawn_write_task(writer, error(db_error(e)));
+            return;
+        }
+    };
+
+    let response = read_receipt(ReadReceiptResponse {
+        user_id,
+        username: user.username,
+        target,
+        message_id,
+    });
+    send_to_users(&*clients.lock().await, &audience, &response);
+}
+
+/// Moves the bytes of an uploaded image or file into the attachment store
+///
+/// Returns the content to save with the message, text is returned unchanged. Clients can't send
+/// an `Attachment` themselves, it would give them the attachment of its id.
+///
+/// # Arguments
+///
+/// * `content` - The content sent by the client
+/// * `db` - The database
+/// * `store` - The attachment store
+fn store_attachment(
+    content: MessageContent,
+    db: &Arc<DB>,
+    store: &Arc<AttachmentStore>,
+) -> Result<MessageContent, ErrorResponse> {
+    let (name, bytes) = match content {
+        MessageContent::Image(bytes) => (image_name(&bytes), bytes),
+        MessageContent::File(name, bytes) => (name, bytes),
+        MessageContent::Text(_) => return Ok(content),
+        MessageContent::Attachment(_) => return Err(server_error(attachment_not_allowed())),
+    };
+
+    let sha256 = store.save(&bytes).map_err(|e| {
+        error!(error = %e, "Failed to save the attachment");
+        db_error(DBError::AttachmentInsertionError)
+    })?;
+    let mime_type = guess_mime_type(&name);
+    let attachment_obj = db
+        .create_attachment(sha256, bytes.len() as i64, mime_type, name)
+        .map_err(db_error)?;
+
+    Ok(attachment(AttachmentInfo::from_db_attachment(
+        &attachment_obj,
+    )))
+}
+
+/// Returns whether the message belongs to the conversation, as the user sees it
+///
+/// # Arguments
+///
+/// * `message` - The message to check
+/// * `target` - The room, or the other user of the direct conversation
+/// * `user_id` - The user
+fn is_in_conversation(message: &DBMessage, target: &MessageTarget, user_id: i32) -> bool {
+    match *target {
+        MessageTarget::Room(room_id) => message.room_id == Some(room_id),
+        MessageTarget::User(peer_id) => {
+            (message.user_id == user_id && message.recipient_id == Some(peer_id))
+                || (message.user_id == peer_id && message.recipient_id == Some(user_id))
+        }
+    }
+}
+
+/// Returns the conversation the message belongs to, as the user sees it
+///
+/// Direct messages are targeted at the recipient, the conversation is with the other user.
+///
+/// # Arguments
+///
+/// * `message` - The message
+/// * `user_id` - The user
+fn get_conversation(message: &DBMessage, user_id: i32) -> MessageTarget {
+    match MessageTarget::from_db_message(message) {
+        MessageTarget::User(recipient_id) if recipient_id == user_id => {
+            MessageTarget::User(message.user_id)
+        }
+        target => target,
+    }
+}
+
+/// Checks that the message exists and was sent by the user, sends an error to the client otherwise
+///
+/// # Arguments
+///
+/// * `message_id` - The message to check
+/// * `user_id` - The user who wants to change the message
+/// * `writer` - The writer of the client, used for error responses
+/// * `db` - The database
+fn check_message_author(
+    message_id: i32,
+    user_id: i32,
+    writer: &Arc<Mutex<WSWriter>>,
+    db: &Arc<DB>,
+) -> bool {
+    match db.get_message(message_id) {
+        Ok(message_obj) if message_obj.user_id == user_id => true,
+        Ok(_) => {
+            spawn_write_task(writer, error(server_error(not_message_author())));
+            false
+        }
+        Err(e) => {
+            spawn_write_task(writer, error(db_error(e)));
+            false
+        }
+    }
+}
+
+/// Checks that the user isn't muted, sends an error to the client if they are
+///
+/// # Arguments
+/// * `user_id` - The user who wants to send a message
+/// * `writer` - The writer of the client, used for error responses
+/// * `db` - The database
+fn check_not_muted(user_id: i32, writer: &Arc<Mutex<WSWriter>>, db: &Arc<DB>) -> bool {
+    match db.get_user(user_id).map(|user| user.active_mute()) {
+        Ok(None) => true,
+        Ok(Some(muted_until)) => {
+            spawn_write_task(
+                writer,
+                error(server_error(ServerError::UserMuted(muted_until))),
+            );
+            false
+        }
+        Err(e) => {
+            spawn_write_task(writer, error(db_error(e)));
+            false
+        }
+    }
+}
+
+/// Checks that the connection and its user can send another message, sends `RateLimited` if they
+/// can't yet
+///
+/// # Arguments
+/// * `addr` - The address of the connection
+/// * `user_id` - The user the connection is authenticated as
+/// * `rate_limits` - The limits of the messages
+/// * `writer` - The writer of the client, used for error responses
+/// * `clients` - The clients registry, holding the token buckets
+async fn check_message_rate(
+    addr: SocketAddr,
+    user_id: i32,
+    rate_limits: &RateLimits,
+    writer: &Arc<Mutex<WSWriter>>,
+    clients: &Arc<Mutex<Clients>>,
+) -> bool {
+    let wait = clients
+        .lock()
+        .await
+        .throttle_message(addr, user_id, rate_limits.user_messages);
+    match wait {
+        None => true,
+        Some(wait) => {
+            spawn_write_task(
+                writer,
+                error(server_error(ServerError::RateLimited(retry_after(wait)))),
+            );
+            false
+        }
+    }
+}
+
+/// Sends the changed message to everyone who can see it
+///
+/// Returns the message and its audience once it's sent
+///
+/// # Arguments
+///
+/// * `changed_message` - The message after the change, as stored in the DB
+/// * `to_response` - Wraps the message into the matching response variant
+/// * `writer` - The writer of the client, used for error responses
+/// * `clients` - The clients registry
+/// * `db` - The database
+async fn broadcast_message_change(
+    changed_message: Result<DBMessage, DBError>,
+    to_response: fn(MessageResponse) -> ServerResponse,
+    writer: &Arc<Mutex<WSWriter>>,
+    clients: &Arc<Mutex<Clients>>,
+    db: &Arc<DB>,
+) -> Option<(MessageResponse, Vec<i32>)> {
+    let message_obj = match changed_message {
+        Ok(message_obj) => message_obj,
+        Err(e) => {
+            spawn_write_task(writer, error(db_error(e)));
+            return None;
+        }
+    };
+    let message_response = match MessageResponse::from_db_message(&message_obj, db) {
+        Ok(message_response) => message_response,
+        Err(error_response) => {
+            spawn_write_task(writer, error(error_response));
+            return None;
+        }
+    };
+    let audience = match get_audience(&message_response.target, message_obj.user_id, db) {
+        Ok(audience) => audience,
+        Err(e) => {
+            spawn_write_task(writer, error(db_error(e)));
+            return None;
+        }
+    };
+
+    send_to_users(
+        &*clients.lock().await,
+        &audience,
+        &to_response(message_response.clone()),
+    );
+    Some((message_response, audience))
+}
+
+/// Edits the user's own message and sends the new version to everyone who can see it
+///
+/// Only text can be edited, attachments stay as they were sent.
+///
+/// # Arguments
+///
+/// * `user_id` - The user the connection is authenticated as
+/// * `edit_message_request` - The edit message request
+/// * `writer` - The stream writer (for response)
+/// * `clients` - The clients registry
+/// * `db` - The database
+async fn handle_edit_message(
+    user_id: i32,
+    edit_message_request: EditMessageRequest,
+    writer: &Arc<Mutex<WSWriter>>,
+    clients: &Arc<Mutex<Clients>>,
+    db: &Arc<DB>,
+) {
+    let message_id = edit_message_request.message_id;
+    if !matches!(edit_message_request.message, MessageContent::Text(_)) {
+        spawn_write_task(writer, error(server_error(text_only_edit())));
+        return;
+    }
+    if !check_message_author(message_id, user_id, writer, db)
+        || !check_not_muted(user_id, writer, db)
+    {
+        return;
+    }
+
+    let edited = db.edit_message(message_id, edit_message_request.message);
+    if let Some((message_response, audience)) =
+        broadcast_message_change(edited, message_edited, writer, clients, db).await
+    {
+        send_mentions(message_response, &audience, writer, clients, db).await;
+    }
+}
+
+/// Deletes the user's own message, or any message if the user is a moderator, and sends the
+/// tombstone to everyone who can see it
+///
+/// # Arguments
+///
+/// * `user_id` - The user the connection is authenticated as
+/// * `delete_message_request` - The delete message request
+/// * `writer` - The stream writer (for response)
+/// * `clients` - The clients registry
+/// * `db` - The database
+async fn handle_delete_message(
+    user_id: i32,
+    delete_message_request: DeleteMessageRequest,
+    writer: &Arc<Mutex<WSWriter>>,
+    clients: &Arc<Mutex<Clients>>,
+    db: &Arc<DB>,
+) {
+    let message_id = delete_message_request.message_id;
+    let is_moderator = match db.get_user(user_id) {
+        Ok(user) => user.role() >= Role::Moderator,
+        Err(e) => {
+            spawn_write_task(writer, error(db_error(e)));
+            return;
+        }
+    };
+    if !is_moderator && !check_message_author(message_id, user_id, writer, db) {
+        return;
+    }
+
+    let deleted = db.delete_message(message_id);
+    broadcast_message_change(deleted, message_deleted, writer, clients, db).await;
+}
+
+/// Adds or removes the user's reaction to a message and sends the change to everyone who can see it
+///
+/// # Arguments
+///
+/// * `user_id` - The user the connection is authenticated as
+/// * `reaction_request` - The reaction request
+/// * `add` - Whether to add the reaction, or remove it
+/// * `writer` - The stream writer (for response)
+/// * `clients` - The clients registry
+/// * `db` - The database
+async fn handle_reaction(
+    user_id: i32,
+    reaction_request: ReactionRequest,
+    add: bool,
+    writer: &Arc<Mutex<WSWriter>>,
+    clients: &Arc<Mutex<Clients>>,
+    db: &Arc<DB>,
+) {
+    if !reaction_request.is_valid() {
+        spawn_write_task(writer, error(server_error(invalid_reaction())));
+        return;
+    }
+    let ReactionRequest { message_id, emoji } = reaction_request;
+
+    let message_obj = match db.get_message(message_id) {
+        // Deleted messages can't be reacted to
+        Ok(message_obj) if message_obj.deleted_at.is_none() => message_obj,
+        Ok(_) => {
+            spawn_write_task(writer, error(db_error(DBError::MessageNotFoundError)));
+            return;
+        }
+        Err(e) => {
+            spawn_write_task(writer, error(db_error(e)));
+            return;
+        }
+    };
+    let conversation = get_conversation(&message_obj, user_id);
+    if !is_in_conversation(&message_obj, &conversation, user_id) {
+        spawn_write_task(writer, error(db_error(DBError::MessageNotFoundError)));
+        return;
+    }
+    if !check_target(&conversation, user_id, writer, db) {
+        return;
+    }
+
+    let changed = if add {
+        db.add_reaction(message_id, user_id, &emoji)
+    } else {
+        db.remove_reaction(message_id, user_id, &emoji)
+    };
+    match changed {
+        Ok(true) => {}
+        // The user has already reacted with the emoji, or hasn't reacted with it at all
+        Ok(false) => return,
+        Err(e) => {
+            spawn_write_task(writer, error(db_error(e)));
+            return;
+        }
+    }
+
+    let target = MessageTarget::from_db_message(&message_obj);
+    let audience = match get_audience(&target, message_obj.user_id, db) {
+        Ok(audience) => audience,
+        Err(e) => {
+            spawn_write_task(writer, error(db_error(e)));
+            return;
+        }
+    };
+    let reactions_res = db.get_reactions(message_id).and_then(|reactions| {
+        let user = db.get_user(user_id)?;
+        Ok((reactions, user))
+    });
+    let (reactions, user) = match reactions_res {
+        Ok(reactions_and_user) => reactions_and_user,
+        Err(e) => {
+            spawn_write_task(writer, error(db_error(e)));
+            return;
+        }
+    };
+
+    let response = reaction(ReactionResponse {
+        message_id,
+        target,
+        user_id,
+        username: user.username,
+        emoji,
+        added: add,
+        reactions: ReactionCount::from_db_reactions(&reactions),
+    });
+    send_to_users(&*clients.lock().await, &audience, &response);
+}
+
+/// Converts the upload into its progress response
+///
+/// # Arguments
+///
+/// * `upload` - The upload
+/// * `chunk_size` - The size of the chunks the client uploads
+fn upload_progress_response(upload: &Upload, chunk_size: u32) -> ServerResponse {
+    upload_progress(UploadProgressResponse {
+        upload_id: upload.id.unwrap(),
+        name: upload.name.to_owned(),
+        size: upload.size,
+        received: upload.received,
+        chunk_size,
+    })
+}
+
+/// Handles a request to begin (or resume) a chunked upload
+///
+/// # Arguments
+///
+/// * `user_id` - The user the connection is authenticated as
+/// * `begin_upload_request` - The begin upload request
+/// * `writer` - The stream writer (for response)
+/// * `db` - The database
+/// * `store` - The attachment store
+/// * `limits` - The largest upload and the chunk size
+fn handle_begin_upload(
+    user_id: i32,
+    begin_upload_request: BeginUploadRequest,
+    writer: &Arc<Mutex<WSWriter>>,
+    db: &Arc<DB>,
+    store: &Arc<AttachmentStore>,
+    limits: &LimitsConfig,
+) {
+    let size = begin_upload_request.size.max(0);
+    if size as u64 > limits.max_upload_size {
+        spawn_write_task(writer, error(server_error(upload_too_large())));
+        return;
+    }
+    let upload = match db.begin_upload(user_id, begin_upload_request.name, size) {
+        Ok(upload) => upload,
+        Err(e) => {
+            spawn_write_task(writer, error(db_error(e)));
+            return;
+        }
+    };
+    // Creates the partial file right away, so even an empty file can be finished
+    if upload.received == 0 {
+        if let Err(e) = store.write_chunk(upload.id.unwrap(), 0, &[]) {
+            error!(error = %e, "Failed to create the upload file");
+            spawn_write_task(writer, error(db_error(DBError::UploadInsertionError)));
+            return;
+        }
+    }
+
+    spawn_write_task(
+        writer,
+        upload_progress_response(&upload, limits.upload_chunk_size),
+    );
+}
+
+/// Handles a chunk of an upload, every chunk is acknowledged with the progress of the upload
+///
+/// # Arguments
+///
+/// * `user_id` - The user the connection is authenticated as
+/// * `upload_chunk_request` - The upload chunk request
+/// * `writer` - The stream writer (for response)
+/// * `db` - The database
+/// * `store` - The attachment store
+/// * `limits` - The chunk size
+fn handle_upload_chunk(
+    user_id: i32,
+    upload_chunk_request: UploadChunkRequest,
+    writer: &Arc<Mutex<WSWriter>>,
+    db: &Arc<DB>,
+    store: &Arc<AttachmentStore>,
+    limits: &LimitsConfig,
+) {
+    let chunk_size = limits.upload_chunk_size;
+    let mut upload = match db.get_upload(upload_chunk_request.upload_id, user_id) {
+        Ok(upload) => upload,
+        Err(e) => {
+            spawn_write_task(writer, error(db_error(e)));
+            return;
+        }
+    };
+
+    let offset = upload_chunk_request.index as i64 * chunk_size as i64;
+    let end = offset + upload_chunk_request.data.len() as i64;
+    // Only the last chunk may be shorter than the chunk size
+    let expected_end = (offset + chunk_size as i64).min(upload.size);
+    if offset != upload.received || end != expected_end {
+        // The progress tells the client where to continue from
+        spawn_write_task(writer, error(server_error(invalid_upload_chunk())));
+        spawn_write_task(writer, upload_progress_response(&upload, chunk_size));
+        return;
+    }
+
+    let upload_id = upload.id.unwrap();
+    if let Err(e) = store.write_chunk(upload_id, offset as u64, &upload_chunk_request.data) {
+        error!(upload_id, error = %e, "Failed to write the upload chunk");
+        spawn_write_task(writer, error(db_error(DBError::UploadInsertionError)));
+        return;
+    }
+    if let Err(e) = db.set_upload_received(upload_id, end) {
+        spawn_write_task(writer, error(db_error(e)));
+        return;
+    }
+    upload.received = end;
+
+    spawn_write_task(writer, upload_progress_response(&upload, chunk_size));
+}
+
+/// Handles a request to finish an upload, the file is sent as a message once the checksum matches
+///
+/// # Arguments
+///
+/// * `user_id` - The user the connection is authenticated as
+/// * `finish_upload_request` - The finish upload request
+/// * `writer` - The stream writer (for response)
+/// * `clients` - The clients registry
+/// * `db` - The database
+/// * `store` - The attachment store
+async fn handle_finish_upload(
+    user_id: i32,
+    finish_upload_request: FinishUploadRequest,
+    writer: &Arc<Mutex<WSWriter>>,
+    clients: &Arc<Mutex<Clients>>,
+    db: &Arc<DB>,
+    store: &Arc<AttachmentStore>,
+) {
+    let target = finish_upload_request.target;
+    if !check_target(&target, user_id, writer, db) || !check_not_muted(user_id, writer, db) {
+        return;
+    }
+    let upload = match db.get_upload(finish_upload_request.upload_id, user_id) {
+        Ok(upload) => upload,
+        Err(e) => {
+            spawn_write_task(writer, error(db_error(e)));
+            return;
+        }
+    };
+    if upload.received != upload.size {
+        spawn_write_task(writer, error(server_error(upload_incomplete())));
+        return;
+    }
+
+    let upload_id = upload.id.unwrap();
+    let sha256 = finish_upload_request.sha256.to_lowercase();
+    match store.finish_upload(upload_id, &sha256) {
+        Ok(true) => {}
+        Ok(false) => {
+            db.delete_upload(upload_id).ok();
+            spawn_write_task(writer, error(server_error(upload_checksum_mismatch())));
+            return;
+        }
+        Err(e) => {
+            error!(upload_id, error = %e, "Failed to finish the upload");
+            spawn_write_task(writer, error(db_error(DBError::AttachmentInsertionError)));
+            return;
+        }
+    }
+    db.delete_upload(upload_id).ok();
+
+    let mime_type = guess_mime_type(&upload.name);
+    let attachment_obj = match db.create_attachment(sha256, upload.size, mime_type, upload.name) {
+        Ok(attachment_obj) => attachment_obj,
+        Err(e) => {
+            spawn_write_task(writer, error(db_error(e)));
+            return;
+        }
+    };
+    let content = attachment(AttachmentInfo::from_db_attachment(&attachment_obj));
+
+    send_message(user_id, target, content, None, writer, clients, db).await;
+}
+
+/// Handles a request to download an attachment, the bytes are streamed in chunks
+///
+/// # Arguments
+///
+/// * `user_id` - The user the connection is authenticated as
+/// * `fetch_attachment_request` - The fetch attachment request
+/// * `writer` - The stream writer (for response)
+/// * `db` - The database
+/// * `store` - The attachment store
+/// * `limits` - The size of the chunks the attachment is sent in
+async fn handle_fetch_attachment(
+    user_id: i32,
+    fetch_attachment_request: FetchAttachmentRequest,
+    writer: &Arc<Mutex<WSWriter>>,
+    db: &Arc<DB>,
+    store: &Arc<AttachmentStore>,
+    limits: &LimitsConfig,
+) {
+    let attachment_id = fetch_attachment_request.attachment_id;
+
+    // Attachments of messages the user can't see are reported as missing
+    match db.can_access_attachment(attachment_id, user_id) {
+        Ok(true) => {}
+        Ok(false) => {
+            spawn_write_task(writer, error(db_error(DBError::AttachmentNotFoundError)));
+            return;
+        }
+        Err(e) => {
+            spawn_write_task(writer, error(db_error(e)));
+            return;
+        }
+    }
+    let attachment_obj = match db.get_attachment(attachment_id) {
+        Ok(attachment_obj) => attachment_obj,
+        Err(e) => {
+            spawn_write_task(writer, error(db_error(e)));
+            return;
+        }
+    };
+    let mut file = match store.open(&attachment_obj.sha256) {
+        Ok(file) => file,
+        Err(e) => {
+            error!(attachment_id, error = %e, "Failed to open the attachment");
+            spawn_write_task(writer, error(db_error(DBError::AttachmentNotFoundError)));
+            return;
+        }
+    };
+
+    await_write_task(
+        writer,
+        attachment_info(AttachmentInfo::from_db_attachment(&attachment_obj)),
+    )
+    .await;
+
+    let mut offset = 0;
+    let mut buffer = vec![0u8; limits.attachment_chunk_size];
+    loop {
+        let read = match file.read(&mut buffer) {
+            Ok(read) => read,
+            Err(e) => {
+                error!(attachment_id, error = %e, "Failed to read the attachment");
+                return;
+            }
+        };
+        // A short file ends the download too, the client must never wait for a missing chunk
+        let last = read == 0 || offset + read as u64 >= attachment_obj.size as u64;
+
+        await_write_task(
+            writer,
+            attachment_chunk(AttachmentChunkResponse {
+                attachment_id,
+                offset,
+                data: buffer[..read].to_vec(),
+                last,
+            }),
+        )
+        .await;
+
+        offset += read as u64;
+        if last {
+            break;
+        }
+    }
+}
+
+/// Handles a request for the message history of a room or a direct conversation
+///
+/// # Arguments
+///
+/// * `user_id` - The user the connection is authenticated as
+/// * `read_request` - The read request
+/// * `writer` - The stream writer (for response)
+/// * `db` - The database
+async fn handle_read_request(
+    user_id: i32,
+    read_request: ReadRequest,
+    writer: &Arc<Mutex<WSWriter>>,
+    db: &Arc<DB>,
+) {
+    if !check_target(&read_request.target, user_id, writer, db) {
+        return;
+    }
+
+    let (messages, has_more) = match db.read_history(
+        user_id,
+        &read_request.target,
+        read_request.amount,
+        read_request.before_id,
+        read_request.before.map(|before| before.naive_utc()),
+        read_request.after.map(|after| after.naive_utc()),
+    ) {
+        Ok(page) => page,
+        Err(_) => {
+            await_write_task(writer, error(db_error(DBError::MessageHistoryError))).await;
+            return;
+        }
+    };
+    let message_responses_res = MessageResponse::from_db_messages(&messages, db);
+
+    match message_responses_res {
+        Ok(message_responses) => {
+            let history_response = HistoryResponse {
+                target: read_request.target,
+                messages: message_responses,
+                has_more,
+            };
+            await_write_task(writer, history(history_response)).await;
+        }
+        Err(error_response) => {
+            await_write_task(writer, error(error_response)).await;
+        }
+    }
+}
+
+/// Handles a request for a page of the messages mentioning the user
+///
+/// # Arguments
+///
+/// * `user_id` - The user the connection is authenticated as
+/// * `list_mentions_request` - The list mentions request
+/// * `writer` - The stream writer (for response)
+/// * `db` - The database
+async fn handle_list_mentions(
+    user_id: i32,
+    list_mentions_request: ListMentionsRequest,
+    writer: &Arc<Mutex<WSWriter>>,
+    db: &Arc<DB>,
+) {
+    let (messages, has_more) = match db.get_mentions(
+        user_id,
+        list_mentions_request.amount,
+        list_mentions_request.before_id,
+    ) {
+        Ok(page) => page,
+        Err(e) => {
+            await_write_task(writer, error(db_error(e))).await;
+            return;
+        }
+    };
+    let message_responses_res = MessageResponse::from_db_messages(&messages, db);
+
+    match message_responses_res {
+        Ok(message_responses) => {
+            let mentions_response = MentionsResponse {
+                messages: message_responses,
+                has_more,
+            };
+            await_write_task(writer, mentions(mentions_response)).await;
+        }
+        Err(error_response) => {
+            await_write_task(writer, error(error_response)).await;
+        }
+    }
+}
+
+/// Handles a search in the messages the user can see
+///
+/// # Arguments
+///
+/// * `user_id` - The user the connection is authenticated as
+/// * `search_request` - The search request
+/// * `writer` - The stream writer (for response)
+/// * `db` - The database
+async fn handle_search(
+    user_id: i32,
+    search_request: SearchRequest,
+    writer: &Arc<Mutex<WSWriter>>,
+    db: &Arc<DB>,
+) {
+    let (results, has_more) = match db.search_messages(
+        user_id,
+        &search_request.query,
+        search_request.user_id,
+        search_request.amount,
+        search_request.offset,
+    ) {
+        Ok(page) => page,
+        Err(e) => {
+            await_write_task(writer, error(db_error(e))).await;
+            return;
+        }
+    };
+    let (messages, snippets): (Vec<_>, Vec<_>) = results.into_iter().unzip();
+    let search_results_res = MessageResponse::from_db_messages(&messages, db).map(|responses| {
+        responses
+            .into_iter()
+            .zip(snippets)
+            .map(|(message, snippet)| SearchResult {
+                message,
+                snippet: SnippetPart::from_db_snippet(&snippet),
+            })
+            .collect::<Vec<SearchResult>>()
+    });
+
+    match search_results_res {
+        Ok(search_results) => {
+            let search_response = SearchResponse {
+                query: search_request.query,
+                results: search_results,
+                has_more,
+            };
+            await_write_task(writer, search(search_response)).await;
+        }
+        Err(error_response) => {
+            await_write_task(writer, error(error_response)).await;
+        }
+    }
+}
+
+/// Handles a request for a page of a thread
+///
+/// # Arguments
+///
+/// * `user_id` - The user the connection is authenticated as
+/// * `read_thread_request` - The read thread request
+/// * `writer` - The stream writer (for response)
+/// * `db` - The database
+async fn handle_read_thread(
+    user_id: i32,
+    read_thread_request: ReadThreadRequest,
+    writer: &Arc<Mutex<WSWriter>>,
+    db: &Arc<DB>,
+) {
+    let message_obj = match db.get_message(read_thread_request.message_id) {
+        Ok(message_obj) => message_obj,
+        Err(e) => {
+            await_write_task(writer, error(db_error(e))).await;
+            return;
+        }
+    };
+    let conversation = get_conversation(&message_obj, user_id);
+    if !is_in_conversation(&message_obj, &conversation, user_id) {
+        await_write_task(writer, error(db_error(DBError::MessageNotFoundError))).await;
+        return;
+    }
+    if !check_target(&conversation, user_id, writer, db) {
+        return;
+    }
+
+    let root_obj = match message_obj.thread_id {
+        Some(thread_id) => match db.get_message(thread_id) {
+            Ok(root_obj) => root_obj,
+            Err(e) => {
+                await_write_task(writer, error(db_error(e))).await;
+                return;
+            }
+        },
+        None => message_obj,
+    };
+    let (replies, has_more) = match db.read_thread(
+        root_obj.id.unwrap(),
+        read_thread_request.amount,
+        read_thread_request.after_id,
+    ) {
+        Ok(page) => page,
+        Err(e) => {
+            await_write_task(writer, error(db_error(e))).await;
+            return;
+        }
+    };
+
+    // The root is built with the replies, so the whole page is loaded together
+    let page: Vec<_> = std::iter::once(root_obj).chain(replies).collect();
+    let thread_response_res = MessageResponse::from_db_messages(&page, db).map(|mut responses| {
+        let root = responses.remove(0);
+        ThreadResponse {
+            root,
+            replies: responses,
+            has_more,
+        }
+    });
+
+    match thread_response_res {
+        Ok(thread_response) => await_write_task(writer, thread(thread_response)).await,
+        Err(error_response) => await_write_task(writer, error(error_response)).await,
+    }
+}
+
+/// Handles a request to create a new room
+///
+/// # Arguments
+///
+/// * `user_id` - The user the connection is authenticated as
+/// * `create_room_request` - The create room request
+/// * `writer` - The stream writer (for response)
+/// * `db` - The database
+fn handle_create_room(
+    user_id: i32,
+    create_room_request: CreateRoomRequest,
+    writer: &Arc<Mutex<WSWriter>>,
+    db: &Arc<DB>,
+) {
+    match db.create_room(create_room_request.name, user_id) {
+        Ok(room) => spawn_write_task(writer, room_joined(RoomResponse::from_db_room(&room, true))),
+        Err(_) => spawn_write_task(writer, error(db_error(DBError::RoomInsertionError))),
+    }
+}
+
+/// Handles a request to join a room
+///
+/// # Arguments
+///
+/// * `user_id` - The user the connection is authenticated as
+/// * `room_request` - The room request
+/// * `writer` - The stream writer (for response)
+/// * `db` - The database
+fn handle_join_room(
+    user_id: i32,
+    room_request: RoomRequest,
+    writer: &Arc<Mutex<WSWriter>>,
+    db: &Arc<DB>,
+) {
+    match db.join_room(room_request.room_id, user_id) {
+        Ok(room) => spawn_write_task(writer, room_joined(RoomResponse::from_db_room(&room, true))),
+        Err(e) => spawn_write_task(writer, error(db_error(e))),
+    }
+}
+
+/// Handles a request to leave a room
+///
+/// # Arguments
+///
+/// * `user_id` - The user the connection is authenticated as
+/// * `room_request` - The room request
+/// * `writer` - The stream writer (for response)
+/// * `db` - The database
+fn handle_leave_room(
+    user_id: i32,
+    room_request: RoomRequest,
+    writer: &Arc<Mutex<WSWriter>>,
+    db: &Arc<DB>,
+) {
+    match db.leave_room(room_request.room_id, user_id) {
+        Ok(room) => spawn_write_task(writer, room_left(RoomResponse::from_db_room(&room, false))),
+        Err(e) => spawn_write_task(writer, error(db_error(e))),
+    }
+}
+
+/// Handles a request to list all rooms
+///
+/// # Arguments
+///
+/// * `user_id` - The user the connection is authenticated as
+/// * `writer` - The stream writer (for response)
+/// * `db` - The database
+fn handle_list_rooms(user_id: i32, writer: &Arc<Mutex<WSWriter>>, db: &Arc<DB>) {
+    let rooms_res = db.list_rooms().and_then(|rooms| {
+        let joined_ids = db.get_user_room_ids(user_id)?;
+        Ok(rooms
+            .iter()
+            .map(|room| RoomResponse::from_db_room(room, joined_ids.contains(&room.id.unwrap())))
+            .collect())
+    });
+
+    match rooms_res {
+        Ok(rooms) => spawn_write_task(writer, room_list(RoomListResponse { rooms })),
+        Err(e) => spawn_write_task(writer, error(db_error(e))),
+    }
+}
+
+/// Handles a request to list the users who are online
+///
+/// # Arguments
+///
+/// * `writer` - The stream writer (for response)
+/// * `clients` - The clients registry
+/// * `db` - The database
+async fn handle_list_online_users(
+    writer: &Arc<Mutex<WSWriter>>,
+    clients: &Arc<Mutex<Clients>>,
+    db: &Arc<DB>,
+) {
+    let online_user_ids = clients.lock().await.online_user_ids();
+
+    match db.get_users(&online_user_ids) {
+        Ok(users) => {
+            let users = users
+                .into_iter()
+                .map(|user| UserPresence {
+                    user_id: user.id.unwrap(),
+                    username: user.username,
+                })
+                .collect();
+            spawn_write_task(writer, online_users(OnlineUsersResponse { users }));
+        }
+        Err(e) => spawn_write_task(writer, error(db_error(e))),
+    }
+}
+
+/// Checks that the moderator has a higher role than the user, responds with an error otherwise
+///
+/// Returns the user to moderate
+///
+/// # Arguments
+///
+/// * `moderator_id` - The user the connection is authenticated as
+/// * `user_id` - The user to moderate
+/// * `writer` - The stream writer (for response)
+/// * `db` - The database
+fn check_moderator(
+    moderator_id: i32,
+    user_id: i32,
+    writer: &Arc<Mutex<WSWriter>>,
+    db: &Arc<DB>,
+) -> Option<DBUser> {
+    let moderator_role = match db.get_user(moderator_id) {
+        Ok(moderator) => moderator.role(),
+        Err(e) => {
+            spawn_write_task(writer, error(db_error(e)));
+            return None;
+        }
+    };
+    if moderator_role < Role::Moderator {
+        spawn_write_task(writer, error(server_error(not_moderator())));
+        return None;
+    }
+
+    match db.get_user(user_id) {
+        // Also rules out moderating oneself
+        Ok(user) if user.role() >= moderator_role => {
+            spawn_write_task(writer, error(server_error(cannot_moderate())));
+            None
+        }
+        Ok(user) => Some(user),
+        Err(e) => {
+            spawn_write_task(writer, error(db_error(e)));
+            None
+        }
+    }
+}
+
+/// Sends the moderation to the moderator and to every live connection of the moderated user
+///
+/// # Arguments
+///
+/// * `moderator_id` - The moderator
+/// * `user` - The moderated user, after the moderation
+/// * `action` - What the moderator did
+/// * `writer` - The stream writer of the moderator
+/// * `clients` - The clients registry
+fn send_moderation(
+    moderator_id: i32,
+    user: &DBUser,
+    action: ModerationAction,
+    writer: &Arc<Mutex<WSWriter>>,
+    clients: &Clients,
+) {
+    let response = moderation(ModerationResponse::from_db_user(user, moderator_id, action));
+    spawn_write_task(writer, response.clone());
+    send_to_users(clients, &[user.id.unwrap()], &response);
+}
+
+/// Revokes the sessions of the user and closes all of their connections, after sending them the
+/// moderation
+///
+/// # Arguments
+///
+/// * `moderator_id` - The moderator
+/// * `user` - The moderated user
+/// * `action` - What the moderator did
+/// * `writer` - The stream writer of the moderator
+/// * `clients` - The clients registry
+/// * `db` - The database
+async fn disconnect_user(
+    moderator_id: i32,
+    user: &DBUser,
+    action: ModerationAction,
+    writer: &Arc<Mutex<WSWriter>>,
+    clients: &Arc<Mutex<Clients>>,
+    db: &Arc<DB>,
+) {
+    let user_id = user.id.unwrap();
+    // Otherwise the user's clients would just resume their sessions
+    if let Err(e) = db.revoke_user_sessions(user_id) {
+        spawn_write_task(writer, error(db_error(e)));
+        return;
+    }
+
+    let signed_out = {
+        let mut clients = clients.lock().await;
+        let (signed_out, changes) = clients.sign_out_user(user_id);
+        broadcast_presence(&clients, changes, db);
+        signed_out
+    };
+
+    let response = moderation(ModerationResponse::from_db_user(user, moderator_id, action));
+    spawn_write_task(writer, response.clone());
+    for signed_out in signed_out {
+        // The user learns why before the connection closes
+        await_write_task(&signed_out, response.clone()).await;
+        signed_out
+            .lock()
+            .await
+            .sink
+            .close()
+            .await
+            .map_err(|e| debug!(error = %e, "Failed to write into the stream"))
+            .ok();
+    }
+}
+
+/// Handles a request to mute or unmute a user, only for moderators
+///
+/// # Arguments
+///
+/// * `moderator_id` - The user the connection is authenticated as
+/// * `user_id` - The user to mute or unmute
+/// * `duration` - How long to mute the user (in seconds), `None` unmutes them
+/// * `writer` - The stream writer (for response)
+/// * `clients` - The clients registry
+/// * `db` - The database
+async fn handle_mute(
+    moderator_id: i32,
+    user_id: i32,
+    duration: Option<u64>,
+    writer: &Arc<Mutex<WSWriter>>,
+    clients: &Arc<Mutex<Clients>>,
+    db: &Arc<DB>,
+) {
+    if check_moderator(moderator_id, user_id, writer, db).is_none() {
+        return;
+    }
+
+    let duration = duration.map(|duration| duration.min(MAX_MUTE_DURATION));
+    let action = match duration {
+        Some(_) => ModerationAction::Mute,
+        None => ModerationAction::Unmute,
+    };
+    match db.mute_user(user_id, duration) {
+        Ok(user) => send_moderation(moderator_id, &user, action, writer, &*clients.lock().await),
+        Err(e) => spawn_write_task(writer, error(db_error(e))),
+    }
+}
+
+/// Handles a request to kick a user, only for moderators
+///
+/// The sessions of the user are revoked and their connections closed, they can log in again.
+///
+/// # Arguments
+///
+/// * `moderator_id` - The user the connection is authenticated as
+/// * `user_request` - The user to kick
+/// * `writer` - The stream writer (for response)
+/// * `clients` - The clients registry
+/// * `db` - The database
+async fn handle_kick(
+    moderator_id: i32,
+    user_request: UserRequest,
+    writer: &Arc<Mutex<WSWriter>>,
+    clients: &Arc<Mutex<Clients>>,
+    db: &Arc<DB>,
+) {
+    let Some(user) = check_moderator(moderator_id, user_request.user_id, writer, db) else {
+        return;
+    };
+
+    disconnect_user(
+        moderator_id,
+        &user,
+        ModerationAction::Kick,
+        writer,
+        clients,
+        db,
+    )
+    .await;
+}
+
+/// Handles a request to ban or unban a user, only for moderators
+///
+/// A banned user is kicked and can't log in until they are unbanned.
+///
+/// # Arguments
+///
+/// * `moderator_id` - The user the connection is authenticated as
+/// * `user_request` - The user to ban or unban
+/// * `banned` - Whether to ban the user, or lift their ban
+/// * `writer` - The stream writer (for response)
+/// * `clients` - The clients registry
+/// * `db` - The database
+async fn handle_ban(
+    moderator_id: i32,
+    user_request: UserRequest,
+    banned: bool,
+    writer: &Arc<Mutex<WSWriter>>,
+    clients: &Arc<Mutex<Clients>>,
+    db: &Arc<DB>,
+) {
+    if check_moderator(moderator_id, user_request.user_id, writer, db).is_none() {
+        return;
+    }
+
+    let user = match db.set_banned(user_request.user_id, banned) {
+        Ok(user) => user,
+        Err(e) => {
+            spawn_write_task(writer, error(db_error(e)));
+            return;
+        }
+    };
+
+    if banned {
+        disconnect_user(
+            moderator_id,
+            &user,
+            ModerationAction::Ban,
+            writer,
+            clients,
+            db,
+        )
+        .await;
+    } else {
+        let clients = clients.lock().await;
+        send_moderation(
+            moderator_id,
+            &user,
+            ModerationAction::Unban,
+            writer,
+            &clients,
+        );
+    }
 }
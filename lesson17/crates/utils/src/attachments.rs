@@ -0,0 +1,173 @@
+use std::{
+    fs::{create_dir_all, read_dir, remove_file, rename, File, OpenOptions},
+    io::{copy, Seek, SeekFrom, Write},
+    path::PathBuf,
+};
+
+use sha2::{Digest, Sha256};
+
+use crate::errors::StreamError;
+
+/// Content-addressed store of attachment bytes
+///
+/// Every file is saved under the hex-encoded SHA-256 of its content (`<root>/ab/abcdef...`),
+/// so identical uploads share one file on disk. The metadata lives in the `attachments` table.
+/// Unfinished chunked uploads are kept in `<root>/uploads/<upload_id>.part`.
+pub struct AttachmentStore {
+    root: PathBuf,
+}
+
+impl AttachmentStore {
+    /// Create a store keeping its files in the given directory
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        Self { root: root.into() }
+    }
+
+    /// The path of the file with the given hash
+    pub fn path(&self, sha256: &str) -> PathBuf {
+        self.root.join(&sha256[..2]).join(sha256)
+    }
+
+    /// The path of the partial file of an unfinished upload
+    fn upload_path(&self, upload_id: i32) -> PathBuf {
+        self.root
+            .join("uploads")
+            .join(format!("{}.part", upload_id))
+    }
+
+    /// Save the bytes into the store, returns their hex-encoded SHA-256
+    pub fn save(&self, bytes: &[u8]) -> Result<String, StreamError> {
+        let sha256 = format!("{:x}", Sha256::digest(bytes));
+        let path = self.path(&sha256);
+        if path.exists() {
+            return Ok(sha256);
+        }
+
+        let dir = path.parent().expect("store paths always have a parent");
+        create_dir_all(dir).map_err(StreamError::FileCreationError)?;
+
+        // Write into a temporary file first, so a failed write never leaves a truncated file
+        // under the hash of the full content
+        let temp_path = dir.join(format!("{}.{}.tmp", sha256, rand::random::<u32>()));
+        let mut file = File::create(&temp_path).map_err(StreamError::FileCreationError)?;
+        file.write_all(bytes).map_err(StreamError::FileWriteError)?;
+        rename(&temp_path, &path).map_err(StreamError::FileWriteError)?;
+
+        Ok(sha256)
+    }
+
+    /// Open the file with the given hash for reading
+    pub fn open(&self, sha256: &str) -> Result<File, StreamError> {
+        File::open(self.path(sha256)).map_err(StreamError::FileReadError)
+    }
+
+    /// Delete the file with the given hash, once no attachment refers to it
+    pub fn remove(&self, sha256: &str) -> Result<(), StreamError> {
+        match remove_file(self.path(sha256)) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                Err(StreamError::FileWriteError(e))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Write a chunk of an upload at the given offset
+    ///
+    /// Anything past the offset is cut off first, it's a leftover of a chunk that was written
+    /// but never acknowledged.
+    pub fn write_chunk(&self, upload_id: i32, offset: u64, data: &[u8]) -> Result<(), StreamError> {
+        let path = self.upload_path(upload_id);
+        create_dir_all(path.parent().expect("upload paths always have a parent"))
+            .map_err(StreamError::FileCreationError)?;
+
+        let mut file = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(&path)
+            .map_err(StreamError::FileCreationError)?;
+        file.set_len(offset).map_err(StreamError::FileWriteError)?;
+        file.seek(SeekFrom::Start(offset))
+            .map_err(StreamError::FileWriteError)?;
+        file.write_all(data).map_err(StreamError::FileWriteError)?;
+
+        Ok(())
+    }
+
+    /// Move a fully received upload into the store under the given hash
+    ///
+    /// Returns `false` if the bytes don't match the hash, the partial file is discarded then.
+    pub fn finish_upload(&self, upload_id: i32, sha256: &str) -> Result<bool, StreamError> {
+        let upload_path = self.upload_path(upload_id);
+
+        let mut hasher = Sha256::new();
+        let mut file = File::open(&upload_path).map_err(StreamError::FileReadError)?;
+        copy(&mut file, &mut hasher).map_err(StreamError::FileReadError)?;
+        if format!("{:x}", hasher.finalize()) != sha256.to_lowercase() {
+            self.discard_upload(upload_id)?;
+            return Ok(false);
+        }
+
+        let path = self.path(&sha256.to_lowercase());
+        if path.exists() {
+            remove_file(&upload_path).map_err(StreamError::FileWriteError)?;
+        } else {
+            create_dir_all(path.parent().expect("store paths always have a parent"))
+                .map_err(StreamError::FileCreationError)?;
+            rename(&upload_path, &path).map_err(StreamError::FileWriteError)?;
+        }
+
+        Ok(true)
+    }
+
+    /// The ids of the uploads with a partial file
+    pub fn partial_upload_ids(&self) -> Result<Vec<i32>, StreamError> {
+        let entries = match read_dir(self.root.join("uploads")) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(vec![]),
+            Err(e) => return Err(StreamError::FileReadError(e)),
+        };
+
+        let mut upload_ids = vec![];
+        for entry in entries {
+            let entry = entry.map_err(StreamError::FileReadError)?;
+            let name = entry.file_name();
+            let upload_id = name
+                .to_str()
+                .and_then(|name| name.strip_suffix(".part"))
+                .and_then(|id| id.parse::<i32>().ok());
+            upload_ids.extend(upload_id);
+        }
+        Ok(upload_ids)
+    }
+
+    /// Delete the partial file of an upload
+    pub fn discard_upload(&self, upload_id: i32) -> Result<(), StreamError> {
+        match remove_file(self.upload_path(upload_id)) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                Err(StreamError::FileWriteError(e))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Guess the MIME type of a file from its name
+pub fn guess_mime_type(name: &str) -> String {
+    mime_guess::from_path(name)
+        .first_or_octet_stream()
+        .to_string()
+}
+
+/// The name an image is stored under, images are sent without one
+///
+/// The extension follows the format of the bytes, so the MIME type guessed from the name matches.
+pub fn image_name(bytes: &[u8]) -> String {
+    match image::guess_format(bytes)
+        .ok()
+        .and_then(|format| format.extensions_str().first())
+    {
+        Some(extension) => format!("image.{}", extension),
+        None => "image".to_string(),
+    }
+}
@@ -1,16 +1,42 @@
+use crate::attachments::{guess_mime_type, image_name, AttachmentStore};
 use crate::errors::DBError;
-use crate::{serialize_data, MessageContent};
+use crate::{
+    deserialize_data, serialize_data, AttachmentInfo, MessageContent, MessageTarget, Role,
+};
 use bcrypt::{hash_with_salt, verify, DEFAULT_COST};
+use chrono::{NaiveDateTime, TimeDelta, Utc};
 use diesel::prelude::*;
 use diesel::r2d2::{self, ConnectionManager};
+use diesel::sql_types::{BigInt, Integer, Nullable, Text, Timestamp};
+use diesel_migrations::{embed_migrations, EmbeddedMigrations, MigrationHarness};
 use rand::RngCore;
+use std::collections::HashMap;
+use tracing::{info, warn};
 
 pub mod schema;
 use schema::messages as messages_schema;
 pub mod structs;
-use structs::{Message, ToBeInsertedMessage, ToBeInsertedUser, User};
+use structs::{
+    Attachment, Message, Reaction, ReadReceipt, Room, Session, ToBeInsertedAttachment,
+    ToBeInsertedMention, ToBeInsertedMessage, ToBeInsertedReaction, ToBeInsertedRoom,
+    ToBeInsertedRoomMember, ToBeInsertedSession, ToBeInsertedUpload, ToBeInsertedUser, Upload,
+    User,
+};
 
-static DB_PATH: &'static str = "chat.db";
+static DB_PATH: &str = "chat.db";
+
+/// The migrations of the `migrations` directory, built into the binary
+const MIGRATIONS: EmbeddedMigrations = embed_migrations!("../../migrations");
+
+/// The id of the room every user joins on registration (created by the rooms migration)
+pub static GENERAL_ROOM_ID: i32 = 1;
+
+/// Marks the start of a matched term in the snippets of `search_messages`
+pub static HIGHLIGHT_START: char = '\u{1}';
+/// Marks the end of a matched term in the snippets of `search_messages`
+pub static HIGHLIGHT_END: char = '\u{2}';
+/// The most tokens a snippet of `search_messages` has
+static SNIPPET_TOKENS: i32 = 16;
 
 type SqlitePool = r2d2::Pool<ConnectionManager<SqliteConnection>>;
 
@@ -24,41 +50,76 @@ use anyhow::Result;
 impl DB {
     /// Create a new database struct using the SQLite database at ./chat.db
     pub fn new() -> Result<Self> {
-        println!("Creating/reading database chat.db");
-        let manager = ConnectionManager::<SqliteConnection>::new(DB_PATH);
+        Self::open(DB_PATH)
+    }
+
+    /// Create a new database struct using the SQLite database at the given path
+    ///
+    /// A missing database is created, and the migrations it hasn't run yet are run.
+    pub fn open(path: &str) -> Result<Self> {
+        info!(path, "Creating/reading database");
+        let manager = ConnectionManager::<SqliteConnection>::new(path);
         let pool = r2d2::Pool::builder()
             .build(manager)
             .map_err(|_| DBError::PoolCreationError)?;
+
+        let mut conn = pool.get().map_err(|_| DBError::ConnectionError)?;
+        let migrated = conn.run_pending_migrations(MIGRATIONS).map_err(|e| {
+            warn!(error = %e, "Failed to run the migrations");
+            DBError::MigrationError
+        })?;
+        for migration in migrated {
+            info!(%migration, "Ran migration");
+        }
+
         Ok(Self { pool })
     }
 
-    /// Create a new user with the given username and password
+    /// Create a new user with the given username and password, as a member of the general room
+    ///
+    /// The user and the membership are inserted together, so a failed join doesn't leave a user
+    /// behind who can't register again.
     pub fn create_user(&self, username: String, password: String) -> Result<User> {
-        use schema::users::dsl::{id as id_field, users as users_table};
-
-        let mut salt = [0u8; 16];
-        rand::thread_rng().fill_bytes(&mut salt);
+        use schema::room_members::dsl::room_members as room_members_table;
+        use schema::rooms::dsl::{id as room_id_field, rooms as rooms_table};
+        use schema::users::dsl::users as users_table;
 
-        let hashed_password = hash_with_salt(password, DEFAULT_COST, salt).unwrap();
-        let new_user =
-            ToBeInsertedUser::new(username.clone(), hashed_password.to_string(), salt.to_vec());
+        let (hashed_password, salt) = hash_password(password);
+        let new_user = ToBeInsertedUser::new(
+            username.clone(),
+            hashed_password,
+            salt,
+            Role::Member.as_db_role().to_string(),
+            None,
+            None,
+        );
 
         let mut conn = self.pool.get().map_err(|_| DBError::ConnectionError)?;
-        diesel::insert_into(users_table)
-            .values(&new_user)
-            .execute(&mut conn)
-            .map_err(|e| {
-                println!("{}", e);
-                return DBError::UserInsertionError;
-            })?;
+        let user = conn.transaction(|conn| {
+            let user: User = diesel::insert_into(users_table)
+                .values(&new_user)
+                .get_result(conn)
+                .map_err(|e| {
+                    warn!(error = %e, "Failed to insert the user");
+                    DBError::UserInsertionError
+                })?;
 
-        // Diesel doesn't support RETURNING clause for SQLite, so I have to fetch the last user manually
-        let user = users_table
-            .order(id_field.desc())
-            .first(&mut conn)
-            .map_err(|_| DBError::UserNotFoundError)?;
+            let _: Room = rooms_table
+                .filter(room_id_field.eq(GENERAL_ROOM_ID))
+                .first(conn)
+                .map_err(|_| DBError::RoomNotFoundError)?;
+            diesel::insert_or_ignore_into(room_members_table)
+                .values(&ToBeInsertedRoomMember::new(
+                    GENERAL_ROOM_ID,
+                    user.id.unwrap(),
+                ))
+                .execute(conn)
+                .map_err(|_| DBError::RoomMembershipError)?;
+
+            Ok::<_, DBError>(user)
+        })?;
 
-        println!("User created");
+        info!(user_id = user.id, "User created");
 
         Ok(user)
     }
@@ -92,28 +153,833 @@ impl DB {
         Ok(verified)
     }
 
-    /// Save a message from the given user
-    pub fn save_message(&self, user_id: i32, message: MessageContent) -> Result<Message> {
-        use schema::messages::dsl::{id as id_field, messages as messages_table};
+    /// Replace the password of the user
+    pub fn set_password(&self, user_id: i32, password: String) -> Result<(), DBError> {
+        use schema::users::dsl::{
+            id as id_field, password as password_field, salt as salt_field, users as users_table,
+        };
+
+        let (hashed_password, salt) = hash_password(password);
+        let mut conn = self.pool.get().map_err(|_| DBError::ConnectionError)?;
+        let updated = diesel::update(users_table.filter(id_field.eq(user_id)))
+            .set((password_field.eq(hashed_password), salt_field.eq(salt)))
+            .execute(&mut conn)
+            .map_err(|_| DBError::UserUpdateError)?;
+        if updated == 0 {
+            return Err(DBError::UserNotFoundError);
+        }
+
+        Ok(())
+    }
+
+    /// Change the role of the user
+    pub fn set_role(&self, user_id: i32, role: Role) -> Result<User, DBError> {
+        use schema::users::dsl::{id as id_field, role as role_field, users as users_table};
+
+        let mut conn = self.pool.get().map_err(|_| DBError::ConnectionError)?;
+        let updated = diesel::update(users_table.filter(id_field.eq(user_id)))
+            .set(role_field.eq(role.as_db_role()))
+            .execute(&mut conn)
+            .map_err(|_| DBError::UserUpdateError)?;
+        if updated == 0 {
+            return Err(DBError::UserNotFoundError);
+        }
 
+        self.get_user(user_id)
+    }
+
+    /// Delete the user with everything they left behind: their messages, the direct messages sent
+    /// to them, their reactions, mentions, read receipts, room memberships, sessions and uploads
+    ///
+    /// Replies to the deleted messages stay, without the quote. The attachments no message refers
+    /// to anymore are deleted from the store, and so are the partial files of the uploads.
+    /// Returns the amount of deleted messages.
+    pub fn delete_user(&self, user_id: i32, store: &AttachmentStore) -> Result<usize, DBError> {
+        use schema::users::dsl::{id as id_field, users as users_table};
+        use schema::{
+            mentions, messages, reactions, read_receipts, room_members, sessions, uploads,
+        };
+
+        let mut conn = self.pool.get().map_err(|_| DBError::ConnectionError)?;
+        let (deleted_messages, upload_ids) = conn
+            .transaction(|conn| {
+                let deleted_messages = diesel::delete(
+                    messages::table.filter(
+                        messages::user_id
+                            .eq(user_id)
+                            .or(messages::recipient_id.eq(user_id)),
+                    ),
+                )
+                .execute(conn)?;
+                diesel::delete(reactions::table.filter(reactions::user_id.eq(user_id)))
+                    .execute(conn)?;
+                diesel::delete(mentions::table.filter(mentions::user_id.eq(user_id)))
+                    .execute(conn)?;
+                diesel::delete(
+                    read_receipts::table.filter(
+                        read_receipts::user_id
+                            .eq(user_id)
+                            .or(read_receipts::peer_id.eq(user_id)),
+                    ),
+                )
+                .execute(conn)?;
+                diesel::delete(room_members::table.filter(room_members::user_id.eq(user_id)))
+                    .execute(conn)?;
+                diesel::delete(sessions::table.filter(sessions::user_id.eq(user_id)))
+                    .execute(conn)?;
+                let upload_ids: Vec<Option<i32>> =
+                    diesel::delete(uploads::table.filter(uploads::user_id.eq(user_id)))
+                        .returning(uploads::id)
+                        .get_results(conn)?;
+                if diesel::delete(users_table.filter(id_field.eq(user_id))).execute(conn)? == 0 {
+                    return Err(diesel::result::Error::NotFound);
+                }
+                remove_dangling_references(conn)?;
+                Ok((deleted_messages, upload_ids))
+            })
+            .map_err(|e| match e {
+                diesel::result::Error::NotFound => DBError::UserNotFoundError,
+                _ => DBError::UserDeletionError,
+            })?;
+
+        for upload_id in upload_ids.into_iter().flatten() {
+            if let Err(e) = store.discard_upload(upload_id) {
+                warn!(upload_id, error = %e, "Failed to delete the partial upload");
+            }
+        }
+        self.remove_unreferenced_attachments(store)?;
+
+        Ok(deleted_messages)
+    }
+
+    /// Get all users with the amount of messages (that aren't deleted) each of them has sent
+    pub fn list_users(&self) -> Result<Vec<(User, i64)>, DBError> {
+        use schema::messages::dsl::{
+            deleted_at as deleted_at_field, messages as messages_table, user_id as user_id_field,
+        };
+        use schema::users::dsl::{id as id_field, users as users_table};
+
+        let mut conn = self.pool.get().map_err(|_| DBError::ConnectionError)?;
+        let users: Vec<User> = users_table
+            .order(id_field.asc())
+            .load(&mut conn)
+            .map_err(|_| DBError::UserNotFoundError)?;
+        let counts: HashMap<i32, i64> = messages_table
+            .filter(deleted_at_field.is_null())
+            .group_by(user_id_field)
+            .select((user_id_field, diesel::dsl::count_star()))
+            .load::<(i32, i64)>(&mut conn)
+            .map_err(|_| DBError::MessageHistoryError)?
+            .into_iter()
+            .collect();
+
+        Ok(users
+            .into_iter()
+            .map(|user| {
+                let count = counts.get(&user.id.unwrap()).copied().unwrap_or(0);
+                (user, count)
+            })
+            .collect())
+    }
+
+    /// Mute the user for the given duration (in seconds), or unmute them with `None`
+    pub fn mute_user(&self, user_id: i32, duration: Option<u64>) -> Result<User, DBError> {
+        use schema::users::dsl::{
+            id as id_field, muted_until as muted_until_field, users as users_table,
+        };
+
+        let muted_until =
+            duration.map(|duration| Utc::now().naive_utc() + TimeDelta::seconds(duration as i64));
+        let mut conn = self.pool.get().map_err(|_| DBError::ConnectionError)?;
+        let updated = diesel::update(users_table.filter(id_field.eq(user_id)))
+            .set(muted_until_field.eq(muted_until))
+            .execute(&mut conn)
+            .map_err(|_| DBError::UserUpdateError)?;
+        if updated == 0 {
+            return Err(DBError::UserNotFoundError);
+        }
+
+        self.get_user(user_id)
+    }
+
+    /// Ban the user, or lift their ban, a banned user can't log in
+    pub fn set_banned(&self, user_id: i32, banned: bool) -> Result<User, DBError> {
+        use schema::users::dsl::{
+            banned_at as banned_at_field, id as id_field, users as users_table,
+        };
+
+        let banned_at = banned.then(|| Utc::now().naive_utc());
+        let mut conn = self.pool.get().map_err(|_| DBError::ConnectionError)?;
+        let updated = diesel::update(users_table.filter(id_field.eq(user_id)))
+            .set(banned_at_field.eq(banned_at))
+            .execute(&mut conn)
+            .map_err(|_| DBError::UserUpdateError)?;
+        if updated == 0 {
+            return Err(DBError::UserNotFoundError);
+        }
+
+        self.get_user(user_id)
+    }
+
+    /// Save a message from the given user, sent either into a room or directly to another user
+    ///
+    /// A reply joins the thread of its parent, or starts a thread if the parent isn't in one
+    pub fn save_message(
+        &self,
+        user_id: i32,
+        target: &MessageTarget,
+        message: MessageContent,
+        parent: Option<&Message>,
+    ) -> Result<Message> {
+        let (room_id, recipient_id) = match *target {
+            MessageTarget::Room(room_id) => (Some(room_id), None),
+            MessageTarget::User(recipient_id) => (None, Some(recipient_id)),
+        };
+
+        let attachment_id = match &message {
+            MessageContent::Attachment(info) => Some(info.id),
+            _ => None,
+        };
+
+        let search_text = message.search_text();
         let serialized_message = serialize_data(message)?;
-        let new_message = ToBeInsertedMessage::new(user_id, serialized_message);
+        let new_message = ToBeInsertedMessage::new(
+            user_id,
+            serialized_message,
+            room_id,
+            recipient_id,
+            Utc::now().naive_utc(),
+            None,
+            None,
+            attachment_id,
+            parent.and_then(|parent| parent.id),
+            parent.and_then(|parent| parent.thread_id.or(parent.id)),
+            Some(search_text),
+        );
 
         let mut conn = self.pool.get().map_err(|_| DBError::ConnectionError)?;
-        diesel::insert_into(messages_schema::table)
+        let message = diesel::insert_into(messages_schema::table)
             .values(&new_message)
-            .execute(&mut conn)
+            .get_result(&mut conn)
             .map_err(|_| DBError::MessageInsertionError)?;
 
-        // Diesel doesn't support RETURNING clause for SQLite, so I have to fetch the last message manually
+        Ok(message)
+    }
+
+    /// Get the message with the given id
+    pub fn get_message(&self, message_id: i32) -> Result<Message, DBError> {
+        use schema::messages::dsl::{id as id_field, messages as messages_table};
+
+        let mut conn = self.pool.get().map_err(|_| DBError::ConnectionError)?;
         let message = messages_table
-            .order(id_field.desc())
+            .filter(id_field.eq(message_id))
             .first(&mut conn)
-            .map_err(|_| DBError::UserNotFoundError)?;
+            .map_err(|_| DBError::MessageNotFoundError)?;
 
         Ok(message)
     }
 
+    /// Get the messages with the given ids by id, unknown ids are skipped
+    pub fn get_messages(&self, message_ids: &[i32]) -> Result<HashMap<i32, Message>, DBError> {
+        use schema::messages::dsl::{id as id_field, messages as messages_table};
+
+        let mut conn = self.pool.get().map_err(|_| DBError::ConnectionError)?;
+        let messages: Vec<Message> = messages_table
+            .filter(id_field.eq_any(message_ids))
+            .load(&mut conn)
+            .map_err(|_| DBError::MessageNotFoundError)?;
+
+        Ok(messages
+            .into_iter()
+            .map(|message| (message.id.unwrap(), message))
+            .collect())
+    }
+
+    /// Load what the responses of a page of messages need besides the messages themselves, in
+    /// one query per table rather than per message
+    pub fn get_message_details(&self, messages: &[Message]) -> Result<MessageDetails, DBError> {
+        let message_ids: Vec<i32> = messages.iter().filter_map(|message| message.id).collect();
+        let parent_ids: Vec<i32> = messages
+            .iter()
+            .filter_map(|message| message.parent_id)
+            .collect();
+        let parents = self.get_messages(&parent_ids)?;
+
+        let user_ids: Vec<i32> = messages
+            .iter()
+            .chain(parents.values())
+            .map(|message| message.user_id)
+            .collect();
+        let usernames = self
+            .get_users(&user_ids)?
+            .into_iter()
+            .map(|user| (user.id.unwrap(), user.username))
+            .collect();
+
+        Ok(MessageDetails {
+            usernames,
+            parents,
+            reactions: self.get_reactions_by_message(&message_ids)?,
+        })
+    }
+
+    /// Replace the content of the message, deleted messages can't be edited
+    pub fn edit_message(
+        &self,
+        message_id: i32,
+        message: MessageContent,
+    ) -> Result<Message, DBError> {
+        use schema::messages::dsl::{
+            content as content_field, deleted_at as deleted_at_field, edited_at as edited_at_field,
+            id as id_field, messages as messages_table, search_text as search_text_field,
+        };
+
+        let search_text = message.search_text();
+        let serialized_message =
+            serialize_data(message).map_err(|_| DBError::MessageInsertionError)?;
+
+        let mut conn = self.pool.get().map_err(|_| DBError::ConnectionError)?;
+        let updated = diesel::update(
+            messages_table
+                .filter(id_field.eq(message_id))
+                .filter(deleted_at_field.is_null()),
+        )
+        .set((
+            content_field.eq(serialized_message),
+            edited_at_field.eq(Some(Utc::now().naive_utc())),
+            search_text_field.eq(Some(search_text)),
+        ))
+        .execute(&mut conn)
+        .map_err(|_| DBError::MessageInsertionError)?;
+
+        if updated == 0 {
+            return Err(DBError::MessageNotFoundError);
+        }
+
+        self.get_message(message_id)
+    }
+
+    /// Extract the search text of the messages saved before messages were indexed for search
+    ///
+    /// Returns the number of messages indexed
+    pub fn index_messages(&self) -> Result<usize, DBError> {
+        use schema::messages::dsl::{
+            content as content_field, deleted_at as deleted_at_field, id as id_field,
+            messages as messages_table, search_text as search_text_field,
+        };
+
+        let mut conn = self.pool.get().map_err(|_| DBError::ConnectionError)?;
+        let unindexed: Vec<(Option<i32>, Vec<u8>)> = messages_table
+            .filter(search_text_field.is_null())
+            .filter(deleted_at_field.is_null())
+            .select((id_field, content_field))
+            .load(&mut conn)
+            .map_err(|_| DBError::MessageHistoryError)?;
+
+        for (message_id, content) in &unindexed {
+            // Content that can't be deserialized is indexed as empty, so it isn't retried
+            let search_text = deserialize_data(content.to_owned())
+                .map(|content| content.search_text())
+                .unwrap_or_default();
+            diesel::update(messages_table.filter(id_field.eq(message_id)))
+                .set(search_text_field.eq(Some(search_text)))
+                .execute(&mut conn)
+                .map_err(|_| DBError::MessageInsertionError)?;
+        }
+
+        Ok(unindexed.len())
+    }
+
+    /// Move the images and files saved inside messages, before the attachment store, into the store
+    ///
+    /// Returns the number of messages converted
+    pub fn store_inline_attachments(&self, store: &AttachmentStore) -> Result<usize, DBError> {
+        use schema::messages::dsl::{
+            attachment_id as attachment_id_field, content as content_field,
+            deleted_at as deleted_at_field, id as id_field, messages as messages_table,
+            search_text as search_text_field,
+        };
+
+        let mut conn = self.pool.get().map_err(|_| DBError::ConnectionError)?;
+        let unconverted: Vec<(Option<i32>, Vec<u8>)> = messages_table
+            .filter(attachment_id_field.is_null())
+            .filter(deleted_at_field.is_null())
+            .select((id_field, content_field))
+            .load(&mut conn)
+            .map_err(|_| DBError::MessageHistoryError)?;
+
+        let mut converted = 0;
+        for (message_id, content) in unconverted {
+            let (name, bytes) = match deserialize_data(content) {
+                Ok(MessageContent::Image(bytes)) => (image_name(&bytes), bytes),
+                Ok(MessageContent::File(name, bytes)) => (name, bytes),
+                _ => continue,
+            };
+
+            let sha256 = store.save(&bytes).map_err(|e| {
+                warn!(error = %e, "Failed to save the attachment");
+                DBError::AttachmentInsertionError
+            })?;
+            let mime_type = guess_mime_type(&name);
+            let attachment = self.create_attachment(sha256, bytes.len() as i64, mime_type, name)?;
+            let content =
+                MessageContent::Attachment(AttachmentInfo::from_db_attachment(&attachment));
+            let search_text = content.search_text();
+            let serialized_message =
+                serialize_data(content).map_err(|_| DBError::MessageInsertionError)?;
+
+            diesel::update(messages_table.filter(id_field.eq(message_id)))
+                .set((
+                    content_field.eq(serialized_message),
+                    attachment_id_field.eq(attachment.id),
+                    search_text_field.eq(Some(search_text)),
+                ))
+                .execute(&mut conn)
+                .map_err(|_| DBError::MessageInsertionError)?;
+            converted += 1;
+        }
+
+        Ok(converted)
+    }
+
+    /// Soft-delete the message, leaving a tombstone with empty content in the history
+    pub fn delete_message(&self, message_id: i32) -> Result<Message, DBError> {
+        use schema::messages::dsl::{
+            content as content_field, deleted_at as deleted_at_field, id as id_field,
+            messages as messages_table, search_text as search_text_field,
+        };
+
+        let tombstone = serialize_data(MessageContent::Text(String::new()))
+            .map_err(|_| DBError::MessageInsertionError)?;
+
+        let mut conn = self.pool.get().map_err(|_| DBError::ConnectionError)?;
+        let updated = diesel::update(
+            messages_table
+                .filter(id_field.eq(message_id))
+                .filter(deleted_at_field.is_null()),
+        )
+        .set((
+            content_field.eq(tombstone),
+            deleted_at_field.eq(Some(Utc::now().naive_utc())),
+            // Removes the message from the search index
+            search_text_field.eq(None::<String>),
+        ))
+        .execute(&mut conn)
+        .map_err(|_| DBError::MessageInsertionError)?;
+
+        if updated == 0 {
+            return Err(DBError::MessageNotFoundError);
+        }
+
+        self.get_message(message_id)
+    }
+
+    /// Save the metadata of an attachment whose bytes are already in the attachment store
+    pub fn create_attachment(
+        &self,
+        sha256: String,
+        size: i64,
+        mime_type: String,
+        name: String,
+    ) -> Result<Attachment, DBError> {
+        use schema::attachments::dsl::attachments as attachments_table;
+
+        let new_attachment = ToBeInsertedAttachment::new(sha256, size, mime_type, name);
+
+        let mut conn = self.pool.get().map_err(|_| DBError::ConnectionError)?;
+        let attachment = diesel::insert_into(attachments_table)
+            .values(&new_attachment)
+            .get_result(&mut conn)
+            .map_err(|_| DBError::AttachmentInsertionError)?;
+
+        Ok(attachment)
+    }
+
+    /// Get the metadata of the attachment with the given id
+    pub fn get_attachment(&self, attachment_id: i32) -> Result<Attachment, DBError> {
+        use schema::attachments::dsl::{attachments as attachments_table, id as id_field};
+
+        let mut conn = self.pool.get().map_err(|_| DBError::ConnectionError)?;
+        let attachment = attachments_table
+            .filter(id_field.eq(attachment_id))
+            .first(&mut conn)
+            .map_err(|_| DBError::AttachmentNotFoundError)?;
+
+        Ok(attachment)
+    }
+
+    /// Get the user's unfinished upload of the file with the given name and size, or start a new one
+    pub fn begin_upload(&self, user_id: i32, name: String, size: i64) -> Result<Upload, DBError> {
+        use schema::uploads::dsl::{
+            id as id_field, name as name_field, size as size_field, uploads as uploads_table,
+            user_id as user_id_field,
+        };
+
+        let mut conn = self.pool.get().map_err(|_| DBError::ConnectionError)?;
+        let unfinished = uploads_table
+            .filter(user_id_field.eq(user_id))
+            .filter(name_field.eq(&name))
+            .filter(size_field.eq(size))
+            .order(id_field.desc())
+            .first(&mut conn)
+            .optional()
+            .map_err(|_| DBError::UploadNotFoundError)?;
+        if let Some(upload) = unfinished {
+            return Ok(upload);
+        }
+
+        let new_upload = ToBeInsertedUpload::new(user_id, name, size, 0, Utc::now().naive_utc());
+        let upload = diesel::insert_into(uploads_table)
+            .values(&new_upload)
+            .get_result(&mut conn)
+            .map_err(|_| DBError::UploadInsertionError)?;
+
+        Ok(upload)
+    }
+
+    /// Get the upload with the given id, uploads of other users are reported as missing
+    pub fn get_upload(&self, upload_id: i32, user_id: i32) -> Result<Upload, DBError> {
+        use schema::uploads::dsl::{
+            id as id_field, uploads as uploads_table, user_id as user_id_field,
+        };
+
+        let mut conn = self.pool.get().map_err(|_| DBError::ConnectionError)?;
+        let upload = uploads_table
+            .filter(id_field.eq(upload_id))
+            .filter(user_id_field.eq(user_id))
+            .first(&mut conn)
+            .map_err(|_| DBError::UploadNotFoundError)?;
+
+        Ok(upload)
+    }
+
+    /// Record how many bytes of the upload have been written to disk
+    pub fn set_upload_received(&self, upload_id: i32, received: i64) -> Result<(), DBError> {
+        use schema::uploads::dsl::{
+            id as id_field, received as received_field, uploads as uploads_table,
+        };
+
+        let mut conn = self.pool.get().map_err(|_| DBError::ConnectionError)?;
+        diesel::update(uploads_table.filter(id_field.eq(upload_id)))
+            .set(received_field.eq(received))
+            .execute(&mut conn)
+            .map_err(|_| DBError::UploadInsertionError)?;
+
+        Ok(())
+    }
+
+    /// Delete the upload, once it is finished or discarded
+    pub fn delete_upload(&self, upload_id: i32) -> Result<(), DBError> {
+        use schema::uploads::dsl::{id as id_field, uploads as uploads_table};
+
+        let mut conn = self.pool.get().map_err(|_| DBError::ConnectionError)?;
+        diesel::delete(uploads_table.filter(id_field.eq(upload_id)))
+            .execute(&mut conn)
+            .map_err(|_| DBError::UploadNotFoundError)?;
+
+        Ok(())
+    }
+
+    /// Delete the uploads that have stayed unfinished longer than their lifetime, and the partial
+    /// files left without an upload, e.g. by deleted users
+    ///
+    /// Returns the amount of deleted partial files.
+    ///
+    /// # Arguments
+    /// * `store` - The attachment store holding the partial files
+    /// * `lifetime` - How long an upload can stay unfinished (in seconds)
+    pub fn remove_stale_uploads(
+        &self,
+        store: &AttachmentStore,
+        lifetime: u64,
+    ) -> Result<usize, DBError> {
+        use schema::uploads::dsl::{
+            created_at as created_at_field, id as id_field, uploads as uploads_table,
+        };
+
+        let before = Utc::now().naive_utc() - TimeDelta::seconds(lifetime as i64);
+        let mut conn = self.pool.get().map_err(|_| DBError::ConnectionError)?;
+        diesel::delete(uploads_table.filter(created_at_field.lt(before)))
+            .execute(&mut conn)
+            .map_err(|_| DBError::UploadNotFoundError)?;
+        // Listed before the uploads are loaded, so the file of an upload started in between is
+        // never taken for a leftover
+        let partial_ids = store.partial_upload_ids().map_err(|e| {
+            warn!(error = %e, "Failed to list the partial uploads");
+            DBError::UploadNotFoundError
+        })?;
+        let upload_ids: Vec<Option<i32>> = uploads_table
+            .select(id_field)
+            .load(&mut conn)
+            .map_err(|_| DBError::UploadNotFoundError)?;
+
+        let mut removed = 0;
+        for upload_id in partial_ids {
+            if upload_ids.contains(&Some(upload_id)) {
+                continue;
+            }
+            match store.discard_upload(upload_id) {
+                Ok(()) => removed += 1,
+                Err(e) => warn!(upload_id, error = %e, "Failed to delete the partial upload"),
+            }
+        }
+
+        Ok(removed)
+    }
+
+    /// Start a new session of the user
+    ///
+    /// # Arguments
+    /// * `user_id` - The user who logged in
+    /// * `refresh_token_hash` - The hash of the session's refresh token
+    /// * `lifetime` - How long the refresh token is valid (in seconds)
+    pub fn create_session(
+        &self,
+        user_id: i32,
+        refresh_token_hash: String,
+        lifetime: u64,
+    ) -> Result<Session, DBError> {
+        use schema::sessions::dsl::sessions as sessions_table;
+
+        let mut conn = self.pool.get().map_err(|_| DBError::ConnectionError)?;
+        let now = Utc::now().naive_utc();
+        let new_session = ToBeInsertedSession::new(
+            user_id,
+            refresh_token_hash,
+            now,
+            now + TimeDelta::seconds(lifetime as i64),
+            None,
+        );
+        let session = diesel::insert_into(sessions_table)
+            .values(&new_session)
+            .get_result(&mut conn)
+            .map_err(|_| DBError::SessionInsertionError)?;
+
+        Ok(session)
+    }
+
+    /// Check whether the session hasn't been revoked
+    pub fn is_session_active(&self, session_id: i32) -> Result<bool, DBError> {
+        use schema::sessions::dsl::{
+            id as id_field, revoked_at as revoked_at_field, sessions as sessions_table,
+        };
+
+        let mut conn = self.pool.get().map_err(|_| DBError::ConnectionError)?;
+        let count: i64 = sessions_table
+            .filter(id_field.eq(session_id))
+            .filter(revoked_at_field.is_null())
+            .count()
+            .get_result(&mut conn)
+            .map_err(|_| DBError::SessionNotFoundError)?;
+
+        Ok(count > 0)
+    }
+
+    /// Replace the refresh token of a live session, so every refresh token can be used only once
+    ///
+    /// # Arguments
+    /// * `refresh_token_hash` - The hash of the refresh token being used
+    /// * `new_refresh_token_hash` - The hash of the refresh token replacing it
+    /// * `lifetime` - How long the new refresh token is valid (in seconds)
+    pub fn refresh_session(
+        &self,
+        refresh_token_hash: &str,
+        new_refresh_token_hash: String,
+        lifetime: u64,
+    ) -> Result<Session, DBError> {
+        use schema::sessions::dsl::{
+            expires_at as expires_at_field, id as id_field,
+            refresh_token_hash as refresh_token_hash_field, revoked_at as revoked_at_field,
+            sessions as sessions_table,
+        };
+
+        let mut conn = self.pool.get().map_err(|_| DBError::ConnectionError)?;
+        let now = Utc::now().naive_utc();
+        let session: Session = sessions_table
+            .filter(refresh_token_hash_field.eq(refresh_token_hash))
+            .filter(revoked_at_field.is_null())
+            .filter(expires_at_field.gt(now))
+            .first(&mut conn)
+            .map_err(|_| DBError::SessionNotFoundError)?;
+
+        // Only the request that still sees the old token swaps it, a concurrent reuse finds nothing
+        let updated = diesel::update(
+            sessions_table
+                .filter(id_field.eq(session.id))
+                .filter(refresh_token_hash_field.eq(refresh_token_hash)),
+        )
+        .set((
+            refresh_token_hash_field.eq(new_refresh_token_hash),
+            expires_at_field.eq(now + TimeDelta::seconds(lifetime as i64)),
+        ))
+        .execute(&mut conn)
+        .map_err(|_| DBError::SessionInsertionError)?;
+        if updated == 0 {
+            return Err(DBError::SessionNotFoundError);
+        }
+
+        let session = sessions_table
+            .filter(id_field.eq(session.id))
+            .first(&mut conn)
+            .map_err(|_| DBError::SessionNotFoundError)?;
+
+        Ok(session)
+    }
+
+    /// Revoke the session, its tokens can't be used or refreshed anymore
+    pub fn revoke_session(&self, session_id: i32) -> Result<(), DBError> {
+        use schema::sessions::dsl::{
+            id as id_field, revoked_at as revoked_at_field, sessions as sessions_table,
+        };
+
+        let mut conn = self.pool.get().map_err(|_| DBError::ConnectionError)?;
+        diesel::update(
+            sessions_table
+                .filter(id_field.eq(session_id))
+                .filter(revoked_at_field.is_null()),
+        )
+        .set(revoked_at_field.eq(Utc::now().naive_utc()))
+        .execute(&mut conn)
+        .map_err(|_| DBError::SessionInsertionError)?;
+
+        Ok(())
+    }
+
+    /// Revoke every live session of the user, e.g. when they are kicked or banned
+    pub fn revoke_user_sessions(&self, user_id: i32) -> Result<(), DBError> {
+        use schema::sessions::dsl::{
+            revoked_at as revoked_at_field, sessions as sessions_table, user_id as user_id_field,
+        };
+
+        let mut conn = self.pool.get().map_err(|_| DBError::ConnectionError)?;
+        diesel::update(
+            sessions_table
+                .filter(user_id_field.eq(user_id))
+                .filter(revoked_at_field.is_null()),
+        )
+        .set(revoked_at_field.eq(Utc::now().naive_utc()))
+        .execute(&mut conn)
+        .map_err(|_| DBError::SessionInsertionError)?;
+
+        Ok(())
+    }
+
+    /// Delete the messages sent before the given time, with their reactions and mentions
+    ///
+    /// Newer replies to them stay, without the quote. The attachments no message refers to
+    /// anymore are deleted from the store. Returns the amount of deleted messages.
+    pub fn purge_messages(
+        &self,
+        before: NaiveDateTime,
+        store: &AttachmentStore,
+    ) -> Result<usize, DBError> {
+        use schema::messages::dsl::{created_at as created_at_field, messages as messages_table};
+
+        let mut conn = self.pool.get().map_err(|_| DBError::ConnectionError)?;
+        let purged = conn
+            .transaction(|conn| {
+                let purged = diesel::delete(messages_table.filter(created_at_field.lt(before)))
+                    .execute(conn)?;
+                remove_dangling_references(conn)?;
+                Ok(purged)
+            })
+            .map_err(|_: diesel::result::Error| DBError::MessageDeletionError)?;
+        self.remove_unreferenced_attachments(store)?;
+
+        Ok(purged)
+    }
+
+    /// Delete the attachments no message refers to, with their files unless another attachment
+    /// has the same content
+    ///
+    /// Returns the amount of deleted attachments. Only safe while the server is stopped, it
+    /// creates attachments just before the messages carrying them.
+    pub fn remove_unreferenced_attachments(
+        &self,
+        store: &AttachmentStore,
+    ) -> Result<usize, DBError> {
+        use schema::attachments::dsl::{
+            attachments as attachments_table, id as id_field, sha256 as sha256_field,
+        };
+        use schema::messages::dsl::{attachment_id, messages as messages_table};
+
+        let mut conn = self.pool.get().map_err(|_| DBError::ConnectionError)?;
+        let (removed, unused_hashes) = conn
+            .transaction(|conn| {
+                let referenced = messages_table
+                    .filter(attachment_id.is_not_null())
+                    .select(attachment_id);
+                let removed: Vec<String> = diesel::delete(
+                    attachments_table.filter(id_field.nullable().ne_all(referenced)),
+                )
+                .returning(sha256_field)
+                .get_results(conn)?;
+
+                let kept: Vec<String> = attachments_table
+                    .filter(sha256_field.eq_any(&removed))
+                    .select(sha256_field)
+                    .load(conn)?;
+                let mut unused_hashes: Vec<String> = removed
+                    .iter()
+                    .filter(|sha256| !kept.contains(sha256))
+                    .cloned()
+                    .collect();
+                unused_hashes.sort();
+                unused_hashes.dedup();
+                Ok((removed.len(), unused_hashes))
+            })
+            .map_err(|_: diesel::result::Error| DBError::AttachmentDeletionError)?;
+
+        for sha256 in unused_hashes {
+            if let Err(e) = store.remove(&sha256) {
+                warn!(sha256, error = %e, "Failed to delete the attachment file");
+            }
+        }
+
+        Ok(removed)
+    }
+
+    /// Rebuild the database file to reclaim the space of deleted rows, and merge the segments of
+    /// the search index
+    pub fn vacuum(&self) -> Result<(), DBError> {
+        let mut conn = self.pool.get().map_err(|_| DBError::ConnectionError)?;
+        diesel::sql_query("INSERT INTO messages_fts (messages_fts) VALUES ('optimize')")
+            .execute(&mut conn)
+            .map_err(|_| DBError::VacuumError)?;
+        diesel::sql_query("VACUUM")
+            .execute(&mut conn)
+            .map_err(|_| DBError::VacuumError)?;
+
+        Ok(())
+    }
+
+    /// Check whether the user can see a message (that isn't deleted) carrying the attachment
+    pub fn can_access_attachment(&self, attachment_id: i32, user_id: i32) -> Result<bool, DBError> {
+        use schema::messages::dsl::{
+            attachment_id as attachment_id_field, deleted_at as deleted_at_field,
+            messages as messages_table, recipient_id as recipient_id_field,
+            room_id as room_id_field, user_id as user_id_field,
+        };
+
+        let room_ids = self.get_user_room_ids(user_id)?;
+
+        let mut conn = self.pool.get().map_err(|_| DBError::ConnectionError)?;
+        let count: i64 = messages_table
+            .filter(attachment_id_field.eq(attachment_id))
+            .filter(deleted_at_field.is_null())
+            .filter(
+                room_id_field
+                    .eq_any(room_ids)
+                    .or(user_id_field.eq(user_id))
+                    .or(recipient_id_field.eq(user_id)),
+            )
+            .count()
+            .get_result(&mut conn)
+            .map_err(|_| DBError::AttachmentNotFoundError)?;
+
+        Ok(count > 0)
+    }
+
     /// Get the info of a user with the given id
     pub fn get_user(&self, user_id: i32) -> Result<User, DBError> {
         use schema::users::dsl::{id as id_field, users as users_table};
@@ -127,22 +993,905 @@ impl DB {
         Ok(user)
     }
 
-    /// Get the history of messages
+    /// Get the info of the users with the given ids, ordered by username
+    pub fn get_users(&self, user_ids: &[i32]) -> Result<Vec<User>, DBError> {
+        use schema::users::dsl::{
+            id as id_field, username as username_field, users as users_table,
+        };
+
+        let mut conn = self.pool.get().map_err(|_| DBError::ConnectionError)?;
+        let users = users_table
+            .filter(id_field.eq_any(user_ids))
+            .order(username_field.asc())
+            .load(&mut conn)
+            .map_err(|_| DBError::UserNotFoundError)?;
+
+        Ok(users)
+    }
+
+    /// Get the users with the given usernames, unknown usernames are skipped
+    pub fn get_users_by_username(&self, usernames: &[String]) -> Result<Vec<User>, DBError> {
+        use schema::users::dsl::{username as username_field, users as users_table};
+
+        let mut conn = self.pool.get().map_err(|_| DBError::ConnectionError)?;
+        let users = users_table
+            .filter(username_field.eq_any(usernames))
+            .load(&mut conn)
+            .map_err(|_| DBError::UserNotFoundError)?;
+
+        Ok(users)
+    }
+
+    /// Get a page of the history of messages in a room, or of the direct conversation between two users
+    ///
+    /// Returns the messages (oldest first) and whether there are older messages left to read
     ///
     /// # Arguments
+    /// * `user_id` - The user reading the history
+    /// * `target` - The room or the other user of the conversation
     /// * `amount` - The number of messages to read
-    pub fn read_history(&self, amount: i32, offset: i32) -> Result<Vec<Message>> {
-        use schema::messages::dsl::{id as id_field, messages as messages_table};
+    /// * `before_id` - Only read messages older than this message, i.e. the cursor of the next page
+    /// * `before` - Only read messages sent before this time (UTC)
+    /// * `after` - Only read messages sent after this time (UTC)
+    pub fn read_history(
+        &self,
+        user_id: i32,
+        target: &MessageTarget,
+        amount: i32,
+        before_id: Option<i32>,
+        before: Option<NaiveDateTime>,
+        after: Option<NaiveDateTime>,
+    ) -> Result<(Vec<Message>, bool)> {
+        use schema::messages::dsl::{
+            created_at as created_at_field, id as id_field, messages as messages_table,
+            recipient_id as recipient_id_field, room_id as room_id_field, user_id as user_id_field,
+        };
+
+        let mut query = match *target {
+            MessageTarget::Room(room_id) => messages_table
+                .filter(room_id_field.eq(room_id))
+                .into_boxed(),
+            MessageTarget::User(other_id) => messages_table
+                .filter(
+                    user_id_field
+                        .eq(user_id)
+                        .and(recipient_id_field.eq(other_id))
+                        .or(user_id_field
+                            .eq(other_id)
+                            .and(recipient_id_field.eq(user_id))),
+                )
+                .into_boxed(),
+        };
+        if let Some(before_id) = before_id {
+            query = query.filter(id_field.lt(before_id));
+        }
+        if let Some(before) = before {
+            query = query.filter(created_at_field.lt(before));
+        }
+        if let Some(after) = after {
+            query = query.filter(created_at_field.gt(after));
+        }
 
         let mut conn = self.pool.get().map_err(|_| DBError::ConnectionError)?;
-        let messages: Vec<Message> = messages_table
+        let amount = amount.max(0) as usize;
+        // Load one message more than requested to find out whether there is another page
+        let mut messages: Vec<Message> = query
             .order(id_field.desc())
-            .offset(offset as i64)
-            .limit(amount as i64)
+            .limit(amount as i64 + 1)
             .load(&mut conn)
-            .map_err(|_| DBError::MessageHistoryError)?.iter().rev().cloned().collect();
+            .map_err(|_| DBError::MessageHistoryError)?;
+
+        let has_more = messages.len() > amount;
+        messages.truncate(amount);
+        messages.reverse();
 
+        Ok((messages, has_more))
+    }
+
+    /// Get a page of the replies in the thread started by the message
+    ///
+    /// Returns the replies (oldest first) and whether there are newer replies left to read
+    ///
+    /// # Arguments
+    /// * `thread_id` - The message starting the thread
+    /// * `amount` - The number of replies to read
+    /// * `after_id` - Only read replies newer than this message, i.e. the cursor of the next page
+    pub fn read_thread(
+        &self,
+        thread_id: i32,
+        amount: i32,
+        after_id: Option<i32>,
+    ) -> Result<(Vec<Message>, bool), DBError> {
+        use schema::messages::dsl::{
+            id as id_field, messages as messages_table, thread_id as thread_id_field,
+        };
+
+        let mut query = messages_table
+            .filter(thread_id_field.eq(thread_id))
+            .into_boxed();
+        if let Some(after_id) = after_id {
+            query = query.filter(id_field.gt(after_id));
+        }
+
+        let mut conn = self.pool.get().map_err(|_| DBError::ConnectionError)?;
+        let amount = amount.max(0) as usize;
+        // Load one reply more than requested to find out whether there is another page
+        let mut replies: Vec<Message> = query
+            .order(id_field.asc())
+            .limit(amount as i64 + 1)
+            .load(&mut conn)
+            .map_err(|_| DBError::MessageHistoryError)?;
+
+        let has_more = replies.len() > amount;
+        replies.truncate(amount);
+
+        Ok((replies, has_more))
+    }
+
+    /// Create a new room with the given name and make the owner its first member
+    pub fn create_room(&self, name: String, owner_id: i32) -> Result<Room> {
+        use schema::rooms::dsl::rooms as rooms_table;
+
+        let new_room = ToBeInsertedRoom::new(name);
+
+        let mut conn = self.pool.get().map_err(|_| DBError::ConnectionError)?;
+        let room: Room = diesel::insert_into(rooms_table)
+            .values(&new_room)
+            .get_result(&mut conn)
+            .map_err(|_| DBError::RoomInsertionError)?;
+
+        self.join_room(room.id.unwrap(), owner_id)?;
+
+        Ok(room)
+    }
+
+    /// Get the room with the given id
+    pub fn get_room(&self, room_id: i32) -> Result<Room, DBError> {
+        use schema::rooms::dsl::{id as id_field, rooms as rooms_table};
+
+        let mut conn = self.pool.get().map_err(|_| DBError::ConnectionError)?;
+        let room = rooms_table
+            .filter(id_field.eq(room_id))
+            .first(&mut conn)
+            .map_err(|_| DBError::RoomNotFoundError)?;
+
+        Ok(room)
+    }
+
+    /// Get all rooms, ordered by id
+    pub fn list_rooms(&self) -> Result<Vec<Room>, DBError> {
+        use schema::rooms::dsl::{id as id_field, rooms as rooms_table};
+
+        let mut conn = self.pool.get().map_err(|_| DBError::ConnectionError)?;
+        let rooms = rooms_table
+            .order(id_field.asc())
+            .load(&mut conn)
+            .map_err(|_| DBError::RoomNotFoundError)?;
+
+        Ok(rooms)
+    }
+
+    /// Add the user to the room, joining a room twice is a no-op
+    pub fn join_room(&self, room_id: i32, user_id: i32) -> Result<Room, DBError> {
+        use schema::room_members::dsl::room_members as room_members_table;
+
+        let room = self.get_room(room_id)?;
+
+        let mut conn = self.pool.get().map_err(|_| DBError::ConnectionError)?;
+        diesel::insert_or_ignore_into(room_members_table)
+            .values(&ToBeInsertedRoomMember::new(room_id, user_id))
+            .execute(&mut conn)
+            .map_err(|_| DBError::RoomMembershipError)?;
+
+        Ok(room)
+    }
+
+    /// Remove the user from the room
+    pub fn leave_room(&self, room_id: i32, user_id: i32) -> Result<Room, DBError> {
+        use schema::room_members::dsl::{
+            room_id as room_id_field, room_members as room_members_table, user_id as user_id_field,
+        };
+
+        let room = self.get_room(room_id)?;
+
+        let mut conn = self.pool.get().map_err(|_| DBError::ConnectionError)?;
+        diesel::delete(
+            room_members_table
+                .filter(room_id_field.eq(room_id))
+                .filter(user_id_field.eq(user_id)),
+        )
+        .execute(&mut conn)
+        .map_err(|_| DBError::RoomMembershipError)?;
+
+        Ok(room)
+    }
+
+    /// Check whether the user is a member of the room
+    pub fn is_room_member(&self, room_id: i32, user_id: i32) -> Result<bool, DBError> {
+        use schema::room_members::dsl::{
+            room_id as room_id_field, room_members as room_members_table, user_id as user_id_field,
+        };
+
+        let mut conn = self.pool.get().map_err(|_| DBError::ConnectionError)?;
+        let count: i64 = room_members_table
+            .filter(room_id_field.eq(room_id))
+            .filter(user_id_field.eq(user_id))
+            .count()
+            .get_result(&mut conn)
+            .map_err(|_| DBError::RoomMembershipError)?;
+
+        Ok(count > 0)
+    }
+
+    /// Get the ids of all rooms the user is a member of
+    pub fn get_user_room_ids(&self, user_id: i32) -> Result<Vec<i32>, DBError> {
+        use schema::room_members::dsl::{
+            room_id as room_id_field, room_members as room_members_table, user_id as user_id_field,
+        };
+
+        let mut conn = self.pool.get().map_err(|_| DBError::ConnectionError)?;
+        let room_ids = room_members_table
+            .filter(user_id_field.eq(user_id))
+            .select(room_id_field)
+            .load(&mut conn)
+            .map_err(|_| DBError::RoomMembershipError)?;
+
+        Ok(room_ids)
+    }
+
+    /// Get the ids of all members of the room
+    pub fn get_room_member_ids(&self, room_id: i32) -> Result<Vec<i32>, DBError> {
+        use schema::room_members::dsl::{
+            room_id as room_id_field, room_members as room_members_table, user_id as user_id_field,
+        };
+
+        let mut conn = self.pool.get().map_err(|_| DBError::ConnectionError)?;
+        let user_ids = room_members_table
+            .filter(room_id_field.eq(room_id))
+            .select(user_id_field)
+            .load(&mut conn)
+            .map_err(|_| DBError::RoomMembershipError)?;
+
+        Ok(user_ids)
+    }
+
+    /// Get the read receipt of the user in the room or the direct conversation
+    pub fn get_read_receipt(
+        &self,
+        user_id: i32,
+        target: &MessageTarget,
+    ) -> Result<Option<ReadReceipt>, DBError> {
+        use schema::read_receipts::dsl::{
+            peer_id as peer_id_field, read_receipts as read_receipts_table,
+            room_id as room_id_field, user_id as user_id_field,
+        };
+
+        let query = read_receipts_table
+            .filter(user_id_field.eq(user_id))
+            .into_boxed();
+        let query = match *target {
+            MessageTarget::Room(room_id) => query.filter(room_id_field.eq(room_id)),
+            MessageTarget::User(peer_id) => query.filter(peer_id_field.eq(peer_id)),
+        };
+
+        let mut conn = self.pool.get().map_err(|_| DBError::ConnectionError)?;
+        let read_receipt = query
+            .first(&mut conn)
+            .optional()
+            .map_err(|_| DBError::MessageHistoryError)?;
+
+        Ok(read_receipt)
+    }
+
+    /// Move the read receipt of the user in the room or the direct conversation up to the message
+    ///
+    /// Returns whether it moved, a receipt never moves back to an older message
+    ///
+    /// # Arguments
+    /// * `user_id` - The user who has read the message
+    /// * `target` - The room or the other user of the conversation
+    /// * `message_id` - The last message the user has read
+    pub fn mark_read(
+        &self,
+        user_id: i32,
+        target: &MessageTarget,
+        message_id: i32,
+    ) -> Result<bool, DBError> {
+        let (room_id, peer_id, conflict_target) = match *target {
+            MessageTarget::Room(room_id) => (Some(room_id), None, "room_id"),
+            MessageTarget::User(peer_id) => (None, Some(peer_id), "peer_id"),
+        };
+
+        let mut conn = self.pool.get().map_err(|_| DBError::ConnectionError)?;
+        // The conflict target names one of the partial unique indexes of the receipts
+        let updated = diesel::sql_query(format!(
+            "INSERT INTO read_receipts (user_id, room_id, peer_id, message_id, read_at)
+            VALUES (?, ?, ?, ?, ?)
+            ON CONFLICT (user_id, {conflict_target}) WHERE {conflict_target} IS NOT NULL
+            DO UPDATE SET message_id = excluded.message_id, read_at = excluded.read_at
+                WHERE excluded.message_id > read_receipts.message_id"
+        ))
+        .bind::<Integer, _>(user_id)
+        .bind::<Nullable<Integer>, _>(room_id)
+        .bind::<Nullable<Integer>, _>(peer_id)
+        .bind::<Integer, _>(message_id)
+        .bind::<Timestamp, _>(Utc::now().naive_utc())
+        .execute(&mut conn)
+        .map_err(|_| DBError::ReadReceiptInsertionError)?;
+
+        Ok(updated > 0)
+    }
+
+    /// Count the messages of other users the user hasn't read yet
+    ///
+    /// Returns the rooms of the user and the direct conversations with unread messages, with
+    /// their counts
+    pub fn get_unread_counts(&self, user_id: i32) -> Result<Vec<(MessageTarget, i64)>, DBError> {
+        let mut conn = self.pool.get().map_err(|_| DBError::ConnectionError)?;
+        // Direct messages to the user are grouped by their author, the other user of the
+        // conversation, and room messages by their room
+        let counts: Vec<UnreadCount> = diesel::sql_query(
+            "SELECT messages.room_id AS room_id,
+                CASE WHEN messages.room_id IS NULL THEN messages.user_id END AS peer_id,
+                COUNT(*) AS unread
+            FROM messages LEFT JOIN read_receipts
+                ON read_receipts.user_id = ?
+                AND (read_receipts.room_id = messages.room_id
+                    OR (messages.room_id IS NULL AND read_receipts.peer_id = messages.user_id))
+            WHERE messages.user_id != ?
+                AND messages.deleted_at IS NULL
+                AND messages.id > COALESCE(read_receipts.message_id, 0)
+                AND (messages.room_id IN (SELECT room_id FROM room_members WHERE user_id = ?)
+                    OR messages.recipient_id = ?)
+            GROUP BY messages.room_id, peer_id
+            ORDER BY messages.room_id IS NULL, messages.room_id, peer_id",
+        )
+        .bind::<Integer, _>(user_id)
+        .bind::<Integer, _>(user_id)
+        .bind::<Integer, _>(user_id)
+        .bind::<Integer, _>(user_id)
+        .load(&mut conn)
+        .map_err(|_| DBError::MessageHistoryError)?;
+
+        let unread_counts = counts
+            .into_iter()
+            .filter_map(|count| {
+                let target = match (count.room_id, count.peer_id) {
+                    (Some(room_id), _) => MessageTarget::Room(room_id),
+                    (None, Some(peer_id)) => MessageTarget::User(peer_id),
+                    (None, None) => return None,
+                };
+                Some((target, count.unread))
+            })
+            .collect();
+
+        Ok(unread_counts)
+    }
+
+    /// Add the reaction of the user to the message
+    ///
+    /// Returns whether it was added, i.e. the user hasn't reacted with the emoji yet
+    ///
+    /// # Arguments
+    /// * `message_id` - The message to react to
+    /// * `user_id` - The user who reacts
+    /// * `emoji` - The emoji to react with
+    pub fn add_reaction(
+        &self,
+        message_id: i32,
+        user_id: i32,
+        emoji: &str,
+    ) -> Result<bool, DBError> {
+        use schema::reactions::dsl::reactions as reactions_table;
+
+        let new_reaction = ToBeInsertedReaction::new(
+            message_id,
+            user_id,
+            emoji.to_string(),
+            Utc::now().naive_utc(),
+        );
+
+        let mut conn = self.pool.get().map_err(|_| DBError::ConnectionError)?;
+        let inserted = diesel::insert_or_ignore_into(reactions_table)
+            .values(&new_reaction)
+            .execute(&mut conn)
+            .map_err(|_| DBError::ReactionError)?;
+
+        Ok(inserted > 0)
+    }
+
+    /// Remove the reaction of the user from the message
+    ///
+    /// Returns whether it was removed, i.e. the user has reacted with the emoji before
+    ///
+    /// # Arguments
+    /// * `message_id` - The message the reaction belongs to
+    /// * `user_id` - The user who reacted
+    /// * `emoji` - The emoji of the reaction
+    pub fn remove_reaction(
+        &self,
+        message_id: i32,
+        user_id: i32,
+        emoji: &str,
+    ) -> Result<bool, DBError> {
+        use schema::reactions::dsl::{
+            emoji as emoji_field, message_id as message_id_field, reactions as reactions_table,
+            user_id as user_id_field,
+        };
+
+        let mut conn = self.pool.get().map_err(|_| DBError::ConnectionError)?;
+        let deleted = diesel::delete(
+            reactions_table
+                .filter(message_id_field.eq(message_id))
+                .filter(user_id_field.eq(user_id))
+                .filter(emoji_field.eq(emoji)),
+        )
+        .execute(&mut conn)
+        .map_err(|_| DBError::ReactionError)?;
+
+        Ok(deleted > 0)
+    }
+
+    /// Get all reactions to the message, oldest first
+    pub fn get_reactions(&self, message_id: i32) -> Result<Vec<Reaction>, DBError> {
+        use schema::reactions::dsl::{
+            id as id_field, message_id as message_id_field, reactions as reactions_table,
+        };
+
+        let mut conn = self.pool.get().map_err(|_| DBError::ConnectionError)?;
+        reactions_table
+            .filter(message_id_field.eq(message_id))
+            .order(id_field.asc())
+            .load(&mut conn)
+            .map_err(|_| DBError::ReactionError)
+    }
+
+    /// Get the reactions to the messages by message, each in the order they were added
+    pub fn get_reactions_by_message(
+        &self,
+        message_ids: &[i32],
+    ) -> Result<HashMap<i32, Vec<Reaction>>, DBError> {
+        use schema::reactions::dsl::{
+            id as id_field, message_id as message_id_field, reactions as reactions_table,
+        };
+
+        let mut conn = self.pool.get().map_err(|_| DBError::ConnectionError)?;
+        let reactions: Vec<Reaction> = reactions_table
+            .filter(message_id_field.eq_any(message_ids))
+            .order(id_field.asc())
+            .load(&mut conn)
+            .map_err(|_| DBError::ReactionError)?;
+
+        let mut by_message: HashMap<i32, Vec<Reaction>> = HashMap::new();
+        for reaction in reactions {
+            by_message
+                .entry(reaction.message_id)
+                .or_default()
+                .push(reaction);
+        }
+        Ok(by_message)
+    }
+
+    /// Record that the message mentions the users
+    ///
+    /// Returns the users who weren't mentioned by the message before, e.g. when it's edited
+    ///
+    /// # Arguments
+    /// * `message_id` - The message mentioning the users
+    /// * `user_ids` - The mentioned users
+    pub fn add_mentions(&self, message_id: i32, user_ids: &[i32]) -> Result<Vec<i32>, DBError> {
+        use schema::mentions::dsl::mentions as mentions_table;
+
+        let mut conn = self.pool.get().map_err(|_| DBError::ConnectionError)?;
+        let now = Utc::now().naive_utc();
+        let mut mentioned = vec![];
+        for &user_id in user_ids {
+            let inserted = diesel::insert_or_ignore_into(mentions_table)
+                .values(&ToBeInsertedMention::new(message_id, user_id, now))
+                .execute(&mut conn)
+                .map_err(|_| DBError::MentionInsertionError)?;
+            if inserted > 0 {
+                mentioned.push(user_id);
+            }
+        }
+
+        Ok(mentioned)
+    }
+
+    /// Get a page of the messages mentioning the user, deleted messages are skipped
+    ///
+    /// Returns the messages (oldest first) and whether there are older mentions left to read
+    ///
+    /// # Arguments
+    /// * `user_id` - The mentioned user
+    /// * `amount` - The number of messages to read
+    /// * `before_id` - Only read messages older than this one, i.e. the cursor of the next page
+    pub fn get_mentions(
+        &self,
+        user_id: i32,
+        amount: i32,
+        before_id: Option<i32>,
+    ) -> Result<(Vec<Message>, bool), DBError> {
+        use schema::mentions::dsl::{mentions as mentions_table, user_id as user_id_field};
+        use schema::messages::dsl::{deleted_at as deleted_at_field, id as id_field};
+
+        let mut query = mentions_table
+            .inner_join(messages_schema::table)
+            .filter(user_id_field.eq(user_id))
+            .filter(deleted_at_field.is_null())
+            .select(messages_schema::all_columns)
+            .into_boxed();
+        if let Some(before_id) = before_id {
+            query = query.filter(id_field.lt(before_id));
+        }
+
+        let mut conn = self.pool.get().map_err(|_| DBError::ConnectionError)?;
+        let amount = amount.max(0) as usize;
+        // Load one message more than requested to find out whether there is another page
+        let mut messages: Vec<Message> = query
+            .order(id_field.desc())
+            .limit(amount as i64 + 1)
+            .load(&mut conn)
+            .map_err(|_| DBError::MessageHistoryError)?;
+
+        let has_more = messages.len() > amount;
+        messages.truncate(amount);
+        messages.reverse();
+
+        Ok((messages, has_more))
+    }
+
+    /// Search the messages the user can see, the best matches first
+    ///
+    /// Returns the messages with a snippet of their text, matched terms are enclosed in
+    /// `HIGHLIGHT_START` and `HIGHLIGHT_END`, and whether there are more results left to read
+    ///
+    /// # Arguments
+    /// * `user_id` - The user searching, only their rooms and direct conversations are searched
+    /// * `query` - The words to search for, the last one may be a prefix of a word
+    /// * `author_id` - Only search the messages of this user
+    /// * `amount` - The number of results to read
+    /// * `offset` - The number of results to skip
+    pub fn search_messages(
+        &self,
+        user_id: i32,
+        query: &str,
+        author_id: Option<i32>,
+        amount: i32,
+        offset: i32,
+    ) -> Result<(Vec<(Message, String)>, bool), DBError> {
+        let Some(match_query) = fts_query(query) else {
+            return Ok((vec![], false));
+        };
+
+        let mut conn = self.pool.get().map_err(|_| DBError::ConnectionError)?;
+        let amount = amount.max(0) as usize;
+        // Load one result more than requested to find out whether there is another page
+        let mut hits: Vec<SearchHit> = diesel::sql_query(
+            "SELECT messages.id AS id,
+                snippet(messages_fts, 0, ?, ?, '…', ?) AS snippet
+            FROM messages_fts JOIN messages ON messages.id = messages_fts.rowid
+            WHERE messages_fts MATCH ?
+                AND messages.deleted_at IS NULL
+                AND (messages.room_id IN (SELECT room_id FROM room_members WHERE user_id = ?)
+                    OR (messages.recipient_id IS NOT NULL AND messages.user_id = ?)
+                    OR messages.recipient_id = ?)
+                AND (? IS NULL OR messages.user_id = ?)
+            ORDER BY rank
+            LIMIT ? OFFSET ?",
+        )
+        .bind::<Text, _>(HIGHLIGHT_START.to_string())
+        .bind::<Text, _>(HIGHLIGHT_END.to_string())
+        .bind::<Integer, _>(SNIPPET_TOKENS)
+        .bind::<Text, _>(match_query)
+        .bind::<Integer, _>(user_id)
+        .bind::<Integer, _>(user_id)
+        .bind::<Integer, _>(user_id)
+        .bind::<Nullable<Integer>, _>(author_id)
+        .bind::<Nullable<Integer>, _>(author_id)
+        .bind::<Integer, _>(amount as i32 + 1)
+        .bind::<Integer, _>(offset.max(0))
+        .load(&mut conn)
+        .map_err(|_| DBError::MessageHistoryError)?;
+
+        let has_more = hits.len() > amount;
+        hits.truncate(amount);
+
+        let hit_ids: Vec<i32> = hits.iter().map(|hit| hit.id).collect();
+        let mut messages = self.get_messages(&hit_ids)?;
+        let results = hits
+            .into_iter()
+            .map(|hit| {
+                let message = messages
+                    .remove(&hit.id)
+                    .ok_or(DBError::MessageNotFoundError)?;
+                Ok((message, hit.snippet))
+            })
+            .collect::<Result<Vec<(Message, String)>, DBError>>()?;
+
+        Ok((results, has_more))
+    }
+}
+
+/// Hashes the password with a new random salt, returns the hash and the salt
+fn hash_password(password: String) -> (String, Vec<u8>) {
+    let mut salt = [0u8; 16];
+    rand::thread_rng().fill_bytes(&mut salt);
+
+    let hashed_password = hash_with_salt(password, DEFAULT_COST, salt).unwrap();
+    (hashed_password.to_string(), salt.to_vec())
+}
+
+/// Cleans up after deleted messages: replies lose their quote (and thread) when the message is
+/// gone, and its reactions and mentions are deleted
+fn remove_dangling_references(conn: &mut SqliteConnection) -> QueryResult<()> {
+    // Diesel can't filter an update by a subselect of the same table
+    for statement in [
+        "UPDATE messages SET parent_id = NULL WHERE parent_id NOT IN (SELECT id FROM messages)",
+        "UPDATE messages SET thread_id = NULL WHERE thread_id NOT IN (SELECT id FROM messages)",
+        "DELETE FROM reactions WHERE message_id NOT IN (SELECT id FROM messages)",
+        "DELETE FROM mentions WHERE message_id NOT IN (SELECT id FROM messages)",
+    ] {
+        diesel::sql_query(statement).execute(conn)?;
+    }
+
+    Ok(())
+}
+
+/// What the responses of a page of messages need, see `DB::get_message_details`
+///
+/// # Fields
+/// * `usernames` - The usernames of the authors of the messages and of their parents, by id
+/// * `parents` - The messages replied to, by id
+/// * `reactions` - The reactions to the messages by message, in the order they were added
+pub struct MessageDetails {
+    pub usernames: HashMap<i32, String>,
+    pub parents: HashMap<i32, Message>,
+    pub reactions: HashMap<i32, Vec<Reaction>>,
+}
+
+/// A message found by `DB::search_messages`
+#[derive(QueryableByName)]
+struct SearchHit {
+    #[diesel(sql_type = Integer)]
+    id: i32,
+    #[diesel(sql_type = Text)]
+    snippet: String,
+}
+
+/// The unread messages of a conversation counted by `DB::get_unread_counts`
+#[derive(QueryableByName)]
+struct UnreadCount {
+    #[diesel(sql_type = Nullable<Integer>)]
+    room_id: Option<i32>,
+    #[diesel(sql_type = Nullable<Integer>)]
+    peer_id: Option<i32>,
+    #[diesel(sql_type = BigInt)]
+    unread: i64,
+}
+
+/// Turns the words of a search into an FTS5 query matching messages containing all of them
+///
+/// Every word is quoted, so the FTS5 syntax can't be used (or misused) in searches, and the last
+/// one matches as a prefix, so results show up while the last word is still being typed.
+/// Returns `None` if there are no words to search for.
+fn fts_query(query: &str) -> Option<String> {
+    let terms: Vec<String> = query
+        .split_whitespace()
+        .map(|word| format!("\"{}\"", word.replace('"', "\"\"")))
+        .collect();
+    if terms.is_empty() {
+        return None;
+    }
+
+    Some(terms.join(" ") + "*")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{env, fs};
+
+    /// Opens a new migrated database for a test, named after the test so they can run in
+    /// parallel
+    fn test_db(name: &str) -> (DB, String) {
+        let path = env::temp_dir().join(format!("chat-db-{}-{}.db", name, std::process::id()));
+        let _ = fs::remove_file(&path);
+        let path = path.to_string_lossy().into_owned();
+        (DB::open(&path).unwrap(), path)
+    }
+
+    #[test]
+    fn created_users_join_the_general_room() {
+        let (db, path) = test_db("create-user");
+        let user = db.create_user("bob".to_string(), "pw".to_string());
+        let room_ids = user
+            .as_ref()
+            .map(|user| db.get_user_room_ids(user.id.unwrap()));
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(room_ids.unwrap().unwrap(), vec![GENERAL_ROOM_ID]);
+    }
+
+    #[test]
+    fn failed_join_rolls_the_user_back() {
+        let (db, path) = test_db("create-user-rollback");
+        let mut conn = db.pool.get().unwrap();
+        diesel::sql_query("DELETE FROM rooms")
+            .execute(&mut conn)
+            .unwrap();
+        let failed = db.create_user("bob".to_string(), "pw".to_string());
+        let user_id = db.get_user_id("bob");
+        drop(conn);
+        fs::remove_file(&path).unwrap();
+
+        assert!(matches!(
+            failed.unwrap_err().downcast::<DBError>(),
+            Ok(DBError::RoomNotFoundError)
+        ));
+        assert!(user_id.is_err());
+    }
+
+    #[test]
+    fn message_details_cover_the_whole_page() {
+        let (db, path) = test_db("message-details");
+        let details = (|| {
+            let alice = db.create_user("alice".to_string(), "pw".to_string())?;
+            let bob = db.create_user("bob".to_string(), "pw".to_string())?;
+            let (alice, bob) = (alice.id.unwrap(), bob.id.unwrap());
+            let general = MessageTarget::Room(GENERAL_ROOM_ID);
+            let text = |text: &str| MessageContent::Text(text.to_string());
+
+            let question = db.save_message(alice, &general, text("?"), None)?;
+            let answer = db.save_message(bob, &general, text("!"), Some(&question))?;
+            let other = db.save_message(bob, &general, text("."), None)?;
+            db.add_reaction(answer.id.unwrap(), alice, "👍")?;
+            db.add_reaction(answer.id.unwrap(), bob, "🎉")?;
+            db.add_reaction(other.id.unwrap(), alice, "👍")?;
+
+            let details = db.get_message_details(std::slice::from_ref(&answer))?;
+            anyhow::Ok((details, alice, bob, question, answer))
+        })();
+        fs::remove_file(&path).unwrap();
+
+        let (details, alice, bob, question, answer) = details.unwrap();
+        assert_eq!(details.usernames.len(), 2);
+        assert_eq!(details.usernames[&alice], "alice");
+        assert_eq!(details.usernames[&bob], "bob");
+        assert_eq!(
+            details.parents.keys().collect::<Vec<_>>(),
+            [&question.id.unwrap()]
+        );
+        // Only the reactions to the messages of the page, in the order they were added
+        assert_eq!(details.reactions.len(), 1);
+        let emoji: Vec<&str> = details.reactions[&answer.id.unwrap()]
+            .iter()
+            .map(|reaction| reaction.emoji.as_str())
+            .collect();
+        assert_eq!(emoji, ["👍", "🎉"]);
+    }
+
+    #[test]
+    fn deleting_messages_removes_the_files_only_they_carried() {
+        let (db, path) = test_db("unreferenced-attachments");
+        let root = env::temp_dir().join(format!("chat-store-{}", std::process::id()));
+        let store = AttachmentStore::new(&root);
+        let result = (|| {
+            let alice = db
+                .create_user("alice".to_string(), "pw".to_string())?
+                .id
+                .unwrap();
+            let bob = db
+                .create_user("bob".to_string(), "pw".to_string())?
+                .id
+                .unwrap();
+            let general = MessageTarget::Room(GENERAL_ROOM_ID);
+            let send = |user_id: i32, bytes: &[u8]| {
+                let sha256 = store.save(bytes)?;
+                let attachment =
+                    db.create_attachment(sha256.clone(), 1, "text/plain".to_string(), "a".into())?;
+                let info = AttachmentInfo::from_db_attachment(&attachment);
+                db.save_message(user_id, &general, MessageContent::Attachment(info), None)?;
+                anyhow::Ok(sha256)
+            };
+            let shared = send(alice, b"shared")?;
+            send(bob, b"shared")?;
+            let own = send(alice, b"own")?;
+
+            db.delete_user(alice, &store)?;
+            let after_delete = (store.path(&shared).exists(), store.path(&own).exists());
+            db.purge_messages(Utc::now().naive_utc() + TimeDelta::days(1), &store)?;
+            let after_purge = store.path(&shared).exists();
+            anyhow::Ok((after_delete, after_purge))
+        })();
+        fs::remove_file(&path).unwrap();
+        let _ = fs::remove_dir_all(&root);
+
+        let (after_delete, after_purge) = result.unwrap();
+        assert_eq!(after_delete, (true, false));
+        assert!(!after_purge);
+    }
+
+    #[test]
+    fn stale_and_leftover_uploads_are_removed() {
+        let (db, path) = test_db("stale-uploads");
+        let root = env::temp_dir().join(format!("chat-uploads-{}", std::process::id()));
+        let store = AttachmentStore::new(&root);
+        let result = (|| {
+            let bob = db
+                .create_user("bob".to_string(), "pw".to_string())?
+                .id
+                .unwrap();
+            let fresh = db.begin_upload(bob, "fresh".to_string(), 1)?.id.unwrap();
+            let stale = db.begin_upload(bob, "stale".to_string(), 1)?.id.unwrap();
+            for upload_id in [fresh, stale, 999] {
+                store.write_chunk(upload_id, 0, b"a")?;
+            }
+            let mut conn = db.pool.get()?;
+            diesel::sql_query("UPDATE uploads SET created_at = '2000-01-01 00:00:00' WHERE id = ?")
+                .bind::<Integer, _>(stale)
+                .execute(&mut conn)?;
+
+            let removed = db.remove_stale_uploads(&store, 60)?;
+            let mut left = store.partial_upload_ids()?;
+            left.sort();
+            anyhow::Ok((removed, left, fresh, db.get_upload(stale, bob).is_err()))
+        })();
+        fs::remove_file(&path).unwrap();
+        let _ = fs::remove_dir_all(&root);
+
+        let (removed, left, fresh, stale_gone) = result.unwrap();
+        assert_eq!(removed, 2);
+        assert_eq!(left, [fresh]);
+        assert!(stale_gone);
+    }
+
+    #[test]
+    fn fts_query_quotes_every_word_and_prefixes_the_last() {
+        assert_eq!(
+            fts_query("hello  world").as_deref(),
+            Some("\"hello\" \"world\"*")
+        );
+        assert_eq!(fts_query(" \t\n"), None);
+    }
+
+    #[test]
+    fn fts_query_escapes_quotes_and_operators() {
+        assert_eq!(
+            fts_query("say \"hi\" OR NOT x*").as_deref(),
+            Some("\"say\" \"\"\"hi\"\"\" \"OR\" \"NOT\" \"x*\"*")
+        );
+    }
+
+    #[test]
+    fn fts_query_is_valid_fts5_syntax() {
+        #[derive(QueryableByName)]
+        struct Hit {
+            #[diesel(sql_type = Integer)]
+            id: i32,
+        }
+
+        let mut conn = SqliteConnection::establish(":memory:").unwrap();
+        diesel::sql_query("CREATE VIRTUAL TABLE texts USING fts5 (body)")
+            .execute(&mut conn)
+            .unwrap();
+        diesel::sql_query(
+            "INSERT INTO texts (rowid, body) VALUES (1, 'say \"hi\" OR bye'), (2, 'hidden')",
+        )
+        .execute(&mut conn)
+        .unwrap();
 
-        Ok(messages)
+        let search = |query: &str, conn: &mut SqliteConnection| -> Vec<i32> {
+            diesel::sql_query("SELECT rowid AS id FROM texts WHERE texts MATCH ? ORDER BY rowid")
+                .bind::<Text, _>(fts_query(query).unwrap())
+                .load::<Hit>(conn)
+                .unwrap()
+                .into_iter()
+                .map(|hit| hit.id)
+                .collect()
+        };
+        assert_eq!(search("hi", &mut conn), vec![1, 2]);
+        assert_eq!(search("\"hi\" OR", &mut conn), vec![1]);
+        assert_eq!(search("NOT (", &mut conn), Vec::<i32>::new());
     }
 }
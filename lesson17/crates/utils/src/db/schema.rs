@@ -1,10 +1,96 @@
 // @generated automatically by Diesel CLI.
 
+diesel::table! {
+    attachments (id) {
+        id -> Nullable<Integer>,
+        sha256 -> Text,
+        size -> BigInt,
+        mime_type -> Text,
+        name -> Text,
+    }
+}
+
+diesel::table! {
+    mentions (id) {
+        id -> Nullable<Integer>,
+        message_id -> Integer,
+        user_id -> Integer,
+        created_at -> Timestamp,
+    }
+}
+
 diesel::table! {
     messages (id) {
         id -> Nullable<Integer>,
         user_id -> Integer,
         content -> Binary,
+        room_id -> Nullable<Integer>,
+        recipient_id -> Nullable<Integer>,
+        created_at -> Timestamp,
+        edited_at -> Nullable<Timestamp>,
+        deleted_at -> Nullable<Timestamp>,
+        attachment_id -> Nullable<Integer>,
+        parent_id -> Nullable<Integer>,
+        thread_id -> Nullable<Integer>,
+        search_text -> Nullable<Text>,
+    }
+}
+
+diesel::table! {
+    reactions (id) {
+        id -> Nullable<Integer>,
+        message_id -> Integer,
+        user_id -> Integer,
+        emoji -> Text,
+        created_at -> Timestamp,
+    }
+}
+
+diesel::table! {
+    read_receipts (id) {
+        id -> Nullable<Integer>,
+        user_id -> Integer,
+        room_id -> Nullable<Integer>,
+        peer_id -> Nullable<Integer>,
+        message_id -> Integer,
+        read_at -> Timestamp,
+    }
+}
+
+diesel::table! {
+    room_members (id) {
+        id -> Nullable<Integer>,
+        room_id -> Integer,
+        user_id -> Integer,
+    }
+}
+
+diesel::table! {
+    rooms (id) {
+        id -> Nullable<Integer>,
+        name -> Text,
+    }
+}
+
+diesel::table! {
+    sessions (id) {
+        id -> Nullable<Integer>,
+        user_id -> Integer,
+        refresh_token_hash -> Text,
+        created_at -> Timestamp,
+        expires_at -> Timestamp,
+        revoked_at -> Nullable<Timestamp>,
+    }
+}
+
+diesel::table! {
+    uploads (id) {
+        id -> Nullable<Integer>,
+        user_id -> Integer,
+        name -> Text,
+        size -> BigInt,
+        received -> BigInt,
+        created_at -> Timestamp,
     }
 }
 
@@ -14,7 +100,24 @@ diesel::table! {
         username -> Text,
         password -> Text,
         salt -> Binary,
+        role -> Text,
+        muted_until -> Nullable<Timestamp>,
+        banned_at -> Nullable<Timestamp>,
     }
 }
 
-diesel::allow_tables_to_appear_in_same_query!(messages, users,);
+diesel::joinable!(mentions -> messages (message_id));
+diesel::joinable!(messages -> attachments (attachment_id));
+
+diesel::allow_tables_to_appear_in_same_query!(
+    attachments,
+    mentions,
+    messages,
+    reactions,
+    read_receipts,
+    room_members,
+    rooms,
+    sessions,
+    uploads,
+    users,
+);
@@ -1,7 +1,11 @@
+use chrono::NaiveDateTime;
 use paste::paste;
 use serde::{Deserialize, Serialize};
 
-use crate::db::schema::{messages, users};
+use crate::db::schema::{
+    attachments, mentions, messages, reactions, read_receipts, room_members, rooms, sessions,
+    uploads, users,
+};
 use diesel::prelude::*;
 
 /// Generate structs representing the data objects to be inserted (without id) ToBeInserted{name}.
@@ -14,7 +18,7 @@ use diesel::prelude::*;
 ///
 /// # Example
 ///
-/// ```
+/// ```ignore
 /// diesel_struct!(
 ///     User,
 ///     users,
@@ -24,7 +28,7 @@ use diesel::prelude::*;
 /// );
 /// ```
 /// generates
-/// ```
+/// ```ignore
 /// #[derive(Queryable, Serialize, Deserialize, Debug, Clone)]
 /// #[diesel(table_name = users)]
 /// pub struct User {
@@ -65,6 +69,8 @@ macro_rules! diesel_struct {
             }
 
             impl [<ToBeInserted $name>] {
+                // Takes one argument per column, so wide tables go past clippy's limit
+                #[allow(clippy::too_many_arguments)]
                 pub fn new($($field: $type),*) -> Self {
                     [<ToBeInserted $name>] {$($field),*}
                 }
@@ -78,6 +84,75 @@ diesel_struct!(
     users,
     username: String,
     password: String,
-    salt: Vec<u8>
+    salt: Vec<u8>,
+    role: String,
+    muted_until: Option<NaiveDateTime>,
+    banned_at: Option<NaiveDateTime>
+);
+diesel_struct!(
+    Message,
+    messages,
+    user_id: i32,
+    content: Vec<u8>,
+    room_id: Option<i32>,
+    recipient_id: Option<i32>,
+    created_at: NaiveDateTime,
+    edited_at: Option<NaiveDateTime>,
+    deleted_at: Option<NaiveDateTime>,
+    attachment_id: Option<i32>,
+    parent_id: Option<i32>,
+    thread_id: Option<i32>,
+    search_text: Option<String>
+);
+diesel_struct!(
+    Mention,
+    mentions,
+    message_id: i32,
+    user_id: i32,
+    created_at: NaiveDateTime
+);
+diesel_struct!(Room, rooms, name: String);
+diesel_struct!(RoomMember, room_members, room_id: i32, user_id: i32);
+diesel_struct!(
+    Attachment,
+    attachments,
+    sha256: String,
+    size: i64,
+    mime_type: String,
+    name: String
+);
+diesel_struct!(
+    Upload,
+    uploads,
+    user_id: i32,
+    name: String,
+    size: i64,
+    received: i64,
+    created_at: NaiveDateTime
+);
+diesel_struct!(
+    Session,
+    sessions,
+    user_id: i32,
+    refresh_token_hash: String,
+    created_at: NaiveDateTime,
+    expires_at: NaiveDateTime,
+    revoked_at: Option<NaiveDateTime>
+);
+diesel_struct!(
+    ReadReceipt,
+    read_receipts,
+    user_id: i32,
+    room_id: Option<i32>,
+    peer_id: Option<i32>,
+    message_id: i32,
+    read_at: NaiveDateTime
+);
+diesel_struct!(
+    Reaction,
+    reactions,
+    message_id: i32,
+    user_id: i32,
+    emoji: String,
+    created_at: NaiveDateTime
 );
-diesel_struct!(Message, messages, user_id: i32, content: Vec<u8>);
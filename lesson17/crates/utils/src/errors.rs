@@ -1,3 +1,4 @@
+use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
 use std::io::Error;
 use thiserror::Error;
@@ -17,6 +18,9 @@ pub enum StreamError {
 
     #[error("Failed to read message")]
     ReadMessageError(Error),
+
+    #[error("Failed to read file")]
+    FileReadError(Error),
 }
 
 pub fn invalid_input_error(error: &'static str) -> Error {
@@ -26,11 +30,8 @@ pub fn invalid_input_error(error: &'static str) -> Error {
 pub fn handle_stream_error(e: StreamError) {
     eprintln!("{}", e);
 
-    match e {
-        StreamError::StreamClosed => {
-            std::process::exit(0x0100);
-        }
-        _ => (),
+    if let StreamError::StreamClosed = e {
+        std::process::exit(0x0100);
     }
 }
 
@@ -58,6 +59,51 @@ pub enum DBError {
     UserNotFoundError,
     #[error("Failed to verify password")]
     PasswordVerificationError,
+    #[error("Failed to create room, the name may be taken")]
+    RoomInsertionError,
+    #[error("Room not found")]
+    RoomNotFoundError,
+    #[error("Failed to update room membership")]
+    RoomMembershipError,
+    #[error("Failed to insert into attachments table")]
+    AttachmentInsertionError,
+    #[error("Attachment not found")]
+    AttachmentNotFoundError,
+    #[error("Failed to insert into uploads table")]
+    UploadInsertionError,
+    #[error("Upload not found")]
+    UploadNotFoundError,
+    #[error("Failed to insert into sessions table")]
+    SessionInsertionError,
+    #[error("Session not found")]
+    SessionNotFoundError,
+    #[error("Failed to insert into read_receipts table")]
+    ReadReceiptInsertionError,
+    #[error("Failed to update reactions")]
+    ReactionError,
+    #[error("Failed to insert into mentions table")]
+    MentionInsertionError,
+    #[error("Failed to update users table")]
+    UserUpdateError,
+    #[error("Failed to delete from users table")]
+    UserDeletionError,
+    #[error("Failed to delete from messages table")]
+    MessageDeletionError,
+    #[error("Failed to vacuum the database")]
+    VacuumError,
+    #[error("Failed to run the database migrations")]
+    MigrationError,
+    #[error("Failed to run the database transaction")]
+    TransactionError,
+    #[error("Failed to delete from attachments table")]
+    AttachmentDeletionError,
+}
+
+/// Failures beginning or committing a transaction, the statements inside it map their own errors
+impl From<diesel::result::Error> for DBError {
+    fn from(_: diesel::result::Error) -> Self {
+        DBError::TransactionError
+    }
 }
 
 impl DBError {
@@ -81,6 +127,46 @@ pub enum ServerError {
     InvalidCredentials,
     #[error("Username is used")]
     UsernameUsed,
+    #[error("You are not a member of this room")]
+    NotRoomMember,
+    #[error("Only the author can change this message")]
+    NotMessageAuthor,
+    #[error("Upload chunk doesn't continue the received bytes or has the wrong size")]
+    InvalidUploadChunk,
+    #[error("Upload hasn't received all of its bytes yet")]
+    UploadIncomplete,
+    #[error("Uploaded bytes don't match the checksum, the upload has been discarded")]
+    UploadChecksumMismatch,
+    #[error("The session has been revoked, log in again")]
+    SessionRevoked,
+    #[error("Invalid or expired refresh token, log in again")]
+    InvalidRefreshToken,
+    #[error("Log in first")]
+    NotAuthenticated,
+    #[error("The token has expired, refresh the session or log in again")]
+    TokenExpired,
+    #[error("A reaction must be a single short emoji")]
+    InvalidReaction,
+    #[error("Only moderators can do this")]
+    NotModerator,
+    #[error("You can only moderate users with a lower role")]
+    CannotModerate,
+    #[error("This account has been banned")]
+    UserBanned,
+    #[error("The file is larger than the server accepts")]
+    UploadTooLarge,
+    #[error("Something went wrong on the server")]
+    InternalError,
+    #[error("Attachments are sent as images, files or uploads")]
+    AttachmentNotAllowed,
+    #[error("Only the text of a message can be edited")]
+    TextOnlyEdit,
+    /// Holds when the mute ends
+    #[error("You are muted until {0}")]
+    UserMuted(DateTime<Utc>),
+    /// Holds how long (in seconds) to wait before trying again
+    #[error("Too many requests, try again in {0} seconds")]
+    RateLimited(u64),
 }
 
 impl ServerError {
@@ -101,7 +187,26 @@ create_enum_init_functions!(
     MessageNotFoundError,
     MessageHistoryError,
     UserNotFoundError,
-    PasswordVerificationError
+    PasswordVerificationError,
+    RoomInsertionError,
+    RoomNotFoundError,
+    RoomMembershipError,
+    AttachmentInsertionError,
+    AttachmentNotFoundError,
+    UploadInsertionError,
+    UploadNotFoundError,
+    SessionInsertionError,
+    SessionNotFoundError,
+    ReadReceiptInsertionError,
+    ReactionError,
+    MentionInsertionError,
+    UserUpdateError,
+    UserDeletionError,
+    MessageDeletionError,
+    VacuumError,
+    MigrationError,
+    TransactionError,
+    AttachmentDeletionError
 );
 create_enum_init_functions!(
     ServerError,
@@ -109,5 +214,22 @@ create_enum_init_functions!(
     SerializeObjectError,
     DeserializeObjectError,
     InvalidCredentials,
-    UsernameUsed
+    UsernameUsed,
+    NotRoomMember,
+    NotMessageAuthor,
+    InvalidUploadChunk,
+    UploadIncomplete,
+    UploadChecksumMismatch,
+    SessionRevoked,
+    InvalidRefreshToken,
+    NotAuthenticated,
+    TokenExpired,
+    InvalidReaction,
+    NotModerator,
+    CannotModerate,
+    UserBanned,
+    UploadTooLarge,
+    InternalError,
+    AttachmentNotAllowed,
+    TextOnlyEdit
 );
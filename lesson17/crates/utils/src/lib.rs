@@ -1,5 +1,6 @@
 mod utils;
 pub use utils::*;
-pub mod write_utils;
+pub mod attachments;
+pub mod db;
 pub mod errors;
-pub mod db;
\ No newline at end of file
+pub mod write_utils;
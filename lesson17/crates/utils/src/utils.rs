@@ -4,12 +4,14 @@ use std::{
     sync::Arc,
 };
 
-use crate::db::{structs::Message, DB};
+use crate::db::{
+    structs::{Attachment, Message, Reaction, Room, User},
+    MessageDetails, DB, HIGHLIGHT_END, HIGHLIGHT_START,
+};
 use anyhow::Result;
-use chrono::Local;
-use clap::{arg, command, Parser};
+use chrono::{DateTime, Local, Utc};
 
-use crate::errors::{deserialize_object_error, handle_stream_error, StreamError};
+use crate::errors::{deserialize_object_error, handle_stream_error, DBError, StreamError};
 
 mod structs;
 pub use structs::*;
@@ -18,36 +20,319 @@ impl ServerResponse {
     pub fn serialize(self) -> String {
         serialize_server_response(self).unwrap()
     }
+    pub fn serialize_bincode(self) -> Vec<u8> {
+        serialize_server_response_bincode(self).unwrap()
+    }
 }
 
+/// The amount of characters of the text quoted in replies
+static SNIPPET_LENGTH: usize = 100;
+
 impl MessageResponse {
     pub fn from_db_message(message: &Message, db: &Arc<DB>) -> Result<Self, ErrorResponse> {
-        let user = db.get_user(message.user_id).map_err(|e| db_error(e))?;
+        let mut responses = Self::from_db_messages(std::slice::from_ref(message), db)?;
+        Ok(responses.remove(0))
+    }
+    /// Builds the responses of a page of messages, loading their authors, parents and reactions
+    /// together
+    pub fn from_db_messages(
+        messages: &[Message],
+        db: &Arc<DB>,
+    ) -> Result<Vec<Self>, ErrorResponse> {
+        let details = db.get_message_details(messages).map_err(db_error)?;
+        messages
+            .iter()
+            .map(|message| Self::from_details(message, &details))
+            .collect()
+    }
+    fn from_details(message: &Message, details: &MessageDetails) -> Result<Self, ErrorResponse> {
         let content = deserialize_data(message.content.to_owned())
             .map_err(|_| server_error(deserialize_object_error()))?;
+        let parent = match message.parent_id {
+            Some(parent_id) => {
+                let parent = details
+                    .parents
+                    .get(&parent_id)
+                    .ok_or(db_error(DBError::MessageNotFoundError))?;
+                Some(QuotedMessage::from_details(parent, details)?)
+            }
+            None => None,
+        };
+        let id = message.id.unwrap();
         Ok(MessageResponse {
-            id: message.id.unwrap(),
-            username: user.username,
+            id,
+            username: username(message, details)?,
             user_id: message.user_id,
+            target: MessageTarget::from_db_message(message),
             content,
+            created_at: message.created_at.and_utc(),
+            edited_at: message.edited_at.map(|edited_at| edited_at.and_utc()),
+            deleted_at: message.deleted_at.map(|deleted_at| deleted_at.and_utc()),
+            parent,
+            thread_id: message.thread_id,
+            reactions: ReactionCount::from_db_reactions(
+                details.reactions.get(&id).map_or(&[], Vec::as_slice),
+            ),
+        })
+    }
+}
+
+/// Returns the username of the author of the message
+fn username(message: &Message, details: &MessageDetails) -> Result<String, ErrorResponse> {
+    details
+        .usernames
+        .get(&message.user_id)
+        .cloned()
+        .ok_or(db_error(DBError::UserNotFoundError))
+}
+
+/// The most characters a reaction can have, enough for emoji joined from several code points
+static MAX_REACTION_LENGTH: usize = 16;
+
+impl ReactionCount {
+    /// Groups the reactions to a message by emoji, in the order the emoji were first used
+    pub fn from_db_reactions(reactions: &[Reaction]) -> Vec<Self> {
+        let mut counts: Vec<ReactionCount> = vec![];
+        for reaction in reactions {
+            match counts
+                .iter_mut()
+                .find(|count| count.emoji == reaction.emoji)
+            {
+                Some(count) => {
+                    count.count += 1;
+                    count.user_ids.push(reaction.user_id);
+                }
+                None => counts.push(ReactionCount {
+                    emoji: reaction.emoji.to_owned(),
+                    count: 1,
+                    user_ids: vec![reaction.user_id],
+                }),
+            }
+        }
+        counts
+    }
+}
+
+impl ReactionRequest {
+    /// Checks that the reaction looks like a single emoji: short, without letters, digits or whitespace
+    pub fn is_valid(&self) -> bool {
+        let length = self.emoji.chars().count();
+        length > 0
+            && length <= MAX_REACTION_LENGTH
+            && !self
+                .emoji
+                .chars()
+                .any(|c| c.is_alphanumeric() || c.is_whitespace() || c.is_control())
+    }
+}
+
+impl QuotedMessage {
+    fn from_details(message: &Message, details: &MessageDetails) -> Result<Self, ErrorResponse> {
+        let content = deserialize_data(message.content.to_owned())
+            .map_err(|_| server_error(deserialize_object_error()))?;
+        Ok(QuotedMessage {
+            id: message.id.unwrap(),
+            user_id: message.user_id,
+            username: username(message, details)?,
+            snippet: content.snippet(),
+            deleted: message.deleted_at.is_some(),
         })
     }
 }
 
-#[derive(Parser, Debug)]
-#[command(version, about, long_about = None)]
-struct Args {
-    #[arg(long, default_value = "localhost")]
-    pub hostname: String,
+impl MessageContent {
+    /// Returns the beginning of the text, or the name of the file
+    pub fn snippet(&self) -> String {
+        match self {
+            MessageContent::Text(text) => text.chars().take(SNIPPET_LENGTH).collect(),
+            MessageContent::Image(_) => "[image]".to_string(),
+            MessageContent::File(name, _) => format!("[{}]", name),
+            MessageContent::Attachment(info) => format!("[{}]", info.name),
+        }
+    }
+    /// Returns the text indexed for searching: the text, or the name of the file
+    pub fn search_text(&self) -> String {
+        match self {
+            MessageContent::Text(text) => text.to_owned(),
+            MessageContent::Image(_) => String::new(),
+            MessageContent::File(name, _) => name.to_owned(),
+            MessageContent::Attachment(info) => info.name.to_owned(),
+        }
+    }
+    /// Returns the usernames mentioned as `@username` in the text, without duplicates
+    ///
+    /// Mentions inside code spans (between runs of the same number of backticks) are skipped,
+    /// and trailing dots and dashes are taken as punctuation rather than part of the username.
+    pub fn mentions(&self) -> Vec<String> {
+        let MessageContent::Text(text) = self else {
+            return vec![];
+        };
+        let chars: Vec<char> = text.chars().collect();
+
+        let mut usernames: Vec<String> = vec![];
+        let mut i = 0;
+        while i < chars.len() {
+            if chars[i] == '`' {
+                let run = backtick_run(&chars, i);
+                // An unclosed run of backticks is literal text
+                i = match closing_backticks(&chars, i + run, run) {
+                    Some(end) => end + run,
+                    None => i + run,
+                };
+                continue;
+            }
+            // An @ inside a word is e.g. an e-mail address
+            if chars[i] == '@' && (i == 0 || !is_username_char(chars[i - 1])) {
+                let start = i + 1;
+                let mut end = start;
+                while end < chars.len() && is_username_char(chars[end]) {
+                    end += 1;
+                }
+                while end > start && matches!(chars[end - 1], '.' | '-') {
+                    end -= 1;
+                }
+
+                let username: String = chars[start..end].iter().collect();
+                if !username.is_empty() && !usernames.contains(&username) {
+                    usernames.push(username);
+                }
+                i = end.max(start);
+                continue;
+            }
+            i += 1;
+        }
+
+        usernames
+    }
+}
+
+/// Whether the character can be a part of a mentioned username
+fn is_username_char(c: char) -> bool {
+    c.is_alphanumeric() || matches!(c, '_' | '-' | '.')
+}
+
+/// Returns the number of backticks in the run starting at `start`
+fn backtick_run(chars: &[char], start: usize) -> usize {
+    chars[start..].iter().take_while(|&&c| c == '`').count()
+}
+
+/// Finds the run of exactly `run` backticks closing a code span, searching from `start`
+fn closing_backticks(chars: &[char], start: usize, run: usize) -> Option<usize> {
+    let mut i = start;
+    while i < chars.len() {
+        if chars[i] == '`' {
+            let length = backtick_run(chars, i);
+            if length == run {
+                return Some(i);
+            }
+            i += length;
+        } else {
+            i += 1;
+        }
+    }
+    None
+}
+
+impl SnippetPart {
+    /// Splits a snippet of `DB::search_messages` into the highlighted parts and the rest
+    pub fn from_db_snippet(snippet: &str) -> Vec<Self> {
+        let mut parts = vec![];
+        let mut text = String::new();
+        let mut highlighted = false;
+        for c in snippet.chars() {
+            if c == HIGHLIGHT_START || c == HIGHLIGHT_END {
+                if !text.is_empty() {
+                    parts.push(SnippetPart {
+                        text: std::mem::take(&mut text),
+                        highlighted,
+                    });
+                }
+                highlighted = c == HIGHLIGHT_START;
+            } else {
+                text.push(c);
+            }
+        }
+        if !text.is_empty() {
+            parts.push(SnippetPart { text, highlighted });
+        }
+        parts
+    }
+}
+
+impl MessageTarget {
+    pub fn from_db_message(message: &Message) -> Self {
+        match (message.room_id, message.recipient_id) {
+            (Some(room_id), _) => MessageTarget::Room(room_id),
+            (None, Some(recipient_id)) => MessageTarget::User(recipient_id),
+            // Ruled out by the CHECK constraint on the messages table
+            (None, None) => unreachable!("message {:?} has no target", message.id),
+        }
+    }
+}
 
-    #[arg(long, default_value = "11111")]
-    pub port: String,
+impl AttachmentInfo {
+    pub fn from_db_attachment(attachment: &Attachment) -> Self {
+        AttachmentInfo {
+            id: attachment.id.unwrap(),
+            name: attachment.name.to_owned(),
+            mime_type: attachment.mime_type.to_owned(),
+            size: attachment.size,
+            sha256: attachment.sha256.to_owned(),
+        }
+    }
 }
 
-pub fn get_address() -> String {
-    let args = Args::parse();
+impl RoomResponse {
+    pub fn from_db_room(room: &Room, joined: bool) -> Self {
+        RoomResponse {
+            id: room.id.unwrap(),
+            name: room.name.to_owned(),
+            joined,
+        }
+    }
+}
 
-    format!("{}:{}", args.hostname, args.port)
+impl Role {
+    /// Reads the role stored in the `role` column of `users`, an unknown role is a member
+    pub fn from_db_role(role: &str) -> Self {
+        match role {
+            "admin" => Role::Admin,
+            "moderator" => Role::Moderator,
+            _ => Role::Member,
+        }
+    }
+    /// The role as stored in the `role` column of `users`
+    pub fn as_db_role(&self) -> &'static str {
+        match self {
+            Role::Admin => "admin",
+            Role::Moderator => "moderator",
+            Role::Member => "member",
+        }
+    }
+}
+
+impl User {
+    pub fn role(&self) -> Role {
+        Role::from_db_role(&self.role)
+    }
+    /// Returns when the mute of the user ends, `None` if they aren't muted (anymore)
+    pub fn active_mute(&self) -> Option<DateTime<Utc>> {
+        self.muted_until
+            .filter(|muted_until| *muted_until > Utc::now().naive_utc())
+            .map(|muted_until| muted_until.and_utc())
+    }
+}
+
+impl ModerationResponse {
+    pub fn from_db_user(user: &User, moderator_id: i32, action: ModerationAction) -> Self {
+        ModerationResponse {
+            user_id: user.id.unwrap(),
+            username: user.username.to_owned(),
+            moderator_id,
+            action,
+            muted_until: user.active_mute(),
+        }
+    }
 }
 
 pub fn flush(message: &str) {
@@ -76,39 +361,69 @@ pub fn deserialize_stream(string: String) -> Result<StreamRequest, serde_json::E
     serde_json::from_str(&string)
 }
 
+pub fn serialize_server_response_bincode(data: ServerResponse) -> Result<Vec<u8>, bincode::Error> {
+    bincode::serialize(&data)
+}
+pub fn deserialize_server_response_bincode(data: &[u8]) -> Result<ServerResponse, bincode::Error> {
+    bincode::deserialize(data)
+}
+
+pub fn serialize_stream_bincode(request: StreamRequest) -> Result<Vec<u8>, bincode::Error> {
+    bincode::serialize(&request)
+}
+pub fn deserialize_stream_bincode(data: &[u8]) -> Result<StreamRequest, bincode::Error> {
+    bincode::deserialize(data)
+}
+
 static SECONDS_INDEX: usize = 19;
-pub fn save_image(bytes: &Vec<u8>) -> Result<String, StreamError> {
+pub fn save_image(bytes: &[u8]) -> Result<String, StreamError> {
     let timestamp = &Local::now().to_string()[..SECONDS_INDEX];
     let filename = format!("{}.png", timestamp);
 
     std::fs::create_dir_all("images").map_err(StreamError::FileCreationError)?;
 
     let mut file =
-        File::create(&format!("images/{filename}")).map_err(StreamError::FileCreationError)?;
-    file.write_all(&bytes)
-        .map_err(StreamError::FileWriteError)?;
+        File::create(format!("images/{filename}")).map_err(StreamError::FileCreationError)?;
+    file.write_all(bytes).map_err(StreamError::FileWriteError)?;
 
     Ok(filename)
 }
 
-pub fn save_file(filename: &str, bytes: &Vec<u8>) -> Result<String, StreamError> {
+pub fn save_file(filename: &str, bytes: &[u8]) -> Result<String, StreamError> {
     std::fs::create_dir_all("files").map_err(StreamError::FileCreationError)?;
 
     let mut file =
-        File::create(&format!("files/{filename}")).map_err(StreamError::FileCreationError)?;
-    file.write_all(&bytes)
-        .map_err(StreamError::FileWriteError)?;
+        File::create(format!("files/{filename}")).map_err(StreamError::FileCreationError)?;
+    file.write_all(bytes).map_err(StreamError::FileWriteError)?;
 
     Ok(filename.to_string())
 }
 
 pub fn output_message_data(message_data: MessageResponse) {
+    let time = message_data
+        .created_at
+        .with_timezone(&Local)
+        .format("%Y-%m-%d %H:%M");
+
+    if message_data.deleted_at.is_some() {
+        flush(&format!(
+            "[{}] {}: *message #{} deleted*",
+            time, message_data.username, message_data.id
+        ));
+        return;
+    }
+    let edited = if message_data.edited_at.is_some() {
+        " (edited)"
+    } else {
+        ""
+    };
+
     match message_data.content {
         MessageContent::File(filename, bytes) => {
             match save_file(&filename, &bytes) {
                 Ok(filename) => flush(&format!(
-                    "{}: sent a file {}",
-                    message_data.username, filename
+                    "[{}] {}: sent a file {}",
+                    time, message_data.username, filename
                 )),
                 Err(e) => {
                     flush("Received file, but failed to save it");
@@ -119,8 +434,8 @@ pub fn output_message_data(message_data: MessageResponse) {
         MessageContent::Image(bytes) => {
             match save_image(&bytes) {
                 Ok(filename) => flush(&format!(
-                    "{}: sent an image {}",
-                    message_data.username, filename
+                    "[{}] {}: sent an image {}",
+                    time, message_data.username, filename
                 )),
                 Err(e) => {
                     flush("Received image, but failed to save it");
@@ -128,12 +443,64 @@ pub fn output_message_data(message_data: MessageResponse) {
                 }
             };
         }
+        MessageContent::Attachment(info) => {
+            flush(&format!(
+                "[{}] {}: sent {} ({} bytes), download it with `.fetch {}`",
+                time, message_data.username, info.name, info.size, info.id
+            ));
+        }
         MessageContent::Text(string) => {
-            flush(&format!("{}: {}", message_data.username, string));
+            flush(&format!(
+                "[{}] {}: {}{}",
+                time, message_data.username, string, edited
+            ));
         }
     }
 }
 
 pub fn unspecified_error() -> Result<(), std::io::Error> {
-    return Err(std::io::Error::new(std::io::ErrorKind::Other, ""));
+    Err(std::io::Error::other(""))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mentions(text: &str) -> Vec<String> {
+        MessageContent::Text(text.to_string()).mentions()
+    }
+
+    #[test]
+    fn mentions_are_found_once_in_order() {
+        assert_eq!(
+            mentions("@bob hi @alice, and @bob again"),
+            vec!["bob", "alice"]
+        );
+    }
+
+    #[test]
+    fn trailing_punctuation_is_not_part_of_the_username() {
+        assert_eq!(
+            mentions("thanks @bob. and @al.ice- too"),
+            vec!["bob", "al.ice"]
+        );
+    }
+
+    #[test]
+    fn addresses_and_lone_ats_are_not_mentions() {
+        assert!(mentions("mail bob@example.com or @ or @. now").is_empty());
+    }
+
+    #[test]
+    fn mentions_in_code_spans_are_skipped() {
+        assert_eq!(mentions("`@bob` ``a ` @carl`` @dave"), vec!["dave"]);
+        assert_eq!(mentions("unclosed ` @bob"), vec!["bob"]);
+    }
+
+    #[test]
+    fn only_text_has_mentions() {
+        assert!(MessageContent::File("@bob".to_string(), vec![])
+            .mentions()
+            .is_empty());
+    }
 }
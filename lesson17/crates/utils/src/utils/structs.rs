@@ -1,16 +1,75 @@
+use std::fmt;
 use std::io::stdin;
 
+use chrono::{DateTime, Utc};
 use init_macros::create_valueenum_init_functions;
 use serde::{Deserialize, Serialize};
 
 use crate::errors::{DBError, ServerError};
 
+/// Stands in for a secret in the `Debug` output, which ends up in the logs
+struct Redacted;
+
+impl fmt::Debug for Redacted {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("<redacted>")
+    }
+}
+
+/// Stands in for binary content in the `Debug` output, only its size is shown
+struct ByteCount(usize);
+
+impl fmt::Debug for ByteCount {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "<{} bytes>", self.0)
+    }
+}
+
 /// Represents the content of a chat message.
-#[derive(Serialize, Deserialize, Debug, Clone)]
+///
+/// `Image` and `File` are only sent by clients, the server moves their bytes into the attachment
+/// store and saves the message as an `Attachment`.
+#[derive(Serialize, Deserialize, Clone)]
 pub enum MessageContent {
     Image(Vec<u8>),
     File(String, Vec<u8>),
     Text(String),
+    Attachment(AttachmentInfo),
+}
+
+impl fmt::Debug for MessageContent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MessageContent::Image(bytes) => f
+                .debug_tuple("Image")
+                .field(&ByteCount(bytes.len()))
+                .finish(),
+            MessageContent::File(name, bytes) => f
+                .debug_tuple("File")
+                .field(name)
+                .field(&ByteCount(bytes.len()))
+                .finish(),
+            MessageContent::Text(text) => f.debug_tuple("Text").field(text).finish(),
+            MessageContent::Attachment(info) => f.debug_tuple("Attachment").field(info).finish(),
+        }
+    }
+}
+
+/// Metadata of a stored attachment, the bytes are fetched separately with a `FetchAttachmentRequest`
+///
+/// # Fields
+/// * `id` - The id of the attachment
+/// * `name` - The original file name
+/// * `mime_type` - The MIME type guessed from the name
+/// * `size` - The size of the attachment in bytes
+/// * `sha256` - The hex-encoded SHA-256 of the bytes
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AttachmentInfo {
+    pub id: i32,
+    pub name: String,
+    pub mime_type: String,
+    pub size: i64,
+    pub sha256: String,
 }
 
 // My macros don't support generics, so I have to manually implement these init functions
@@ -27,14 +86,79 @@ pub fn file(filename: String, vec: Vec<u8>) -> MessageContent {
 pub fn text(text: String) -> MessageContent {
     MessageContent::Text(text)
 }
+/// Equivalent to writing out MessageData::Attachment(info)
+pub fn attachment(info: AttachmentInfo) -> MessageContent {
+    MessageContent::Attachment(info)
+}
+
+/// The encoding of the frames on a connection
+///
+/// Text frames carry JSON, binary frames carry bincode. The server answers in the encoding of the
+/// first request the client sent.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+pub enum Encoding {
+    #[default]
+    Json,
+    Bincode,
+}
+
+/// Where a message is sent, either into a room or directly to a single user
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+pub enum MessageTarget {
+    Room(i32),
+    User(i32),
+}
 
 /// Response variant for a message from the server
+///
+/// A message with `deleted_at` set is a tombstone of a deleted message, its content is empty.
+/// For direct messages, `target` is the recipient, so the other side of the conversation
+/// is `user_id` unless the message was sent by the reading user.
+/// A reply quotes its `parent`, and `thread_id` is the message starting its thread.
+/// `reactions` are grouped by emoji, in the order they were first used.
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct MessageResponse {
     pub id: i32,
     pub username: String,
     pub user_id: i32,
+    pub target: MessageTarget,
     pub content: MessageContent,
+    pub created_at: DateTime<Utc>,
+    pub edited_at: Option<DateTime<Utc>>,
+    pub deleted_at: Option<DateTime<Utc>>,
+    pub parent: Option<QuotedMessage>,
+    pub thread_id: Option<i32>,
+    pub reactions: Vec<ReactionCount>,
+}
+
+/// The message a reply quotes
+///
+/// # Fields
+/// * `id` - The id of the quoted message
+/// * `user_id` - The id of its author
+/// * `username` - The username of its author
+/// * `snippet` - The beginning of its text, or the name of its attachment
+/// * `deleted` - Whether it has been deleted since, the snippet is empty then
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct QuotedMessage {
+    pub id: i32,
+    pub user_id: i32,
+    pub username: String,
+    pub snippet: String,
+    pub deleted: bool,
+}
+
+/// The reactions to a message with one emoji
+///
+/// # Fields
+/// * `emoji` - The emoji
+/// * `count` - The number of users who reacted with it
+/// * `user_ids` - The users who reacted with it, oldest first
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ReactionCount {
+    pub emoji: String,
+    pub count: i64,
+    pub user_ids: Vec<i32>,
 }
 
 /// Response variant for an error from the server
@@ -46,11 +170,315 @@ pub enum ErrorResponse {
 // Create shorter inits like pub fn db_error(db_error) => ErrorResponse::DBError(db_error)
 create_valueenum_init_functions!(ErrorResponse, DBError(DBError), ServerError(ServerError));
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+/// The role of a user, deciding whom they can moderate
+///
+/// Moderators can delete any message and mute, kick or ban members, admins can also moderate
+/// moderators. Roles are ordered, a user can only moderate users with a lower role.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
+pub enum Role {
+    #[default]
+    Member,
+    Moderator,
+    Admin,
+}
+
+/// Response variant for a successful login, registration or refresh
+///
+/// # Fields
+/// * `token` - The short-lived JWT token authorizing the requests
+/// * `refresh_token` - The long-lived token of the session, exchanged for a new `Auth` with a `RefreshRequest`
+/// * `username` - The username of the user
+/// * `user_id` - The id of the user
+/// * `role` - The role of the user
+/// * `unread` - The rooms and direct conversations with messages the user hasn't read yet
+#[derive(Serialize, Deserialize, Clone)]
 pub struct Auth {
     pub token: String,
+    pub refresh_token: String,
+    pub username: String,
+    pub user_id: i32,
+    pub role: Role,
+    pub unread: Vec<UnreadCount>,
+}
+
+impl fmt::Debug for Auth {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Auth")
+            .field("token", &Redacted)
+            .field("refresh_token", &Redacted)
+            .field("username", &self.username)
+            .field("user_id", &self.user_id)
+            .field("role", &self.role)
+            .field("unread", &self.unread)
+            .finish()
+    }
+}
+
+/// The amount of unread messages in a room or a direct conversation
+///
+/// # Fields
+/// * `target` - The room or the other user of the conversation
+/// * `count` - The amount of messages of other users after the user's read receipt
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct UnreadCount {
+    pub target: MessageTarget,
+    pub count: i64,
+}
+
+/// Response variant for a connection authenticated with a `ResumeRequest`
+///
+/// # Fields
+/// * `username` - The username of the user
+/// * `user_id` - The id of the user
+/// * `role` - The role of the user
+/// * `expires_at` - When the token expires (in seconds from UNIX epoch), the connection is
+///   signed out then unless it refreshes the session
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ResumedResponse {
+    pub username: String,
+    pub user_id: i32,
+    pub role: Role,
+    pub expires_at: u64,
+}
+
+/// Response variant describing a chat room
+///
+/// # Fields
+/// * `id` - The id of the room
+/// * `name` - The unique name of the room
+/// * `joined` - Whether the requesting user is a member of the room
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RoomResponse {
+    pub id: i32,
+    pub name: String,
+    pub joined: bool,
+}
+
+/// Response variant listing all chat rooms
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RoomListResponse {
+    pub rooms: Vec<RoomResponse>,
+}
+
+/// A user who is online, i.e. has at least one authenticated connection
+///
+/// # Fields
+/// * `user_id` - The id of the user
+/// * `username` - The username of the user
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct UserPresence {
+    pub user_id: i32,
+    pub username: String,
+}
+
+/// Response variant listing the users who are online
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct OnlineUsersResponse {
+    pub users: Vec<UserPresence>,
+}
+
+/// Response variant for a user typing a message, it isn't persisted
+///
+/// The server never sends an event when the user stops typing: expiring the indicator is left to
+/// the clients, which hide it once `expires_in` seconds pass without another `Typing` from the
+/// user, or when the user's message arrives.
+///
+/// # Fields
+/// * `user_id` - The id of the typing user
+/// * `username` - The username of the typing user
+/// * `target` - The room or the user the message is being typed to
+/// * `expires_in` - How long the indicator lasts (in seconds)
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TypingResponse {
+    pub user_id: i32,
+    pub username: String,
+    pub target: MessageTarget,
+    pub expires_in: u64,
+}
+
+/// Response variant for a user who has read the conversation up to a message
+///
+/// # Fields
+/// * `user_id` - The id of the user
+/// * `username` - The username of the user
+/// * `target` - The room or the other user of the conversation, as the reader sees it
+/// * `message_id` - The last message the user has read
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ReadReceiptResponse {
+    pub user_id: i32,
     pub username: String,
+    pub target: MessageTarget,
+    pub message_id: i32,
+}
+
+/// Response variant for a reaction added to or removed from a message
+///
+/// # Fields
+/// * `message_id` - The message the reaction belongs to
+/// * `target` - The room or the recipient of the message
+/// * `user_id` - The id of the user who reacted
+/// * `username` - The username of the user who reacted
+/// * `emoji` - The emoji of the reaction
+/// * `added` - Whether the reaction was added, or removed
+/// * `reactions` - All reactions to the message after the change
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ReactionResponse {
+    pub message_id: i32,
+    pub target: MessageTarget,
     pub user_id: i32,
+    pub username: String,
+    pub emoji: String,
+    pub added: bool,
+    pub reactions: Vec<ReactionCount>,
+}
+
+/// Response variant for a page of the messages mentioning the user
+///
+/// # Fields
+/// * `messages` - The messages of the page, oldest first
+/// * `has_more` - Whether there are older mentions, the id of the first message is the cursor
+///   (`before_id`) of the next page
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct MentionsResponse {
+    pub messages: Vec<MessageResponse>,
+    pub has_more: bool,
+}
+
+/// What a moderator did to a user
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum ModerationAction {
+    Mute,
+    Unmute,
+    Ban,
+    Unban,
+    Kick,
+}
+
+/// Response variant for a moderation of a user, sent to the moderator and to the moderated user
+///
+/// A kicked or banned user gets it right before their connections are closed.
+///
+/// # Fields
+/// * `user_id` - The id of the moderated user
+/// * `username` - The username of the moderated user
+/// * `moderator_id` - The id of the moderator
+/// * `action` - What the moderator did
+/// * `muted_until` - When the mute of the user ends, `None` if they aren't muted
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ModerationResponse {
+    pub user_id: i32,
+    pub username: String,
+    pub moderator_id: i32,
+    pub action: ModerationAction,
+    pub muted_until: Option<DateTime<Utc>>,
+}
+
+/// A part of the snippet of a search result
+///
+/// # Fields
+/// * `text` - The text of the part
+/// * `highlighted` - Whether the part matches the searched words
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SnippetPart {
+    pub text: String,
+    pub highlighted: bool,
+}
+
+/// A message found by a search
+///
+/// # Fields
+/// * `message` - The message
+/// * `snippet` - The part of its text (or file name) around the matches, in parts
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SearchResult {
+    pub message: MessageResponse,
+    pub snippet: Vec<SnippetPart>,
+}
+
+/// Response variant for a page of search results
+///
+/// # Fields
+/// * `query` - The searched words
+/// * `results` - The results of the page, the best matches first
+/// * `has_more` - Whether there are more results, read with a bigger `offset`
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SearchResponse {
+    pub query: String,
+    pub results: Vec<SearchResult>,
+    pub has_more: bool,
+}
+
+/// Response variant for a page of a thread
+///
+/// # Fields
+/// * `root` - The message starting the thread
+/// * `replies` - The replies of the page, oldest first
+/// * `has_more` - Whether there are newer replies, the id of the last reply is the cursor
+///   (`after_id`) of the next page
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ThreadResponse {
+    pub root: MessageResponse,
+    pub replies: Vec<MessageResponse>,
+    pub has_more: bool,
+}
+
+/// Response variant for a page of the message history
+///
+/// # Fields
+/// * `target` - The room or the direct conversation the history belongs to
+/// * `messages` - The messages of the page, oldest first
+/// * `has_more` - Whether there are older messages, the id of the first message is the cursor
+///   (`before_id`) of the next page
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct HistoryResponse {
+    pub target: MessageTarget,
+    pub messages: Vec<MessageResponse>,
+    pub has_more: bool,
+}
+
+/// Response variant for a piece of a downloaded attachment
+///
+/// # Fields
+/// * `attachment_id` - The attachment the bytes belong to
+/// * `offset` - The position of the chunk in the attachment
+/// * `data` - The bytes of the chunk
+/// * `last` - Whether this is the final chunk of the attachment
+#[derive(Serialize, Deserialize, Clone)]
+pub struct AttachmentChunkResponse {
+    pub attachment_id: i32,
+    pub offset: u64,
+    pub data: Vec<u8>,
+    pub last: bool,
+}
+
+impl fmt::Debug for AttachmentChunkResponse {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("AttachmentChunkResponse")
+            .field("attachment_id", &self.attachment_id)
+            .field("offset", &self.offset)
+            .field("data", &ByteCount(self.data.len()))
+            .field("last", &self.last)
+            .finish()
+    }
+}
+
+/// Response variant acknowledging the progress of a chunked upload
+///
+/// Sent when the upload begins (or resumes) and after every received chunk.
+///
+/// # Fields
+/// * `upload_id` - The id of the upload
+/// * `name` - The name of the uploaded file
+/// * `size` - The size of the whole file in bytes
+/// * `received` - The amount of bytes received so far, the next chunk starts here
+/// * `chunk_size` - The size of every chunk but the last one
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct UploadProgressResponse {
+    pub upload_id: i32,
+    pub name: String,
+    pub size: i64,
+    pub received: i64,
+    pub chunk_size: u32,
 }
 
 /// Represents a response coming from the server
@@ -59,31 +487,181 @@ pub enum ServerResponse {
     Message(MessageResponse),
     Auth(Auth),
     Error(ErrorResponse),
+    RoomJoined(RoomResponse),
+    RoomLeft(RoomResponse),
+    RoomList(RoomListResponse),
+    History(HistoryResponse),
+    MessageEdited(MessageResponse),
+    MessageDeleted(MessageResponse),
+    AttachmentInfo(AttachmentInfo),
+    AttachmentChunk(AttachmentChunkResponse),
+    UploadProgress(UploadProgressResponse),
+    /// The session has ended, holds the id of the user who logged out
+    LoggedOut(i32),
+    Resumed(ResumedResponse),
+    /// The first connection of the user has authenticated
+    UserOnline(UserPresence),
+    /// The last connection of the user has closed or signed out
+    UserOffline(UserPresence),
+    OnlineUsers(OnlineUsersResponse),
+    /// Ephemeral, relayed to the other users who can see the target and never stored
+    Typing(TypingResponse),
+    ReadReceipt(ReadReceiptResponse),
+    Thread(ThreadResponse),
+    Reaction(ReactionResponse),
+    /// Sent to the mentioned user's connections, whichever conversation they're in
+    Mention(MessageResponse),
+    Mentions(MentionsResponse),
+    Search(SearchResponse),
+    Moderation(ModerationResponse),
+    /// The server is shutting down and closes the connection once its pending responses are
+    /// written, holds the longest time (in seconds) it waits for them
+    ShuttingDown(u64),
 }
 // Create shorter inits like pub fn message(message_response) => ServerResponse::Message(message_response)
 create_valueenum_init_functions!(
     ServerResponse,
     Message(MessageResponse),
     Auth(Auth),
-    Error(ErrorResponse)
+    Error(ErrorResponse),
+    RoomJoined(RoomResponse),
+    RoomLeft(RoomResponse),
+    RoomList(RoomListResponse),
+    History(HistoryResponse),
+    MessageEdited(MessageResponse),
+    MessageDeleted(MessageResponse),
+    AttachmentInfo(AttachmentInfo),
+    AttachmentChunk(AttachmentChunkResponse),
+    UploadProgress(UploadProgressResponse),
+    LoggedOut(i32),
+    Resumed(ResumedResponse),
+    UserOnline(UserPresence),
+    UserOffline(UserPresence),
+    OnlineUsers(OnlineUsersResponse),
+    Typing(TypingResponse),
+    ReadReceipt(ReadReceiptResponse),
+    Thread(ThreadResponse),
+    Reaction(ReactionResponse),
+    Mention(MessageResponse),
+    Mentions(MentionsResponse),
+    Search(SearchResponse),
+    Moderation(ModerationResponse),
+    ShuttingDown(u64)
 );
 
 /// Request variant for sending messages
 ///
 /// # Fields
-/// * `jwt` - The JWT token of the user
+/// * `target` - The room or the user to send the message to
 /// * `message` - The content of the message
+/// * `parent_id` - The message this one replies to, it has to be in the same conversation
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct MessageRequest {
-    pub jwt: String,
+    pub target: MessageTarget,
     pub message: MessageContent,
+    pub parent_id: Option<i32>,
 }
 impl MessageRequest {
-    pub fn new(jwt: String, message: MessageContent) -> Self {
-        MessageRequest { jwt, message }
+    pub fn new(target: MessageTarget, message: MessageContent) -> Self {
+        MessageRequest {
+            target,
+            message,
+            parent_id: None,
+        }
+    }
+    pub fn reply(target: MessageTarget, message: MessageContent, parent_id: i32) -> Self {
+        MessageRequest {
+            target,
+            message,
+            parent_id: Some(parent_id),
+        }
     }
 }
 
+/// Request variant for downloading the bytes of an attachment
+///
+/// The server answers with an `AttachmentInfo` response carrying the metadata followed by
+/// `AttachmentChunk` responses in order.
+///
+/// # Fields
+/// * `attachment_id` - The attachment to download
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct FetchAttachmentRequest {
+    pub attachment_id: i32,
+}
+
+/// Request variant starting a chunked upload of a file
+///
+/// If the user has an unfinished upload of a file with the same name and size, it is resumed.
+///
+/// # Fields
+/// * `name` - The name of the file
+/// * `size` - The size of the file in bytes
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct BeginUploadRequest {
+    pub name: String,
+    pub size: i64,
+}
+
+/// Request variant carrying one chunk of an upload
+///
+/// Best sent in a binary frame, JSON would inflate the bytes. Chunk `index` starts at byte
+/// `index * chunk_size` of the file and chunks have to arrive in order.
+///
+/// # Fields
+/// * `upload_id` - The upload the chunk belongs to
+/// * `index` - The number of the chunk
+/// * `data` - The bytes of the chunk
+#[derive(Serialize, Deserialize, Clone)]
+pub struct UploadChunkRequest {
+    pub upload_id: i32,
+    pub index: u32,
+    pub data: Vec<u8>,
+}
+
+impl fmt::Debug for UploadChunkRequest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("UploadChunkRequest")
+            .field("upload_id", &self.upload_id)
+            .field("index", &self.index)
+            .field("data", &ByteCount(self.data.len()))
+            .finish()
+    }
+}
+
+/// Request variant finishing an upload and sending the file as a message
+///
+/// # Fields
+/// * `upload_id` - The upload to finish
+/// * `target` - The room or the user to send the file to
+/// * `sha256` - The hex-encoded SHA-256 of the whole file
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct FinishUploadRequest {
+    pub upload_id: i32,
+    pub target: MessageTarget,
+    pub sha256: String,
+}
+
+/// Request variant for editing one of the user's messages
+///
+/// # Fields
+/// * `message_id` - The message to edit
+/// * `message` - The new content of the message
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct EditMessageRequest {
+    pub message_id: i32,
+    pub message: MessageContent,
+}
+
+/// Request variant for deleting one of the user's messages
+///
+/// # Fields
+/// * `message_id` - The message to delete
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DeleteMessageRequest {
+    pub message_id: i32,
+}
+
 /// Type of authentication request
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum AuthRequestKind {
@@ -109,13 +687,23 @@ impl AuthRequestKind {
 /// * `kind` - The type of authentication request
 /// * `username` - The username of the user
 /// * `password` - The password of the user
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Clone)]
 pub struct AuthRequest {
     pub kind: AuthRequestKind,
     pub username: String,
     pub password: String,
 }
 
+impl fmt::Debug for AuthRequest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("AuthRequest")
+            .field("kind", &self.kind)
+            .field("username", &self.username)
+            .field("password", &Redacted)
+            .finish()
+    }
+}
+
 impl AuthRequest {
     pub fn new(kind: AuthRequestKind, username: String, password: String) -> Self {
         AuthRequest {
@@ -129,25 +717,274 @@ impl AuthRequest {
 /// Request variant for reading message history
 ///
 /// # Fields
-/// * `jwt` - The JWT token of the user
+/// * `target` - The room, or the other user of a direct conversation, to read the history of
 /// * `amount` - The number of messages to read
+/// * `before_id` - Only read messages older than this message id, `None` reads the newest page
+/// * `before` - Only read messages sent before this time
+/// * `after` - Only read messages sent after this time
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct ReadRequest {
-    pub jwt: String,
+    pub target: MessageTarget,
+    pub amount: i32,
+    pub before_id: Option<i32>,
+    pub before: Option<DateTime<Utc>>,
+    pub after: Option<DateTime<Utc>>,
+}
+
+/// Request variant for reading a page of a thread
+///
+/// # Fields
+/// * `message_id` - The message starting the thread, or any reply in it
+/// * `amount` - The number of replies to read
+/// * `after_id` - Only read replies newer than this message id, `None` reads the first page
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ReadThreadRequest {
+    pub message_id: i32,
+    pub amount: i32,
+    pub after_id: Option<i32>,
+}
+
+/// Request variant for creating a new room, the creator joins it automatically
+///
+/// # Fields
+/// * `name` - The unique name of the room
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CreateRoomRequest {
+    pub name: String,
+}
+
+/// Request variant for joining or leaving a room
+///
+/// # Fields
+/// * `room_id` - The room to join/leave
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RoomRequest {
+    pub room_id: i32,
+}
+
+/// Request variant for reacting to a message, or taking the reaction back
+///
+/// # Fields
+/// * `message_id` - The message to react to
+/// * `emoji` - The emoji to react with
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ReactionRequest {
+    pub message_id: i32,
+    pub emoji: String,
+}
+
+/// Request variant for reading a page of the messages mentioning the user
+///
+/// # Fields
+/// * `amount` - The number of messages to read
+/// * `before_id` - Only read messages older than this one, `None` reads the newest page
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ListMentionsRequest {
+    pub amount: i32,
+    pub before_id: Option<i32>,
+}
+
+/// Request variant for searching the text and file names of the messages the user can see
+///
+/// # Fields
+/// * `query` - The words to search for, the last one may be a prefix of a word
+/// * `user_id` - Only search the messages of this user
+/// * `amount` - The number of results to read
+/// * `offset` - The number of results to skip, i.e. the results already read
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SearchRequest {
+    pub query: String,
+    pub user_id: Option<i32>,
     pub amount: i32,
     pub offset: i32,
 }
 
+/// Request variant for muting a user, only for moderators
+///
+/// # Fields
+/// * `user_id` - The user to mute
+/// * `duration` - How long the user can't send messages (in seconds)
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct MuteRequest {
+    pub user_id: i32,
+    pub duration: u64,
+}
+
+/// Request variant for unmuting, kicking, banning or unbanning a user, only for moderators
+///
+/// # Fields
+/// * `user_id` - The user to moderate
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct UserRequest {
+    pub user_id: i32,
+}
+
+/// Request variant for listing all rooms
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ListRoomsRequest {}
+
+/// Request variant for listing the users who are online
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ListOnlineUsersRequest {}
+
+/// Request variant announcing that the user is typing a message, sent repeatedly while they type
+///
+/// # Fields
+/// * `target` - The room or the user the message is being typed to
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TypingRequest {
+    pub target: MessageTarget,
+}
+
+/// Request variant for marking a conversation as read up to a message
+///
+/// # Fields
+/// * `target` - The room or the other user of the conversation
+/// * `message_id` - The last message the user has read
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct MarkReadRequest {
+    pub target: MessageTarget,
+    pub message_id: i32,
+}
+
+/// Request variant for renewing the JWT token of a session
+///
+/// # Fields
+/// * `refresh_token` - The refresh token of the session, it is replaced by the one in the new `Auth`
+#[derive(Serialize, Deserialize, Clone)]
+pub struct RefreshRequest {
+    pub refresh_token: String,
+}
+
+impl fmt::Debug for RefreshRequest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RefreshRequest")
+            .field("refresh_token", &Redacted)
+            .finish()
+    }
+}
+
+/// Request variant for ending the session of the connection, its JWT and refresh tokens stop working
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct LogoutRequest {}
+
+/// Request variant for authenticating a new connection with the JWT token of an existing session,
+/// e.g. after a reconnect
+///
+/// # Fields
+/// * `token` - The JWT token of the session
+#[derive(Serialize, Deserialize, Clone)]
+pub struct ResumeRequest {
+    pub token: String,
+}
+
+impl fmt::Debug for ResumeRequest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ResumeRequest")
+            .field("token", &Redacted)
+            .finish()
+    }
+}
+
 /// Represents a request to the server
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum StreamRequest {
     MessageRequest(MessageRequest),
     AuthRequest(AuthRequest),
     ReadRequest(ReadRequest),
+    CreateRoomRequest(CreateRoomRequest),
+    JoinRoomRequest(RoomRequest),
+    LeaveRoomRequest(RoomRequest),
+    ListRoomsRequest(ListRoomsRequest),
+    EditMessageRequest(EditMessageRequest),
+    DeleteMessageRequest(DeleteMessageRequest),
+    FetchAttachmentRequest(FetchAttachmentRequest),
+    BeginUploadRequest(BeginUploadRequest),
+    UploadChunkRequest(UploadChunkRequest),
+    FinishUploadRequest(FinishUploadRequest),
+    RefreshRequest(RefreshRequest),
+    LogoutRequest(LogoutRequest),
+    ResumeRequest(ResumeRequest),
+    ListOnlineUsersRequest(ListOnlineUsersRequest),
+    TypingRequest(TypingRequest),
+    MarkReadRequest(MarkReadRequest),
+    ReadThreadRequest(ReadThreadRequest),
+    ReactRequest(ReactionRequest),
+    UnreactRequest(ReactionRequest),
+    ListMentionsRequest(ListMentionsRequest),
+    SearchRequest(SearchRequest),
+    MuteRequest(MuteRequest),
+    UnmuteRequest(UserRequest),
+    KickRequest(UserRequest),
+    BanRequest(UserRequest),
+    UnbanRequest(UserRequest),
 }
 create_valueenum_init_functions!(
     StreamRequest,
     MessageRequest(MessageRequest),
     AuthRequest(AuthRequest),
     ReadRequest(ReadRequest),
+    CreateRoomRequest(CreateRoomRequest),
+    JoinRoomRequest(RoomRequest),
+    LeaveRoomRequest(RoomRequest),
+    ListRoomsRequest(ListRoomsRequest),
+    EditMessageRequest(EditMessageRequest),
+    DeleteMessageRequest(DeleteMessageRequest),
+    FetchAttachmentRequest(FetchAttachmentRequest),
+    BeginUploadRequest(BeginUploadRequest),
+    UploadChunkRequest(UploadChunkRequest),
+    FinishUploadRequest(FinishUploadRequest),
+    RefreshRequest(RefreshRequest),
+    LogoutRequest(LogoutRequest),
+    ResumeRequest(ResumeRequest),
+    ListOnlineUsersRequest(ListOnlineUsersRequest),
+    TypingRequest(TypingRequest),
+    MarkReadRequest(MarkReadRequest),
+    ReadThreadRequest(ReadThreadRequest),
+    ReactRequest(ReactionRequest),
+    UnreactRequest(ReactionRequest),
+    ListMentionsRequest(ListMentionsRequest),
+    SearchRequest(SearchRequest),
+    MuteRequest(MuteRequest),
+    UnmuteRequest(UserRequest),
+    KickRequest(UserRequest),
+    BanRequest(UserRequest),
+    UnbanRequest(UserRequest),
 );
+
+impl StreamRequest {
+    /// The name of the variant, to tell the requests apart without their content
+    pub fn name(&self) -> &'static str {
+        match self {
+            StreamRequest::MessageRequest(_) => "MessageRequest",
+            StreamRequest::AuthRequest(_) => "AuthRequest",
+            StreamRequest::ReadRequest(_) => "ReadRequest",
+            StreamRequest::CreateRoomRequest(_) => "CreateRoomRequest",
+            StreamRequest::JoinRoomRequest(_) => "JoinRoomRequest",
+            StreamRequest::LeaveRoomRequest(_) => "LeaveRoomRequest",
+            StreamRequest::ListRoomsRequest(_) => "ListRoomsRequest",
+            StreamRequest::EditMessageRequest(_) => "EditMessageRequest",
+            StreamRequest::DeleteMessageRequest(_) => "DeleteMessageRequest",
+            StreamRequest::FetchAttachmentRequest(_) => "FetchAttachmentRequest",
+            StreamRequest::BeginUploadRequest(_) => "BeginUploadRequest",
+            StreamRequest::UploadChunkRequest(_) => "UploadChunkRequest",
+            StreamRequest::FinishUploadRequest(_) => "FinishUploadRequest",
+            StreamRequest::RefreshRequest(_) => "RefreshRequest",
+            StreamRequest::LogoutRequest(_) => "LogoutRequest",
+            StreamRequest::ResumeRequest(_) => "ResumeRequest",
+            StreamRequest::ListOnlineUsersRequest(_) => "ListOnlineUsersRequest",
+            StreamRequest::TypingRequest(_) => "TypingRequest",
+            StreamRequest::MarkReadRequest(_) => "MarkReadRequest",
+            StreamRequest::ReadThreadRequest(_) => "ReadThreadRequest",
+            StreamRequest::ReactRequest(_) => "ReactRequest",
+            StreamRequest::UnreactRequest(_) => "UnreactRequest",
+            StreamRequest::ListMentionsRequest(_) => "ListMentionsRequest",
+            StreamRequest::SearchRequest(_) => "SearchRequest",
+            StreamRequest::MuteRequest(_) => "MuteRequest",
+            StreamRequest::UnmuteRequest(_) => "UnmuteRequest",
+            StreamRequest::KickRequest(_) => "KickRequest",
+            StreamRequest::BanRequest(_) => "BanRequest",
+            StreamRequest::UnbanRequest(_) => "UnbanRequest",
+        }
+    }
+}
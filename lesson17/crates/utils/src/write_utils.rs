@@ -1,17 +1,20 @@
 use std::{
     fs::File,
-    io::{Cursor, Error, Read, Write},
+    io::{copy, Cursor, Error, Read, Seek, SeekFrom, Write},
     net::TcpStream,
     path::Path,
 };
 
 use image as image_crate;
 use image_crate::ImageFormat;
+use sha2::{Digest, Sha256};
 
 use crate::{
-    errors::invalid_input_error, file, image, message_request, serialize_stream, StreamRequest,
+    begin_upload_request, errors::invalid_input_error, finish_upload_request, image,
+    message_request, upload_chunk_request, BeginUploadRequest, FinishUploadRequest, StreamRequest,
+    UploadChunkRequest, UploadProgressResponse,
 };
-use crate::{utils::MessageContent, MessageRequest};
+use crate::{utils::MessageContent, MessageRequest, MessageTarget};
 
 /// Get the image from the path and convert into a MessageContent
 fn get_image(path: &Path) -> Result<MessageContent, Error> {
@@ -23,7 +26,10 @@ fn get_image(path: &Path) -> Result<MessageContent, Error> {
     };
 
     let mut buf = Vec::new();
-    if let Err(_) = img.write_to(&mut Cursor::new(&mut buf), ImageFormat::Png) {
+    if img
+        .write_to(&mut Cursor::new(&mut buf), ImageFormat::Png)
+        .is_err()
+    {
         return Err(invalid_input_error(
             "Failed to convert image to .png, probably invalid/unknown image type",
         ));
@@ -40,7 +46,7 @@ fn get_image(path: &Path) -> Result<MessageContent, Error> {
 fn write_into_stream(mut stream: &TcpStream, content: &[u8]) -> std::io::Result<()> {
     let len_bytes = (content.len() as u32).to_be_bytes();
 
-    stream.write(&len_bytes)?;
+    stream.write_all(&len_bytes)?;
     stream.write_all(content)?;
 
     Ok(())
@@ -66,8 +72,12 @@ pub fn serialize_and_write(stream: &TcpStream, request: StreamRequest) -> std::i
 /// # Arguments
 /// * `stream` - The stream to write into
 /// * `path_string` - The path to the image
-/// * `jwt` - The JWT auth token
-pub fn handle_image(stream: &TcpStream, path_string: &str, jwt: String) -> std::io::Result<()> {
+/// * `target` - The room or the user to send the image to
+pub fn handle_image(
+    stream: &TcpStream,
+    path_string: &str,
+    target: MessageTarget,
+) -> std::io::Result<()> {
     let path = Path::new(path_string);
 
     let message = match get_image(path) {
@@ -77,26 +87,83 @@ pub fn handle_image(stream: &TcpStream, path_string: &str, jwt: String) -> std::
         }
     };
 
-    serialize_and_write(stream, message_request(MessageRequest::new(jwt, message)))
+    serialize_and_write(
+        stream,
+        message_request(MessageRequest::new(target, message)),
+    )
 }
 
-/// Handle the file at the path and write it into the stream
+/// Begin a chunked upload of the file at the path
+///
+/// The chunks are sent once the server acknowledges the upload, see `handle_upload_progress`.
 ///
 /// # Arguments
 /// * `stream` - The stream to write into
 /// * `path_string` - The path to the file
-/// * `jwt` - The JWT auth token
-pub fn handle_file(stream: &TcpStream, path_string: &str, jwt: String) -> std::io::Result<()> {
+pub fn handle_file(stream: &TcpStream, path_string: &str) -> std::io::Result<()> {
     let path = Path::new(path_string);
 
-    let mut file_ref = File::open(path)?;
-    let mut content = vec![];
-    file_ref.read_to_end(&mut content)?;
+    let size = File::open(path)?.metadata()?.len();
+    let name = match path.file_name().and_then(|name| name.to_str()) {
+        Some(name) => name.to_string(),
+        None => return Err(invalid_input_error("Invalid file name")),
+    };
 
-    let message = file(
-        path.file_name().unwrap().to_str().unwrap().to_string(),
-        content,
-    );
+    serialize_and_write(
+        stream,
+        begin_upload_request(BeginUploadRequest {
+            name,
+            size: size as i64,
+        }),
+    )
+}
+
+/// Continue the upload of the file at the path from where the server is,
+/// sends the next chunk or finishes the upload once all bytes are received
+///
+/// Returns whether the upload has been finished.
+///
+/// # Arguments
+/// * `stream` - The stream to write into
+/// * `path_string` - The path to the file
+/// * `target` - The room or the user to send the file to
+/// * `progress` - The progress acknowledged by the server
+pub fn handle_upload_progress(
+    stream: &TcpStream,
+    path_string: &str,
+    target: MessageTarget,
+    progress: &UploadProgressResponse,
+) -> std::io::Result<bool> {
+    let mut file_ref = File::open(path_string)?;
+
+    if progress.received >= progress.size {
+        let mut hasher = Sha256::new();
+        copy(&mut file_ref, &mut hasher)?;
+
+        serialize_and_write(
+            stream,
+            finish_upload_request(FinishUploadRequest {
+                upload_id: progress.upload_id,
+                target,
+                sha256: format!("{:x}", hasher.finalize()),
+            }),
+        )?;
+        return Ok(true);
+    }
 
-    serialize_and_write(stream, message_request(MessageRequest::new(jwt, message)))
+    let mut data = vec![];
+    file_ref.seek(SeekFrom::Start(progress.received as u64))?;
+    file_ref
+        .take(progress.chunk_size as u64)
+        .read_to_end(&mut data)?;
+
+    serialize_and_write(
+        stream,
+        upload_chunk_request(UploadChunkRequest {
+            upload_id: progress.upload_id,
+            index: (progress.received / progress.chunk_size as i64) as u32,
+            data,
+        }),
+    )?;
+    Ok(false)
 }
@@ -1,4 +1,10 @@
-import { type MessageResponse, isFileVariant, isImageVariant, isTextVariant } from './types';
+import {
+	type MessageResponse,
+	isAttachmentVariant,
+	isFileVariant,
+	isImageVariant,
+	isTextVariant
+} from './types';
 
 export const processMessage = (message: MessageResponse) => {
 	if (isImageVariant(message.content)) {
@@ -14,6 +20,13 @@ export const processMessage = (message: MessageResponse) => {
 			id: message.id,
 			username: message.username,
 			user_id: message.user_id,
+			target: message.target,
+			created_at: new Date(message.created_at),
+			edited_at: message.edited_at ? new Date(message.edited_at) : null,
+			deleted: message.deleted_at !== null,
+			parent: message.parent,
+			thread_id: message.thread_id,
+			reactions: message.reactions,
 			content: {
 				kind: 'Image',
 				Image: base64
@@ -31,16 +44,47 @@ export const processMessage = (message: MessageResponse) => {
 			id: message.id,
 			username: message.username,
 			user_id: message.user_id,
+			target: message.target,
+			created_at: new Date(message.created_at),
+			edited_at: message.edited_at ? new Date(message.edited_at) : null,
+			deleted: message.deleted_at !== null,
+			parent: message.parent,
+			thread_id: message.thread_id,
+			reactions: message.reactions,
 			content: {
 				kind: 'File',
 				File: [message.content.File[0], base64] as const
 			}
 		};
+	} else if (isAttachmentVariant(message.content)) {
+		return {
+			id: message.id,
+			username: message.username,
+			user_id: message.user_id,
+			target: message.target,
+			created_at: new Date(message.created_at),
+			edited_at: message.edited_at ? new Date(message.edited_at) : null,
+			deleted: message.deleted_at !== null,
+			parent: message.parent,
+			thread_id: message.thread_id,
+			reactions: message.reactions,
+			content: {
+				kind: 'Attachment',
+				Attachment: message.content.Attachment
+			}
+		};
 	} else if (isTextVariant(message.content)) {
 		return {
 			id: message.id,
 			username: message.username,
 			user_id: message.user_id,
+			target: message.target,
+			created_at: new Date(message.created_at),
+			edited_at: message.edited_at ? new Date(message.edited_at) : null,
+			deleted: message.deleted_at !== null,
+			parent: message.parent,
+			thread_id: message.thread_id,
+			reactions: message.reactions,
 			content: {
 				kind: 'Text',
 				Text: message.content.Text
@@ -52,6 +96,13 @@ export const processMessage = (message: MessageResponse) => {
 			id: message.id,
 			username: message.username,
 			user_id: message.user_id,
+			target: message.target,
+			created_at: new Date(message.created_at),
+			edited_at: message.edited_at ? new Date(message.edited_at) : null,
+			deleted: message.deleted_at !== null,
+			parent: message.parent,
+			thread_id: message.thread_id,
+			reactions: message.reactions,
 			content: {
 				kind: 'Text',
 				Text: 'Unknown message type'
@@ -59,3 +110,37 @@ export const processMessage = (message: MessageResponse) => {
 		};
 	}
 };
+
+/** Index of the `UploadChunkRequest` variant in the Rust `StreamRequest` enum */
+const UPLOAD_CHUNK_REQUEST_VARIANT = 11;
+
+/**
+ * Encodes a `StreamRequest::UploadChunkRequest` the way bincode does, to be sent in a binary frame:
+ * the variant index as u32, upload_id as i32, index as u32 and length-prefixed data, all
+ * little-endian
+ */
+export const encodeUploadChunk = (upload_id: number, index: number, data: Uint8Array) => {
+	const buffer = new ArrayBuffer(4 + 4 + 4 + 8 + data.length);
+	const view = new DataView(buffer);
+
+	let offset = 0;
+	view.setUint32(offset, UPLOAD_CHUNK_REQUEST_VARIANT, true);
+	offset += 4;
+	view.setInt32(offset, upload_id, true);
+	offset += 4;
+	view.setUint32(offset, index, true);
+	offset += 4;
+	view.setBigUint64(offset, BigInt(data.length), true);
+	offset += 8;
+	new Uint8Array(buffer, offset).set(data);
+
+	return buffer;
+};
+
+/** Hex-encoded SHA-256 of the file */
+export const sha256Hex = async (file: Blob) => {
+	const digest = await crypto.subtle.digest('SHA-256', await file.arrayBuffer());
+	return Array.from(new Uint8Array(digest))
+		.map((byte) => byte.toString(16).padStart(2, '0'))
+		.join('');
+};
@@ -6,6 +6,7 @@ type Option<T> = T | null;
 type ImageVariant = { Image: Vec<number> };
 type FileVariant = { File: [string, Vec<number>] };
 type TextVariant = { Text: string };
+type AttachmentVariant = { Attachment: AttachmentInfo };
 export function isImageVariant(obj: MessageContent): obj is ImageVariant {
 	return (obj as ImageVariant).Image !== undefined;
 }
@@ -15,7 +16,37 @@ export function isFileVariant(obj: MessageContent): obj is FileVariant {
 export function isTextVariant(obj: MessageContent): obj is TextVariant {
 	return (obj as TextVariant).Text !== undefined;
 }
-export type MessageContent = ImageVariant | FileVariant | TextVariant;
+export function isAttachmentVariant(obj: MessageContent): obj is AttachmentVariant {
+	return (obj as AttachmentVariant).Attachment !== undefined;
+}
+export type MessageContent = ImageVariant | FileVariant | TextVariant | AttachmentVariant;
+
+export type AttachmentInfo = {
+	id: number;
+	name: string;
+	mime_type: string;
+	/** i64, in bytes */
+	size: number;
+	sha256: string;
+};
+export type UploadProgressResponse = {
+	upload_id: number;
+	name: string;
+	/** i64, in bytes */
+	size: number;
+	/** i64, the next chunk starts here */
+	received: number;
+	/** u32 */
+	chunk_size: number;
+};
+export type AttachmentChunkResponse = {
+	attachment_id: number;
+	/** u64 */
+	offset: number;
+	/** Vec<u8> */
+	data: Vec<number>;
+	last: boolean;
+};
 
 export type User = {
 	//** Option<i32> */
@@ -26,29 +57,232 @@ export type User = {
 	salt: Vec<number>;
 };
 
+export type MessageTarget = { Room: number } | { User: number };
+
 export type MessageResponse = {
 	id: number;
 	username: string;
 	user_id: number;
+	target: MessageTarget;
 	content: MessageContent;
+	/** DateTime<Utc>, RFC 3339 */
+	created_at: string;
+	/** Option<DateTime<Utc>>, RFC 3339 */
+	edited_at: Option<string>;
+	/** Option<DateTime<Utc>>, RFC 3339, set on tombstones of deleted messages */
+	deleted_at: Option<string>;
+	/** The message this one replies to */
+	parent: Option<QuotedMessage>;
+	/** The message starting the thread of a reply */
+	thread_id: Option<number>;
+	/** Grouped by emoji, in the order they were first used */
+	reactions: Vec<ReactionCount>;
+};
+export type ReactionCount = {
+	emoji: string;
+	/** i64 */
+	count: number;
+	user_ids: Vec<number>;
+};
+export type QuotedMessage = {
+	id: number;
+	user_id: number;
+	username: string;
+	snippet: string;
+	deleted: boolean;
 };
+/** Moderators can moderate members, admins can moderate moderators too */
+export type Role = 'Member' | 'Moderator' | 'Admin';
+
 export type Auth = {
 	token: string;
+	/** Opaque, exchanged for a new Auth with a RefreshRequest */
+	refresh_token: string;
+	username: string;
+	user_id: number;
+	role: Role;
+	/** Rooms and direct conversations with unread messages */
+	unread: Vec<UnreadCount>;
+};
+export type UnreadCount = {
+	target: MessageTarget;
+	/** i64 */
+	count: number;
+};
+
+export type ResumedResponse = {
+	username: string;
+	user_id: number;
+	role: Role;
+	/** u64, in seconds from UNIX epoch */
+	expires_at: number;
+};
+
+export type RoomResponse = {
+	id: number;
+	name: string;
+	joined: boolean;
+};
+export type RoomListResponse = {
+	rooms: Vec<RoomResponse>;
+};
+
+export type UserPresence = {
+	user_id: number;
+	username: string;
+};
+export type OnlineUsersResponse = {
+	users: Vec<UserPresence>;
+};
+
+/** Ephemeral, never stored by the server */
+export type TypingResponse = {
+	user_id: number;
+	username: string;
+	target: MessageTarget;
+	/** u64, seconds the indicator lasts unless renewed */
+	expires_in: number;
+};
+
+export type ReadReceiptResponse = {
+	user_id: number;
 	username: string;
+	/** As the reader sees it, a direct conversation is the other user */
+	target: MessageTarget;
+	message_id: number;
+};
+
+export type ReactionResponse = {
+	message_id: number;
+	target: MessageTarget;
 	user_id: number;
+	username: string;
+	emoji: string;
+	/** Whether the reaction was added, or removed */
+	added: boolean;
+	/** All reactions to the message after the change */
+	reactions: Vec<ReactionCount>;
 };
 
+export type ModerationAction = 'Mute' | 'Unmute' | 'Ban' | 'Unban' | 'Kick';
+/** Sent to the moderator and the moderated user, a kicked or banned user is disconnected */
+export type ModerationResponse = {
+	user_id: number;
+	username: string;
+	moderator_id: number;
+	action: ModerationAction;
+	/** Option<DateTime<Utc>>, RFC 3339, when the mute of the user ends */
+	muted_until: Option<string>;
+};
+
+export type MentionsResponse = {
+	messages: Vec<MessageResponse>;
+	has_more: boolean;
+};
+
+export type SnippetPart = {
+	text: string;
+	/** Whether the part matches the searched words */
+	highlighted: boolean;
+};
+export type SearchResult = {
+	message: MessageResponse;
+	snippet: Vec<SnippetPart>;
+};
+export type SearchResponse = {
+	query: string;
+	/** The best matches first */
+	results: Vec<SearchResult>;
+	has_more: boolean;
+};
+
+export type ThreadResponse = {
+	root: MessageResponse;
+	replies: Vec<MessageResponse>;
+	has_more: boolean;
+};
+
+export type HistoryResponse = {
+	target: MessageTarget;
+	messages: Vec<MessageResponse>;
+	has_more: boolean;
+};
+
+/**
+ * `UserMuted` holds when the mute ends (RFC 3339) and `RateLimited` how many seconds to wait, the
+ * other errors are plain strings
+ */
+export type ErrorResponse =
+	| { DBError: string }
+	| { ServerError: string | { UserMuted: string } | { RateLimited: number } };
+
 export type AuthServerResponse = { Auth: Auth };
+export type ErrorServerResponse = { Error: ErrorResponse };
 export type MessageServerResponse = { Message: MessageResponse };
 export function isMessageServerResponse(obj: ServerResponse): obj is MessageServerResponse {
 	return (obj as MessageServerResponse).Message !== undefined;
 }
 
-export type ServerResponse = AuthServerResponse | MessageServerResponse;
+export type RoomJoinedServerResponse = { RoomJoined: RoomResponse };
+export type RoomLeftServerResponse = { RoomLeft: RoomResponse };
+export type RoomListServerResponse = { RoomList: RoomListResponse };
+export type HistoryServerResponse = { History: HistoryResponse };
+export type MessageEditedServerResponse = { MessageEdited: MessageResponse };
+export type MessageDeletedServerResponse = { MessageDeleted: MessageResponse };
+export type AttachmentInfoServerResponse = { AttachmentInfo: AttachmentInfo };
+export type AttachmentChunkServerResponse = { AttachmentChunk: AttachmentChunkResponse };
+export type UploadProgressServerResponse = { UploadProgress: UploadProgressResponse };
+/** i32, the id of the user whose session has ended */
+export type LoggedOutServerResponse = { LoggedOut: number };
+export type ResumedServerResponse = { Resumed: ResumedResponse };
+export type UserOnlineServerResponse = { UserOnline: UserPresence };
+export type UserOfflineServerResponse = { UserOffline: UserPresence };
+export type OnlineUsersServerResponse = { OnlineUsers: OnlineUsersResponse };
+export type TypingServerResponse = { Typing: TypingResponse };
+export type ReadReceiptServerResponse = { ReadReceipt: ReadReceiptResponse };
+export type ThreadServerResponse = { Thread: ThreadResponse };
+export type ReactionServerResponse = { Reaction: ReactionResponse };
+/** Sent to the mentioned user, whichever conversation they're in */
+export type MentionServerResponse = { Mention: MessageResponse };
+export type MentionsServerResponse = { Mentions: MentionsResponse };
+export type SearchServerResponse = { Search: SearchResponse };
+export type ModerationServerResponse = { Moderation: ModerationResponse };
+/** u64, the longest time (in seconds) the server waits for the pending responses before closing */
+export type ShuttingDownServerResponse = { ShuttingDown: number };
+
+export type ServerResponse =
+	| AuthServerResponse
+	| ErrorServerResponse
+	| MessageServerResponse
+	| RoomJoinedServerResponse
+	| RoomLeftServerResponse
+	| RoomListServerResponse
+	| HistoryServerResponse
+	| MessageEditedServerResponse
+	| MessageDeletedServerResponse
+	| AttachmentInfoServerResponse
+	| AttachmentChunkServerResponse
+	| UploadProgressServerResponse
+	| LoggedOutServerResponse
+	| ResumedServerResponse
+	| UserOnlineServerResponse
+	| UserOfflineServerResponse
+	| OnlineUsersServerResponse
+	| TypingServerResponse
+	| ReadReceiptServerResponse
+	| ThreadServerResponse
+	| ReactionServerResponse
+	| MentionServerResponse
+	| MentionsServerResponse
+	| SearchServerResponse
+	| ModerationServerResponse
+	| ShuttingDownServerResponse;
 
 export type MessageRequest = {
-	jwt: string;
+	target: MessageTarget;
 	message: MessageContent;
+	/** The message to reply to */
+	parent_id?: Option<number>;
 };
 export type AuthRequestKind = 'Login' | 'Register';
 export type AuthRequest = {
@@ -57,13 +291,117 @@ export type AuthRequest = {
 	password: string;
 };
 export type ReadRequest = {
-	jwt: string;
+	target: MessageTarget;
+	amount: number;
+	/** Option<i32>, the id of the oldest message already loaded */
+	before_id?: Option<number>;
+	/** Option<DateTime<Utc>>, RFC 3339 */
+	before?: Option<string>;
+	/** Option<DateTime<Utc>>, RFC 3339 */
+	after?: Option<string>;
+};
+export type CreateRoomRequest = {
+	name: string;
+};
+export type RoomRequest = {
+	room_id: number;
+};
+export type ListRoomsRequest = Record<string, never>;
+export type ListOnlineUsersRequest = Record<string, never>;
+export type TypingRequest = {
+	target: MessageTarget;
+};
+export type ReadThreadRequest = {
+	/** The message starting the thread, or any reply in it */
+	message_id: number;
 	amount: number;
-  offset: number;
+	after_id: Option<number>;
+};
+export type ReactionRequest = {
+	message_id: number;
+	emoji: string;
+};
+export type ListMentionsRequest = {
+	amount: number;
+	/** Option<i32>, the id of the oldest mention already loaded */
+	before_id: Option<number>;
+};
+export type SearchRequest = {
+	/** The last word may be a prefix of a word */
+	query: string;
+	/** Option<i32>, only search the messages of this user */
+	user_id: Option<number>;
+	amount: number;
+	/** The number of results already loaded */
+	offset: number;
+};
+export type MuteRequest = {
+	user_id: number;
+	/** u64, in seconds */
+	duration: number;
+};
+export type UserRequest = {
+	user_id: number;
+};
+export type MarkReadRequest = {
+	target: MessageTarget;
+	message_id: number;
+};
+export type EditMessageRequest = {
+	message_id: number;
+	message: MessageContent;
+};
+export type DeleteMessageRequest = {
+	message_id: number;
+};
+export type FetchAttachmentRequest = {
+	attachment_id: number;
+};
+export type BeginUploadRequest = {
+	name: string;
+	size: number;
+};
+export type FinishUploadRequest = {
+	upload_id: number;
+	target: MessageTarget;
+	/** Hex-encoded SHA-256 of the whole file */
+	sha256: string;
+};
+export type RefreshRequest = {
+	refresh_token: string;
+};
+export type LogoutRequest = Record<string, never>;
+export type ResumeRequest = {
+	token: string;
 };
 export type StreamRequest =
 	| { MessageRequest: MessageRequest }
 	| { AuthRequest: AuthRequest }
-	| { ReadRequest: ReadRequest };
+	| { ReadRequest: ReadRequest }
+	| { CreateRoomRequest: CreateRoomRequest }
+	| { JoinRoomRequest: RoomRequest }
+	| { LeaveRoomRequest: RoomRequest }
+	| { ListRoomsRequest: ListRoomsRequest }
+	| { EditMessageRequest: EditMessageRequest }
+	| { DeleteMessageRequest: DeleteMessageRequest }
+	| { FetchAttachmentRequest: FetchAttachmentRequest }
+	| { BeginUploadRequest: BeginUploadRequest }
+	| { FinishUploadRequest: FinishUploadRequest }
+	| { RefreshRequest: RefreshRequest }
+	| { LogoutRequest: LogoutRequest }
+	| { ResumeRequest: ResumeRequest }
+	| { ListOnlineUsersRequest: ListOnlineUsersRequest }
+	| { TypingRequest: TypingRequest }
+	| { MarkReadRequest: MarkReadRequest }
+	| { ReadThreadRequest: ReadThreadRequest }
+	| { ReactRequest: ReactionRequest }
+	| { UnreactRequest: ReactionRequest }
+	| { ListMentionsRequest: ListMentionsRequest }
+	| { SearchRequest: SearchRequest }
+	| { MuteRequest: MuteRequest }
+	| { UnmuteRequest: UserRequest }
+	| { KickRequest: UserRequest }
+	| { BanRequest: UserRequest }
+	| { UnbanRequest: UserRequest };
 
 export type ProcessedMessage = ReturnType<typeof processMessage>;
@@ -0,0 +1,3 @@
+ALTER TABLE messages DROP COLUMN room_id;
+DROP TABLE room_members;
+DROP TABLE rooms;
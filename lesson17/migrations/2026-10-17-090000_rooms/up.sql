@@ -0,0 +1,17 @@
+CREATE TABLE rooms (
+  id INTEGER PRIMARY KEY AUTOINCREMENT,
+  name VARCHAR NOT NULL UNIQUE
+);
+
+CREATE TABLE room_members (
+  id INTEGER PRIMARY KEY AUTOINCREMENT,
+  room_id INT NOT NULL,
+  user_id INT NOT NULL,
+  UNIQUE (room_id, user_id)
+);
+
+-- Every message sent before rooms existed belongs to the default room
+INSERT INTO rooms (id, name) VALUES (1, 'general');
+INSERT INTO room_members (room_id, user_id) SELECT 1, id FROM users;
+
+ALTER TABLE messages ADD COLUMN room_id INT NOT NULL DEFAULT 1;
@@ -0,0 +1,13 @@
+CREATE TABLE messages_old (
+  id INTEGER PRIMARY KEY AUTOINCREMENT,
+  user_id INT NOT NULL,
+  content BINARY NOT NULL,
+  room_id INT NOT NULL DEFAULT 1
+);
+
+-- Direct messages have no room to go back to
+INSERT INTO messages_old (id, user_id, content, room_id)
+SELECT id, user_id, content, room_id FROM messages WHERE room_id IS NOT NULL;
+
+DROP TABLE messages;
+ALTER TABLE messages_old RENAME TO messages;
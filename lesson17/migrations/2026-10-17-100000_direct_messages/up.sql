@@ -0,0 +1,16 @@
+-- SQLite can't drop NOT NULL from a column, so the table has to be rebuilt.
+-- A message either belongs to a room or is a direct message to a single user.
+CREATE TABLE messages_new (
+  id INTEGER PRIMARY KEY AUTOINCREMENT,
+  user_id INT NOT NULL,
+  content BINARY NOT NULL,
+  room_id INT,
+  recipient_id INT,
+  CHECK ((room_id IS NULL) != (recipient_id IS NULL))
+);
+
+INSERT INTO messages_new (id, user_id, content, room_id)
+SELECT id, user_id, content, room_id FROM messages;
+
+DROP TABLE messages;
+ALTER TABLE messages_new RENAME TO messages;
@@ -0,0 +1,2 @@
+DROP INDEX messages_created_at;
+ALTER TABLE messages DROP COLUMN created_at;
@@ -0,0 +1,6 @@
+-- SQLite only allows constant defaults when adding a column, the server always sets created_at itself.
+-- The send time of older messages is unknown, so they are stamped with the time of the migration.
+ALTER TABLE messages ADD COLUMN created_at TIMESTAMP NOT NULL DEFAULT '1970-01-01 00:00:00';
+UPDATE messages SET created_at = CURRENT_TIMESTAMP;
+
+CREATE INDEX messages_created_at ON messages (created_at);
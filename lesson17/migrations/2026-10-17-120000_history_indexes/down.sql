@@ -0,0 +1,2 @@
+DROP INDEX messages_recipient_id;
+DROP INDEX messages_room_id;
@@ -0,0 +1,3 @@
+-- History pages are read newest first by id within a single conversation
+CREATE INDEX messages_room_id ON messages (room_id, id);
+CREATE INDEX messages_recipient_id ON messages (recipient_id, id);
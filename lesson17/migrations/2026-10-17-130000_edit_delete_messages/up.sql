@@ -0,0 +1,3 @@
+ALTER TABLE messages ADD COLUMN edited_at TIMESTAMP;
+-- Deleted messages are kept as tombstones with their content wiped
+ALTER TABLE messages ADD COLUMN deleted_at TIMESTAMP;
@@ -0,0 +1,3 @@
+DROP INDEX messages_attachment_id;
+ALTER TABLE messages DROP COLUMN attachment_id;
+DROP TABLE attachments;
@@ -0,0 +1,12 @@
+-- Attachment bytes live on disk under their SHA-256, rows only hold the metadata
+CREATE TABLE attachments (
+  id INTEGER PRIMARY KEY AUTOINCREMENT,
+  sha256 VARCHAR NOT NULL,
+  size BIGINT NOT NULL,
+  mime_type VARCHAR NOT NULL,
+  name VARCHAR NOT NULL
+);
+CREATE INDEX attachments_sha256 ON attachments (sha256);
+
+ALTER TABLE messages ADD COLUMN attachment_id INT REFERENCES attachments (id);
+CREATE INDEX messages_attachment_id ON messages (attachment_id);
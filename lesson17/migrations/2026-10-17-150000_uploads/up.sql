@@ -0,0 +1,10 @@
+-- Unfinished chunked uploads, the received bytes are kept on disk until the upload is finished
+CREATE TABLE uploads (
+  id INTEGER PRIMARY KEY AUTOINCREMENT,
+  user_id INT NOT NULL,
+  name VARCHAR NOT NULL,
+  size BIGINT NOT NULL,
+  received BIGINT NOT NULL DEFAULT 0,
+  created_at TIMESTAMP NOT NULL
+);
+CREATE INDEX uploads_user_id ON uploads (user_id);
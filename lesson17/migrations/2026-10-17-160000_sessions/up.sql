@@ -0,0 +1,11 @@
+-- Logins of users, renewed with their refresh token until they expire or are revoked
+CREATE TABLE sessions (
+  id INTEGER PRIMARY KEY AUTOINCREMENT,
+  user_id INT NOT NULL,
+  -- Hex-encoded SHA-256 of the refresh token, the token itself is never stored
+  refresh_token_hash VARCHAR NOT NULL UNIQUE,
+  created_at TIMESTAMP NOT NULL,
+  expires_at TIMESTAMP NOT NULL,
+  revoked_at TIMESTAMP
+);
+CREATE INDEX sessions_user_id ON sessions (user_id);
@@ -0,0 +1,11 @@
+-- The last message each user has read in each room and direct conversation
+CREATE TABLE read_receipts (
+  id INTEGER PRIMARY KEY AUTOINCREMENT,
+  user_id INT NOT NULL,
+  -- Either room_id or peer_id (the other user of a direct conversation) is set
+  room_id INT,
+  peer_id INT,
+  message_id INT NOT NULL,
+  read_at TIMESTAMP NOT NULL
+);
+CREATE INDEX read_receipts_user_id ON read_receipts (user_id);
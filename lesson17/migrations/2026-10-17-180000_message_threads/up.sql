@@ -0,0 +1,6 @@
+-- The message a reply quotes
+ALTER TABLE messages ADD COLUMN parent_id INT;
+-- The message starting the thread, a reply to a reply stays in the thread of its parent
+ALTER TABLE messages ADD COLUMN thread_id INT;
+
+CREATE INDEX messages_thread_id ON messages (thread_id, id);
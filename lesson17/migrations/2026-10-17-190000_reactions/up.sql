@@ -0,0 +1,9 @@
+-- Emoji reactions of users to messages, each user can react with each emoji once
+CREATE TABLE reactions (
+  id INTEGER PRIMARY KEY AUTOINCREMENT,
+  message_id INT NOT NULL,
+  user_id INT NOT NULL,
+  emoji VARCHAR NOT NULL,
+  created_at TIMESTAMP NOT NULL,
+  UNIQUE (message_id, user_id, emoji)
+);
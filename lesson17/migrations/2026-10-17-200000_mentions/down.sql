@@ -0,0 +1,2 @@
+DROP INDEX mentions_user_id;
+DROP TABLE mentions;
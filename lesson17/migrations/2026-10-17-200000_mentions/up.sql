@@ -0,0 +1,9 @@
+-- Users mentioned as @username in the text of messages
+CREATE TABLE mentions (
+  id INTEGER PRIMARY KEY AUTOINCREMENT,
+  message_id INT NOT NULL,
+  user_id INT NOT NULL,
+  created_at TIMESTAMP NOT NULL,
+  UNIQUE (message_id, user_id)
+);
+CREATE INDEX mentions_user_id ON mentions (user_id, message_id);
@@ -0,0 +1,26 @@
+-- The text of a message to be searched for, extracted from its content by the server:
+-- the text of text messages and the name of files, empty for images and NULL once deleted.
+-- Messages saved before this migration are indexed when the server starts.
+ALTER TABLE messages ADD COLUMN search_text VARCHAR;
+
+-- External content table, the text itself stays in messages
+CREATE VIRTUAL TABLE messages_fts USING fts5 (
+  search_text,
+  content = 'messages',
+  content_rowid = 'id'
+);
+-- Every row is in the index, so the triggers can always remove the old version first
+INSERT INTO messages_fts (rowid, search_text) SELECT id, search_text FROM messages;
+
+CREATE TRIGGER messages_fts_insert AFTER INSERT ON messages BEGIN
+  INSERT INTO messages_fts (rowid, search_text) VALUES (new.id, new.search_text);
+END;
+CREATE TRIGGER messages_fts_delete AFTER DELETE ON messages BEGIN
+  INSERT INTO messages_fts (messages_fts, rowid, search_text)
+    VALUES ('delete', old.id, old.search_text);
+END;
+CREATE TRIGGER messages_fts_update AFTER UPDATE OF search_text ON messages BEGIN
+  INSERT INTO messages_fts (messages_fts, rowid, search_text)
+    VALUES ('delete', old.id, old.search_text);
+  INSERT INTO messages_fts (rowid, search_text) VALUES (new.id, new.search_text);
+END;
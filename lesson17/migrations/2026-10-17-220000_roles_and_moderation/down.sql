@@ -0,0 +1,3 @@
+ALTER TABLE users DROP COLUMN banned_at;
+ALTER TABLE users DROP COLUMN muted_until;
+ALTER TABLE users DROP COLUMN role;
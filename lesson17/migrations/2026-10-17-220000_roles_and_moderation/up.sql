@@ -0,0 +1,6 @@
+-- Who can moderate: 'admin', 'moderator' or 'member'
+ALTER TABLE users ADD COLUMN role VARCHAR NOT NULL DEFAULT 'member';
+-- A muted user can't send messages until this time
+ALTER TABLE users ADD COLUMN muted_until TIMESTAMP;
+-- A banned user can't log in, NULL unless banned
+ALTER TABLE users ADD COLUMN banned_at TIMESTAMP;
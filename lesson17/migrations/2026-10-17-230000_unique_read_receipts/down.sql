@@ -0,0 +1,2 @@
+DROP INDEX read_receipts_peer;
+DROP INDEX read_receipts_room;
@@ -0,0 +1,14 @@
+-- Keep only the newest receipt of each user in each conversation
+DELETE FROM read_receipts WHERE EXISTS (
+  SELECT 1 FROM read_receipts AS newer
+  WHERE newer.user_id = read_receipts.user_id
+    AND newer.room_id IS read_receipts.room_id
+    AND newer.peer_id IS read_receipts.peer_id
+    AND (newer.message_id > read_receipts.message_id
+      OR (newer.message_id = read_receipts.message_id AND newer.id > read_receipts.id))
+);
+-- A user has one receipt per room and one per direct conversation, moved up in place
+CREATE UNIQUE INDEX read_receipts_room ON read_receipts (user_id, room_id)
+  WHERE room_id IS NOT NULL;
+CREATE UNIQUE INDEX read_receipts_peer ON read_receipts (user_id, peer_id)
+  WHERE peer_id IS NOT NULL;
@@ -0,0 +1,141 @@
+use anyhow::{bail, Result};
+use chrono::NaiveDate;
+use clap::{Parser, Subcommand};
+use utils::{attachments::AttachmentStore, db::DB, Role};
+
+/// Manages the users and the database of the chat server, directly in the database file
+///
+/// Best run while the server is stopped, so the sessions it has cached don't outlive the changes.
+#[derive(Parser, Debug)]
+#[command(version, about, long_about = None)]
+struct Args {
+    /// The SQLite database file
+    #[arg(long, default_value = "chat.db")]
+    pub database: String,
+    /// The directory of the attachment store, `attachments.path` of the server config
+    #[arg(long, default_value = "attachments", global = true)]
+    pub attachments_path: String,
+
+    #[command(subcommand)]
+    pub command: Command,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Create a user, who joins the general room
+    CreateUser {
+        username: String,
+        password: String,
+        #[arg(long, default_value = "member", value_parser = ["member", "moderator", "admin"])]
+        role: String,
+    },
+    /// Delete a user with their messages, the direct messages sent to them and the rest of their
+    /// data, including the files only their messages carried
+    DeleteUser { username: String },
+    /// Replace the password of a user and end their sessions
+    ResetPassword { username: String, password: String },
+    /// Change the role of a user
+    SetRole {
+        username: String,
+        #[arg(value_parser = ["member", "moderator", "admin"])]
+        role: String,
+    },
+    /// List all users with their roles and the amount of messages they have sent
+    ListUsers,
+    /// Delete the messages sent before the date (YYYY-MM-DD, UTC), and the files only they carried
+    PurgeMessages { before: NaiveDate },
+    /// Rebuild the database file to reclaim the space of deleted rows
+    Vacuum,
+    /// Move the images and files saved inside messages, before the attachment store, into the store
+    StoreAttachments,
+}
+
+fn main() -> Result<()> {
+    let args = Args::parse();
+
+    // A missing database is created and migrated, e.g. to create the first admin
+    let db = DB::open(&args.database)?;
+    let store = AttachmentStore::new(&args.attachments_path);
+
+    match args.command {
+        Command::CreateUser {
+            username,
+            password,
+            role,
+        } => {
+            if db.get_user_id(&username).is_ok() {
+                bail!("{} already exists", username);
+            }
+            let user = db.create_user(username, password)?;
+            let user = db.set_role(user.id.unwrap(), Role::from_db_role(&role))?;
+            println!(
+                "Created {} (id {}) as {}",
+                user.username,
+                user.id.unwrap(),
+                user.role
+            );
+        }
+        Command::DeleteUser { username } => {
+            let user_id = db.get_user_id(&username)?;
+            let deleted_messages = db.delete_user(user_id, &store)?;
+            println!(
+                "Deleted {} (id {}) and {} messages",
+                username, user_id, deleted_messages
+            );
+        }
+        Command::ResetPassword { username, password } => {
+            let user_id = db.get_user_id(&username)?;
+            db.set_password(user_id, password)?;
+            db.revoke_user_sessions(user_id)?;
+            println!(
+                "Reset the password of {}, they have to log in again",
+                username
+            );
+        }
+        Command::SetRole { username, role } => {
+            let user_id = db.get_user_id(&username)?;
+            let user = db.set_role(user_id, Role::from_db_role(&role))?;
+            println!("{} is now {}", user.username, user.role);
+        }
+        Command::ListUsers => {
+            println!(
+                "{:>5}  {:<20} {:<10} {:>8}  status",
+                "id", "username", "role", "messages"
+            );
+            for (user, message_count) in db.list_users()? {
+                let status = if user.banned_at.is_some() {
+                    "banned"
+                } else if user.active_mute().is_some() {
+                    "muted"
+                } else {
+                    ""
+                };
+                println!(
+                    "{:>5}  {:<20} {:<10} {:>8}  {}",
+                    user.id.unwrap(),
+                    user.username,
+                    user.role,
+                    message_count,
+                    status
+                );
+            }
+        }
+        Command::PurgeMessages { before } => {
+            let purged = db.purge_messages(before.and_hms_opt(0, 0, 0).unwrap(), &store)?;
+            println!("Deleted {} messages sent before {}", purged, before);
+        }
+        Command::Vacuum => {
+            db.vacuum()?;
+            println!("Vacuumed {}", args.database);
+        }
+        Command::StoreAttachments => {
+            let converted = db.store_inline_attachments(&store)?;
+            println!(
+                "Moved the attachments of {} messages into {}",
+                converted, args.attachments_path
+            );
+        }
+    }
+
+    Ok(())
+}
@@ -1,8 +1,24 @@
-use server::start_server;
-use utils::get_address;
+use clap::Parser;
+use server::{start_server, Args, Config};
 
 #[tokio::main]
 async fn main() {
-    let address = get_address();
-    start_server(address).await
+    let args = Args::parse();
+    let print_config = args.print_config;
+    let config = match Config::load(args) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
+
+    if print_config {
+        print!("{}", config.to_toml());
+        return;
+    }
+    if let Err(e) = start_server(config).await {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
 }